@@ -0,0 +1,270 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{Local, NaiveTime};
+
+// How fast the send streams of a job may go. The global limit is shared by
+// every send of the run, even when datasets are replicated in parallel.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Bandwidth {
+    pub global: Option<BandwidthLimit>,
+    // Limits that only apply to the sends of a single source dataset.
+    pub datasets: BTreeMap<String, BandwidthLimit>,
+}
+
+// A limit in bytes per second that may change with the time of day.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BandwidthLimit {
+    // Applies outside of the windows of the schedule. None is unlimited.
+    pub rate: Option<u64>,
+    // The first window that contains the current time wins.
+    pub schedule: Vec<(TimeWindow, u64)>,
+}
+
+impl BandwidthLimit {
+    pub fn get_rate(&self, time: NaiveTime) -> Option<u64> {
+        self.schedule
+            .iter()
+            .find(|(window, _)| window.contains(time))
+            .map(|(_, rate)| *rate)
+            .or(self.rate)
+    }
+}
+
+// A time of day range such as "08:00-18:00". Ranges that end before they
+// start wrap around midnight (e.g. "22:00-06:00").
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("'{}' is not a valid time window (e.g. 08:00-18:00)", value);
+        let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+        let parse =
+            |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|_| invalid());
+        let window = Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        };
+        if window.start == window.end {
+            return Err(invalid());
+        }
+        Ok(window)
+    }
+}
+
+// Parses a rate in bytes per second such as "500K", "50M" or "1G", with
+// binary units like the rest of ZFS.
+pub fn parse_rate(value: &str) -> Result<u64, String> {
+    let invalid = || format!("'{}' is not a valid rate (e.g. 500K, 50M, 1G)", value);
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last() {
+        Some('K' | 'k') => (&value[..value.len() - 1], 1 << 10),
+        Some('M' | 'm') => (&value[..value.len() - 1], 1 << 20),
+        Some('G' | 'g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    let rate: u64 = number.parse().map_err(|_| invalid())?;
+    match rate.checked_mul(multiplier) {
+        Some(0) | None => Err(invalid()),
+        Some(rate) => Ok(rate),
+    }
+}
+
+// How often a writer that waits for the rate marks that it's still making
+// progress.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+// Slows down the writers of a stream to the rate of a limit. Writers that
+// share a throttle share the rate.
+#[derive(Debug)]
+pub struct Throttle {
+    limit: BandwidthLimit,
+    bucket: Mutex<Bucket>,
+}
+
+// How many bytes may be written right away. Negative when writers are
+// ahead of the rate and have to wait.
+#[derive(Debug)]
+struct Bucket {
+    credit: f64,
+    updated: Instant,
+}
+
+impl Throttle {
+    pub fn new(limit: BandwidthLimit) -> Self {
+        Self {
+            limit,
+            bucket: Mutex::new(Bucket {
+                credit: 0.0,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    // Waits until the given number of bytes may be written. Waiting for the
+    // rate isn't a stall, so the progress is kept up to date meanwhile.
+    pub fn wait(&self, bytes: usize, progress: &Mutex<Instant>) {
+        let mut delay = self.reserve(bytes, Instant::now(), Local::now().time());
+        while !delay.is_zero() {
+            let step = delay.min(PROGRESS_INTERVAL);
+            thread::sleep(step);
+            delay -= step;
+            *progress.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+        }
+    }
+
+    // Accounts for bytes that are about to be written and returns how long
+    // to wait before writing them. At most a second worth of unused rate is
+    // saved up for later.
+    fn reserve(&self, bytes: usize, now: Instant, time: NaiveTime) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.updated = bucket.updated.max(now);
+
+        let Some(rate) = self.limit.get_rate(time) else {
+            bucket.credit = 0.0;
+            return Duration::ZERO;
+        };
+        let rate = rate as f64;
+        bucket.credit = (bucket.credit + elapsed * rate).min(rate) - bytes as f64;
+        if bucket.credit >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.credit / rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    fn get_business_hours_limit() -> BandwidthLimit {
+        BandwidthLimit {
+            rate: None,
+            schedule: vec![("08:00-18:00".parse().unwrap(), 1 << 20)],
+        }
+    }
+
+    #[test]
+    fn test_parse_rate_should_use_binary_units() {
+        assert_eq!(parse_rate("500"), Ok(500));
+        assert_eq!(parse_rate("500K"), Ok(500 * 1024));
+        assert_eq!(parse_rate("50M"), Ok(50 * 1024 * 1024));
+        assert_eq!(parse_rate("1g"), Ok(1024 * 1024 * 1024));
+        assert!(parse_rate("0M").is_err());
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("M").is_err());
+    }
+
+    #[test]
+    fn test_time_window_should_wrap_around_midnight() {
+        let night: TimeWindow = "22:00-06:00".parse().unwrap();
+        let day: TimeWindow = "08:00-18:00".parse().unwrap();
+
+        assert!(night.contains(at("23:30")));
+        assert!(night.contains(at("05:59")));
+        assert!(!night.contains(at("06:00")));
+        assert!(day.contains(at("08:00")));
+        assert!(!day.contains(at("18:00")));
+        assert!("08:00".parse::<TimeWindow>().is_err());
+        assert!("08:00-08:00".parse::<TimeWindow>().is_err());
+        assert!("8-18".parse::<TimeWindow>().is_err());
+    }
+
+    #[test]
+    fn test_get_rate_should_follow_the_schedule() {
+        let mut limit = get_business_hours_limit();
+
+        assert_eq!(limit.get_rate(at("12:00")), Some(1 << 20));
+        assert_eq!(limit.get_rate(at("20:00")), None);
+
+        limit.rate = Some(10 << 20);
+        assert_eq!(limit.get_rate(at("20:00")), Some(10 << 20));
+    }
+
+    #[test]
+    fn test_reserve_should_delay_writes_beyond_the_rate() {
+        let throttle = Throttle::new(get_business_hours_limit());
+        let start = throttle.bucket.lock().unwrap().updated;
+
+        let first = throttle.reserve(512 * 1024, start, at("12:00"));
+        let second = throttle.reserve(512 * 1024, start, at("12:00"));
+        let later = throttle.reserve(512 * 1024, start + Duration::from_secs(2), at("12:00"));
+        let unlimited = throttle.reserve(512 * 1024, start, at("20:00"));
+
+        assert_eq!(first, Duration::from_millis(500));
+        assert_eq!(second, Duration::from_secs(1));
+        assert_eq!(later, Duration::ZERO);
+        assert_eq!(unlimited, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_wait_should_keep_marking_progress() {
+        let throttle = Throttle::new(BandwidthLimit {
+            rate: Some(10 * 1024),
+            schedule: Vec::new(),
+        });
+        let progress = Mutex::new(Instant::now());
+        let stall_timeout = Duration::from_millis(250);
+
+        let mut longest = Duration::ZERO;
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| throttle.wait(5 * 1024, &progress));
+            while !waiter.is_finished() {
+                longest = longest.max(progress.lock().unwrap().elapsed());
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+
+        assert!(longest < stall_timeout);
+    }
+}
@@ -0,0 +1,234 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::report::SCHEMA_VERSION;
+use crate::snapshot::Snapshot;
+
+// Jobs running concurrently in daemon mode can write to the same disk, so
+// updates to the catalog are serialized.
+static LOCK: Mutex<()> = Mutex::new(());
+
+// What was last seen on a backup disk. Rotated disks usually share a pool
+// name, so each disk is tracked by its pool GUID instead.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Disk {
+    pub pool: String,
+    // Kept as a string since GUIDs don't fit in a JSON number for every
+    // consumer.
+    pub guid: String,
+    // RFC 3339 timestamp of the last run that wrote to the disk.
+    pub last_seen: String,
+    // The snapshots of each backup dataset, oldest first.
+    pub datasets: BTreeMap<String, Vec<String>>,
+}
+
+// Every disk in the catalog, as reported by the 'catalog' command.
+#[derive(Clone, Debug, Serialize)]
+pub struct CatalogReport {
+    pub schema_version: u32,
+    pub version: String,
+    pub disks: Vec<Disk>,
+}
+
+impl CatalogReport {
+    pub fn new(disks: Vec<Disk>) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            version: String::from(clap::crate_version!()),
+            disks,
+        }
+    }
+}
+
+impl Disk {
+    // Loads the disk with the given pool GUID. Returns None if it was never
+    // seen or its entry can't be read.
+    pub fn load(state_dir: &Path, guid: u64) -> Option<Self> {
+        let contents = fs::read_to_string(get_path(state_dir, guid)).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    fn save(&self, state_dir: &Path, guid: u64) -> Result<(), String> {
+        let path = get_path(state_dir, guid);
+        let directory = path.parent().unwrap_or(state_dir);
+        fs::create_dir_all(directory)
+            .map_err(|e| format!("Failed to create {}: {}", directory.display(), e))?;
+
+        let temporary = path.with_extension("json.tmp");
+        let contents = serde_json::to_string_pretty(self).unwrap();
+        fs::write(&temporary, contents)
+            .and_then(|_| fs::rename(&temporary, &path))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+}
+
+// Records the snapshots that the given backup datasets have on the disk
+// with the given pool GUID. Datasets written by other jobs are kept.
+pub fn record(
+    state_dir: &Path,
+    pool: &str,
+    guid: u64,
+    backup_datasets: &[String],
+    snapshots: &[Snapshot],
+    now: DateTime<Local>,
+) -> Result<(), String> {
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut disk = Disk::load(state_dir, guid).unwrap_or_default();
+    disk.pool = String::from(pool);
+    disk.guid = guid.to_string();
+    disk.last_seen = now.to_rfc3339();
+    for backup_dataset in backup_datasets {
+        let mut names: Vec<&Snapshot> = snapshots
+            .iter()
+            .filter(|snapshot| {
+                snapshot
+                    .name
+                    .split_once('@')
+                    .is_some_and(|(dataset, _)| dataset == backup_dataset)
+            })
+            .collect();
+        names.sort_unstable();
+        disk.datasets.insert(
+            backup_dataset.clone(),
+            names.iter().map(|snapshot| snapshot.name.clone()).collect(),
+        );
+    }
+    disk.save(state_dir, guid)
+}
+
+// Loads every disk in the catalog, ordered by pool name and GUID.
+pub fn load_all(state_dir: &Path) -> Vec<Disk> {
+    let mut disks = Vec::new();
+    let entries = match fs::read_dir(state_dir.join("catalog")) {
+        Ok(entries) => entries,
+        Err(_) => return disks,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+        let guid = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok());
+        if let Some(disk) = guid.and_then(|guid| Disk::load(state_dir, guid)) {
+            disks.push(disk);
+        }
+    }
+    disks.sort_by(|a, b| (&a.pool, &a.guid).cmp(&(&b.pool, &b.guid)));
+    disks
+}
+
+// Prints the catalog in a human readable form.
+pub fn print_text(report: &CatalogReport) {
+    if report.disks.is_empty() {
+        println!("No backup disks have been recorded yet.");
+        return;
+    }
+
+    for disk in &report.disks {
+        println!(
+            "{} (GUID {}), last seen {}",
+            disk.pool, disk.guid, disk.last_seen
+        );
+        for (backup_dataset, snapshots) in &disk.datasets {
+            match snapshots.last() {
+                Some(newest) => println!(
+                    "  {}: {} snapshots, newest {}",
+                    backup_dataset,
+                    snapshots.len(),
+                    newest
+                ),
+                None => println!("  {}: no snapshots", backup_dataset),
+            }
+        }
+        println!();
+    }
+}
+
+fn get_path(state_dir: &Path, guid: u64) -> PathBuf {
+    state_dir.join("catalog").join(format!("{}.json", guid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_should_keep_datasets_of_other_jobs() {
+        let state_dir =
+            std::env::temp_dir().join(format!("cantaloupe-catalog-{}", std::process::id()));
+        let snapshots = vec![
+            Snapshot::new("usb/tank/os@2022-09-02-0300-00-TEST"),
+            Snapshot::new("usb/tank/os@2022-09-01-0300-00-TEST"),
+            Snapshot::new("usb/tank/var@2022-09-01-0300-00-TEST"),
+            Snapshot::new("tank/os@2022-09-02-0300-00-TEST"),
+        ];
+
+        let first = record(
+            &state_dir,
+            "usb",
+            42,
+            &[String::from("usb/tank/var")],
+            &snapshots,
+            Local::now(),
+        );
+        let second = record(
+            &state_dir,
+            "usb",
+            42,
+            &[String::from("usb/tank/os")],
+            &snapshots,
+            Local::now(),
+        );
+        let disks = load_all(&state_dir);
+        let _ = fs::remove_dir_all(&state_dir);
+
+        assert!(first.is_ok());
+        assert!(second.is_ok());
+        assert_eq!(disks.len(), 1);
+        assert_eq!(disks[0].guid, "42");
+        assert_eq!(
+            disks[0].datasets["usb/tank/os"],
+            vec![
+                "usb/tank/os@2022-09-01-0300-00-TEST",
+                "usb/tank/os@2022-09-02-0300-00-TEST"
+            ]
+        );
+        assert_eq!(disks[0].datasets["usb/tank/var"].len(), 1);
+    }
+}
@@ -0,0 +1,372 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+use std::fmt::Write;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+
+use crate::config::Job;
+use crate::helpers;
+use crate::snapshot::Snapshot;
+use crate::traits::SystemProvider;
+use crate::Cantaloupe;
+
+// The result of a check, with the exit codes monitoring plugins use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckState {
+    Ok,
+    Warning,
+    Critical,
+    Unknown,
+}
+
+impl CheckState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CheckState::Ok => "OK",
+            CheckState::Warning => "WARNING",
+            CheckState::Critical => "CRITICAL",
+            CheckState::Unknown => "UNKNOWN",
+        }
+    }
+
+    pub fn get_exit_code(&self) -> i32 {
+        match self {
+            CheckState::Ok => 0,
+            CheckState::Warning => 1,
+            CheckState::Critical => 2,
+            CheckState::Unknown => 3,
+        }
+    }
+
+    // Critical outranks unknown, which outranks warning, which outranks ok.
+    fn get_severity(&self) -> u8 {
+        match self {
+            CheckState::Ok => 0,
+            CheckState::Warning => 1,
+            CheckState::Unknown => 2,
+            CheckState::Critical => 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DatasetCheck {
+    pub dataset: String,
+    pub backup_dataset: String,
+    pub state: CheckState,
+    // The age of the newest labeled snapshot on the backup dataset.
+    pub age_seconds: Option<i64>,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Thresholds {
+    pub warning: Duration,
+    pub critical: Duration,
+}
+
+// Checks how old the newest backup snapshot of every dataset of the job is.
+// Only reads the list of snapshots.
+pub fn check_job(
+    system: &impl SystemProvider,
+    job: &Job,
+    thresholds: &Thresholds,
+    now: NaiveDateTime,
+) -> Vec<DatasetCheck> {
+    let imported: Vec<bool> = job
+        .backup_pools
+        .iter()
+        .map(|backup_pool| system.is_pool_imported(backup_pool))
+        .collect();
+    let snapshots = if imported.contains(&true) {
+        system.get_all_snapshots()
+    } else {
+        Vec::new()
+    };
+
+    let mut checks = Vec::new();
+    for (backup_pool, backup_pool_imported) in job.backup_pools.iter().zip(imported) {
+        for dataset in &job.datasets {
+            checks.push(check_dataset(
+                &snapshots,
+                job,
+                backup_pool,
+                backup_pool_imported,
+                dataset,
+                thresholds,
+                now,
+            ));
+        }
+    }
+    checks
+}
+
+fn check_dataset(
+    snapshots: &[Snapshot],
+    job: &Job,
+    backup_pool: &str,
+    backup_pool_imported: bool,
+    dataset: &str,
+    thresholds: &Thresholds,
+    now: NaiveDateTime,
+) -> DatasetCheck {
+    let backup_dataset = job.get_backup_dataset(backup_pool, dataset);
+    let mut check = DatasetCheck {
+        dataset: String::from(dataset),
+        backup_dataset: backup_dataset.clone(),
+        state: CheckState::Unknown,
+        age_seconds: None,
+        message: String::new(),
+    };
+
+    if !backup_pool_imported {
+        check.message = format!("{} pool is not imported", backup_pool);
+        return check;
+    }
+
+    let program = Cantaloupe::new_with_backup_dataset(
+        snapshots,
+        backup_pool,
+        &backup_dataset,
+        dataset,
+        &job.label,
+    );
+    let latest = match program.get_latest_backup_snapshot() {
+        Some(latest) => latest,
+        None => {
+            check.state = CheckState::Critical;
+            check.message = format!("no backup snapshots labeled {}", job.label);
+            return check;
+        }
+    };
+    let time = match latest.get_time() {
+        Some(time) => time,
+        None => {
+            check.message = format!("can't parse the time of {}", latest);
+            return check;
+        }
+    };
+
+    let age = (now - time).num_seconds().max(0);
+    check.age_seconds = Some(age);
+    check.state = get_state(age as u64, thresholds);
+    check.message = format!(
+        "newest backup is {} old ({})",
+        helpers::format_duration(age as u64),
+        latest
+    );
+    check
+}
+
+fn get_state(age: u64, thresholds: &Thresholds) -> CheckState {
+    if age >= thresholds.critical.as_secs() {
+        CheckState::Critical
+    } else if age >= thresholds.warning.as_secs() {
+        CheckState::Warning
+    } else {
+        CheckState::Ok
+    }
+}
+
+// Gets the overall state and the plugin output: a status line with
+// performance data, followed by one line per dataset.
+pub fn get_output(checks: &[DatasetCheck], thresholds: &Thresholds) -> (CheckState, String) {
+    let state = checks
+        .iter()
+        .map(|check| check.state)
+        .max_by_key(|state| state.get_severity())
+        .unwrap_or(CheckState::Unknown);
+
+    let problems: Vec<_> = checks
+        .iter()
+        .filter(|check| check.state != CheckState::Ok)
+        .map(|check| format!("{}: {}", check.dataset, check.message))
+        .collect();
+    let summary = if checks.is_empty() {
+        String::from("no datasets to check")
+    } else if problems.is_empty() {
+        format!(
+            "{} datasets backed up within {}",
+            checks.len(),
+            helpers::format_duration(thresholds.warning.as_secs())
+        )
+    } else {
+        problems.join("; ")
+    };
+
+    let performance_data: Vec<_> = checks
+        .iter()
+        .filter_map(|check| {
+            check.age_seconds.map(|age| {
+                format!(
+                    "'{}'={}s;{};{};0",
+                    check.backup_dataset,
+                    age,
+                    thresholds.warning.as_secs(),
+                    thresholds.critical.as_secs()
+                )
+            })
+        })
+        .collect();
+
+    let mut output = format!("CANTALOUPE {} - {}", state.as_str(), summary);
+    if !performance_data.is_empty() {
+        let _ = write!(output, " | {}", performance_data.join(" "));
+    }
+    for check in checks {
+        let _ = write!(
+            output,
+            "\n{} -> {}: {}, {}",
+            check.dataset,
+            check.backup_dataset,
+            check.state.as_str(),
+            check.message
+        );
+    }
+    (state, output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeSystem;
+
+    fn at(value: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn get_thresholds() -> Thresholds {
+        Thresholds {
+            warning: Duration::from_secs(24 * 60 * 60),
+            critical: Duration::from_secs(48 * 60 * 60),
+        }
+    }
+
+    fn get_example_system() -> FakeSystem {
+        FakeSystem::new_with_snaps(vec![
+            Snapshot::new("tank/os@2022-09-03-0300-00-TEST"),
+            Snapshot::new("backup/tank/os@2022-09-03-0300-00-TEST"),
+            Snapshot::new("tank/var@2022-09-03-0300-00-TEST"),
+            Snapshot::new("backup/tank/var@2022-09-01-0300-00-TEST"),
+        ])
+    }
+
+    #[test]
+    fn test_check_job_should_compare_ages_with_thresholds() {
+        let system = get_example_system();
+        let job = Job::new(
+            "backup",
+            "TEST",
+            &[
+                String::from("tank/os"),
+                String::from("tank/var"),
+                String::from("tank/home"),
+            ],
+        );
+
+        let checks = check_job(&system, &job, &get_thresholds(), at("2022-09-03 04:00:00"));
+
+        assert_eq!(checks[0].state, CheckState::Ok);
+        assert_eq!(checks[0].age_seconds, Some(3600));
+        assert_eq!(checks[1].state, CheckState::Critical);
+        assert_eq!(checks[2].state, CheckState::Critical);
+        assert_eq!(checks[2].age_seconds, None);
+    }
+
+    #[test]
+    fn test_check_job_should_be_unknown_without_backup_pool() {
+        let mut system = get_example_system();
+        system.is_pool_imported = false;
+        let job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+
+        let checks = check_job(&system, &job, &get_thresholds(), at("2022-09-03 04:00:00"));
+
+        assert_eq!(checks[0].state, CheckState::Unknown);
+    }
+
+    #[test]
+    fn test_check_job_should_check_every_backup_pool() {
+        let mut system = get_example_system();
+        system
+            .snapshots
+            .push(Snapshot::new("usb/tank/os@2022-09-01-0300-00-TEST"));
+        let mut job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        job.backup_pools.push(String::from("usb"));
+
+        let checks = check_job(&system, &job, &get_thresholds(), at("2022-09-03 04:00:00"));
+
+        assert_eq!(checks.len(), 2);
+        assert_eq!(checks[0].backup_dataset, "backup/tank/os");
+        assert_eq!(checks[0].state, CheckState::Ok);
+        assert_eq!(checks[1].backup_dataset, "usb/tank/os");
+        assert_eq!(checks[1].state, CheckState::Critical);
+    }
+
+    #[test]
+    fn test_get_output_should_include_performance_data() {
+        let system = get_example_system();
+        let job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        let checks = check_job(&system, &job, &get_thresholds(), at("2022-09-04 05:00:00"));
+
+        let (state, output) = get_output(&checks, &get_thresholds());
+
+        assert_eq!(state, CheckState::Warning);
+        assert_eq!(state.get_exit_code(), 1);
+        assert!(output.starts_with(
+            "CANTALOUPE WARNING - tank/os: newest backup is 1d2h old (backup/tank/os@2022-09-03-0300-00-TEST) | 'backup/tank/os'=93600s;86400;172800;0\n"
+        ));
+    }
+
+    #[test]
+    fn test_get_output_should_report_worst_state() {
+        let check = |state| DatasetCheck {
+            dataset: String::from("tank/os"),
+            backup_dataset: String::from("backup/tank/os"),
+            state,
+            age_seconds: None,
+            message: String::new(),
+        };
+
+        let (unknown, _) = get_output(
+            &[check(CheckState::Warning), check(CheckState::Unknown)],
+            &get_thresholds(),
+        );
+        let (critical, _) = get_output(
+            &[check(CheckState::Unknown), check(CheckState::Critical)],
+            &get_thresholds(),
+        );
+        let (ok, output) = get_output(&[check(CheckState::Ok)], &get_thresholds());
+
+        assert_eq!(unknown, CheckState::Unknown);
+        assert_eq!(critical, CheckState::Critical);
+        assert_eq!(ok, CheckState::Ok);
+        assert_eq!(
+            output,
+            "CANTALOUPE OK - 1 datasets backed up within 1d\ntank/os -> backup/tank/os: OK, "
+        );
+    }
+}
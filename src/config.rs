@@ -0,0 +1,1548 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::bandwidth::{self, Bandwidth, BandwidthLimit, TimeWindow};
+use crate::executor::{Parallelism, Timeouts};
+use crate::helpers;
+use crate::hooks::{Hook, HookFailurePolicy, HookPoint};
+use crate::notify::{self, Notification, Notifier, Trigger};
+use crate::pools::{HealthPolicy, ImportOptions, ScrubOptions, POOL_STATES};
+use crate::retry::{ErrorClass, RetryPolicies, RetryPolicy};
+use crate::schedule::Schedule;
+use crate::state;
+
+// A fully validated configuration file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub jobs: BTreeMap<String, Job>,
+    // Sent after every job that runs from this file.
+    pub notifications: Vec<Notification>,
+    // Where the results of previous runs are kept.
+    pub state_dir: PathBuf,
+    pub metrics: MetricsOptions,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsOptions {
+    // A node_exporter textfile that is rewritten after every run.
+    pub textfile: Option<PathBuf>,
+    // Where /metrics is served from in daemon mode.
+    pub listen: Option<SocketAddr>,
+}
+
+// Everything needed to replicate a set of datasets into one or more backup
+// pools.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Job {
+    pub backup_pools: Vec<String>,
+    pub label: String,
+    pub datasets: Vec<String>,
+    pub mapping: BTreeMap<String, String>,
+    pub send_flags: Vec<String>,
+    pub retention: Option<Retention>,
+    // When the job runs in daemon mode. Jobs without one are only run on
+    // demand.
+    pub schedule: Option<Schedule>,
+    // Whether to take a new snapshot of each dataset before replicating.
+    pub snapshot: Option<SnapshotOptions>,
+    // Whether to continue backups that were made with another label by
+    // sending incrementally from the newest snapshot both sides share.
+    pub adopt: bool,
+    // Whether a single 'zfs send' stream is fed into every backup pool that
+    // needs the same snapshots.
+    pub tee: bool,
+    // Whether to import the backup pools before the run (and export them
+    // again afterwards).
+    pub import: Option<ImportOptions>,
+    // The GUIDs that each backup pool may have. Pools with registered GUIDs
+    // are only written to when they match one of them.
+    pub pool_guids: BTreeMap<String, Vec<u64>>,
+    // Which backup pools are too unhealthy to be written to.
+    pub health: HealthPolicy,
+    // Whether to scrub the backup pools once everything has been sent.
+    pub scrub: Option<ScrubOptions>,
+    // How many datasets are replicated at once.
+    pub parallelism: Parallelism,
+    // How fast the send streams may go.
+    pub bandwidth: Bandwidth,
+    // When sends that take too long or stall are stopped.
+    pub timeouts: Timeouts,
+    // How failed sends are retried, by the class of their error.
+    pub retry: RetryPolicies,
+    pub hooks: Vec<Hook>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotOptions {
+    // Also snapshot the descendants of each dataset.
+    #[serde(default)]
+    pub recursive: bool,
+    // Snapshot all of the datasets in a single 'zfs snapshot' call.
+    #[serde(default)]
+    pub atomic: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Retention {
+    // How many labeled snapshots to keep on the backup dataset.
+    pub keep: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    pub path: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => {
+                write!(f, "{}:{}:{}: {}", self.path, line, column, self.message)
+            }
+            _ => write!(f, "{}: {}", self.path, self.message),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    state_dir: Option<String>,
+    jobs: Spanned<BTreeMap<String, Spanned<RawJob>>>,
+    #[serde(default)]
+    notifications: Vec<Spanned<RawNotification>>,
+    metrics: Option<RawMetrics>,
+    bandwidth: Option<Spanned<RawBandwidth>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawMetrics {
+    textfile: Option<String>,
+    listen: Option<Spanned<String>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum NotifierKind {
+    Mail,
+    Webhook,
+    Command,
+}
+
+impl Display for NotifierKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let name = match self {
+            NotifierKind::Mail => "mail",
+            NotifierKind::Webhook => "webhook",
+            NotifierKind::Command => "command",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawNotification {
+    #[serde(rename = "type")]
+    kind: NotifierKind,
+    #[serde(default)]
+    on: Trigger,
+    to: Option<Spanned<Vec<String>>>,
+    from: Option<Spanned<String>>,
+    sendmail: Option<Spanned<String>>,
+    url: Option<Spanned<String>>,
+    command: Option<Spanned<String>>,
+    timeout: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawJob {
+    backup_pool: Option<Spanned<String>>,
+    backup_pools: Option<Spanned<Vec<Spanned<String>>>>,
+    label: Spanned<String>,
+    datasets: Spanned<Vec<Spanned<String>>>,
+    #[serde(default)]
+    mapping: BTreeMap<String, Spanned<String>>,
+    #[serde(default)]
+    send_flags: Vec<Spanned<String>>,
+    retention: Option<RawRetention>,
+    schedule: Option<Spanned<String>>,
+    snapshot: Option<SnapshotOptions>,
+    #[serde(default)]
+    adopt: bool,
+    #[serde(default)]
+    tee: bool,
+    import: Option<RawImport>,
+    #[serde(default)]
+    pool_guids: BTreeMap<String, Spanned<Vec<Spanned<String>>>>,
+    health: Option<RawHealth>,
+    scrub: Option<RawScrub>,
+    parallel: Option<RawParallel>,
+    timeouts: Option<RawTimeouts>,
+    // Retry policies by error class.
+    #[serde(default)]
+    retry: BTreeMap<String, Spanned<RawRetry>>,
+    // Bandwidth limits by source dataset.
+    #[serde(default)]
+    bandwidth: BTreeMap<String, Spanned<RawBandwidth>>,
+    #[serde(default)]
+    hooks: Vec<RawHook>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTimeouts {
+    dataset: Option<Spanned<String>>,
+    stall: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRetry {
+    retries: Spanned<u32>,
+    delay: Option<Spanned<String>>,
+    max_delay: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBandwidth {
+    limit: Option<Spanned<String>>,
+    #[serde(default)]
+    schedule: Vec<RawScheduledRate>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawScheduledRate {
+    between: Spanned<String>,
+    limit: Spanned<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawParallel {
+    datasets: Option<Spanned<usize>>,
+    per_pool: Option<Spanned<usize>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawScrub {
+    poll_interval: Option<Spanned<String>>,
+    timeout: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawHealth {
+    refuse: Option<Vec<Spanned<String>>>,
+    max_capacity: Option<Spanned<u8>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawImport {
+    altroot: Option<Spanned<String>>,
+    // GUIDs are kept as strings since they don't fit in a TOML integer.
+    #[serde(default)]
+    guids: BTreeMap<String, Spanned<String>>,
+    export: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawHook {
+    when: HookPoint,
+    command: Spanned<String>,
+    timeout: Option<Spanned<String>>,
+    #[serde(default)]
+    on_failure: HookFailurePolicy,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRetention {
+    keep: Spanned<usize>,
+}
+
+impl Job {
+    pub fn new(backup_pool: &str, label: &str, datasets: &[String]) -> Self {
+        Self {
+            backup_pools: vec![String::from(backup_pool)],
+            label: String::from(label),
+            datasets: datasets.to_vec(),
+            mapping: BTreeMap::new(),
+            send_flags: Vec::new(),
+            retention: None,
+            schedule: None,
+            snapshot: None,
+            adopt: false,
+            tee: false,
+            import: None,
+            pool_guids: BTreeMap::new(),
+            health: HealthPolicy::default(),
+            scrub: None,
+            parallelism: Parallelism::default(),
+            bandwidth: Bandwidth::default(),
+            timeouts: Timeouts::default(),
+            retry: RetryPolicies::new(),
+            hooks: Vec::new(),
+        }
+    }
+
+    // Replaces the backup pools (e.g. with the ones given on the command
+    // line), checking them like the configuration file does. The targets of
+    // a mapping name their backup pool, so jobs with one can't be moved.
+    pub fn set_backup_pools(&mut self, backup_pools: Vec<String>) -> Result<(), String> {
+        if backup_pools.is_empty() {
+            return Err(String::from("At least one backup pool is required."));
+        }
+        if let Some(name) = backup_pools.iter().find(|name| !is_valid_pool_name(name)) {
+            return Err(format!("'{}' is not a valid pool name.", name));
+        }
+        if !self.mapping.is_empty() && backup_pools != self.backup_pools {
+            return Err(format!(
+                "The backup pools of a job with a mapping can't be overridden, since its targets are in the '{}' pool.",
+                self.backup_pools.join(", ")
+            ));
+        }
+        self.backup_pools = backup_pools;
+        Ok(())
+    }
+
+    // Gets the dataset in the given backup pool that the source dataset
+    // will be replicated into, taking the job's mapping into account.
+    pub fn get_backup_dataset(&self, backup_pool: &str, source_dataset: &str) -> String {
+        helpers::get_mapped_backup_dataset(backup_pool, source_dataset, &self.mapping)
+    }
+
+    // Finds a source dataset that is one of the backup datasets of the job,
+    // or an ancestor or descendant of one, since replicating it would read
+    // what is being written. Returns it along with that backup dataset.
+    pub fn find_overlap(&self) -> Option<(&str, String)> {
+        let backup_datasets: Vec<String> = self
+            .backup_pools
+            .iter()
+            .flat_map(|backup_pool| {
+                self.datasets
+                    .iter()
+                    .map(|dataset| self.get_backup_dataset(backup_pool, dataset))
+            })
+            .collect();
+        self.datasets.iter().find_map(|dataset| {
+            backup_datasets
+                .iter()
+                .find(|backup_dataset| helpers::datasets_overlap(dataset, backup_dataset))
+                .map(|backup_dataset| (dataset.as_str(), backup_dataset.clone()))
+        })
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| ConfigError {
+            path: path.display().to_string(),
+            line: None,
+            column: None,
+            message: e.to_string(),
+        })?;
+        Self::parse(&path.display().to_string(), &contents)
+    }
+
+    pub fn parse(path: &str, contents: &str) -> Result<Self, ConfigError> {
+        let error = |span: Option<Range<usize>>, message: &str| {
+            let position = span.map(|span| get_line_and_column(contents, span.start));
+            ConfigError {
+                path: String::from(path),
+                line: position.map(|(line, _)| line),
+                column: position.map(|(_, column)| column),
+                message: String::from(message),
+            }
+        };
+
+        let raw: RawConfig = toml::from_str(contents).map_err(|e| error(e.span(), e.message()))?;
+
+        if raw.jobs.get_ref().is_empty() {
+            return Err(error(Some(raw.jobs.span()), "at least one job is required"));
+        }
+
+        let global = match &raw.bandwidth {
+            Some(bandwidth) => Some(
+                Self::validate_bandwidth(bandwidth)
+                    .map_err(|(span, message)| error(Some(span), &message))?,
+            ),
+            None => None,
+        };
+
+        let mut jobs = BTreeMap::new();
+        for (name, raw_job) in raw.jobs.into_inner() {
+            let mut job = Self::validate_job(&raw_job)
+                .map_err(|(span, message)| error(Some(span), &message))?;
+            job.bandwidth.global = global.clone();
+            jobs.insert(name, job);
+        }
+
+        let mut notifications = Vec::new();
+        for raw_notification in &raw.notifications {
+            let notification = Self::validate_notification(raw_notification)
+                .map_err(|(span, message)| error(Some(span), &message))?;
+            notifications.push(notification);
+        }
+
+        let mut metrics = MetricsOptions::default();
+        if let Some(raw_metrics) = &raw.metrics {
+            metrics.textfile = raw_metrics.textfile.as_ref().map(PathBuf::from);
+            if let Some(listen) = &raw_metrics.listen {
+                let address = listen.get_ref().parse::<SocketAddr>().map_err(|_| {
+                    error(
+                        Some(listen.span()),
+                        &format!(
+                            "'{}' is not a valid address. Expected <ip>:<port>",
+                            listen.get_ref()
+                        ),
+                    )
+                })?;
+                metrics.listen = Some(address);
+            }
+        }
+
+        Ok(Self {
+            jobs,
+            notifications,
+            metrics,
+            state_dir: PathBuf::from(raw.state_dir.as_deref().unwrap_or(state::DEFAULT_STATE_DIR)),
+        })
+    }
+
+    fn validate_notification(
+        raw: &Spanned<RawNotification>,
+    ) -> Result<Notification, (Range<usize>, String)> {
+        let notification = raw.get_ref();
+        let kind = notification.kind;
+
+        // Every type only accepts its own settings.
+        let span = |field: &Option<Spanned<String>>| field.as_ref().map(|field| field.span());
+        let fields = [
+            (
+                "to",
+                notification.to.as_ref().map(|to| to.span()),
+                kind == NotifierKind::Mail,
+            ),
+            ("from", span(&notification.from), kind == NotifierKind::Mail),
+            (
+                "sendmail",
+                span(&notification.sendmail),
+                kind == NotifierKind::Mail,
+            ),
+            (
+                "url",
+                span(&notification.url),
+                kind == NotifierKind::Webhook,
+            ),
+            (
+                "command",
+                span(&notification.command),
+                kind == NotifierKind::Command,
+            ),
+            (
+                "timeout",
+                span(&notification.timeout),
+                kind != NotifierKind::Mail,
+            ),
+        ];
+        for (name, span, allowed) in fields {
+            if let (Some(span), false) = (span, allowed) {
+                return Err((
+                    span,
+                    format!("'{}' doesn't apply to {} notifications", name, kind),
+                ));
+            }
+        }
+
+        let missing = |field: &str| (raw.span(), format!("'{}' is required", field));
+        let timeout = match &notification.timeout {
+            Some(timeout) => {
+                Some(helpers::parse_duration(timeout.get_ref()).map_err(|e| (timeout.span(), e))?)
+            }
+            None => None,
+        };
+
+        let notifier = match kind {
+            NotifierKind::Mail => {
+                let to = notification.to.as_ref().ok_or_else(|| missing("to"))?;
+                if to.get_ref().is_empty() {
+                    return Err((
+                        to.span(),
+                        String::from("at least one recipient is required"),
+                    ));
+                }
+                Notifier::Mail {
+                    to: to.get_ref().clone(),
+                    from: notification
+                        .from
+                        .as_ref()
+                        .map(|from| from.get_ref().clone()),
+                    sendmail: notification
+                        .sendmail
+                        .as_ref()
+                        .map(|sendmail| sendmail.get_ref().clone())
+                        .unwrap_or_else(|| String::from(notify::DEFAULT_SENDMAIL)),
+                }
+            }
+            NotifierKind::Webhook => {
+                let url = notification.url.as_ref().ok_or_else(|| missing("url"))?;
+                let value = url.get_ref();
+                if !value.starts_with("http://") && !value.starts_with("https://") {
+                    return Err((
+                        url.span(),
+                        format!("'{}' is not an http or https URL", value),
+                    ));
+                }
+                Notifier::Webhook {
+                    url: value.clone(),
+                    timeout: timeout.unwrap_or(notify::DEFAULT_TIMEOUT),
+                }
+            }
+            NotifierKind::Command => {
+                let command = notification
+                    .command
+                    .as_ref()
+                    .ok_or_else(|| missing("command"))?;
+                if command.get_ref().trim().is_empty() {
+                    return Err((command.span(), String::from("the command can't be empty")));
+                }
+                Notifier::Command {
+                    command: command.get_ref().clone(),
+                    timeout,
+                }
+            }
+        };
+
+        Ok(Notification {
+            on: notification.on,
+            notifier,
+        })
+    }
+
+    fn validate_job(spanned: &Spanned<RawJob>) -> Result<Job, (Range<usize>, String)> {
+        let raw = spanned.get_ref();
+        let pools: Vec<&Spanned<String>> = match (&raw.backup_pool, &raw.backup_pools) {
+            (Some(pool), None) => vec![pool],
+            (None, Some(pools)) if pools.get_ref().is_empty() => {
+                return Err((
+                    pools.span(),
+                    String::from("at least one backup pool is required"),
+                ));
+            }
+            (None, Some(pools)) => pools.get_ref().iter().collect(),
+            (Some(pool), Some(_)) => {
+                return Err((
+                    pool.span(),
+                    String::from("use either 'backup_pool' or 'backup_pools', not both"),
+                ));
+            }
+            (None, None) => {
+                return Err((
+                    spanned.span(),
+                    String::from("'backup_pool' or 'backup_pools' is required"),
+                ));
+            }
+        };
+
+        let mut backup_pools: Vec<String> = Vec::new();
+        for pool in &pools {
+            let name = pool.get_ref();
+            if !is_valid_pool_name(name) {
+                return Err((pool.span(), format!("'{}' is not a valid pool name", name)));
+            }
+            if backup_pools.contains(name) {
+                return Err((pool.span(), format!("'{}' is listed more than once", name)));
+            }
+            backup_pools.push(name.clone());
+        }
+
+        let label = raw.label.get_ref();
+        if !is_valid_name(label) || label.contains('/') {
+            return Err((
+                raw.label.span(),
+                format!("'{}' is not a valid label", label),
+            ));
+        }
+
+        if raw.datasets.get_ref().is_empty() {
+            return Err((
+                raw.datasets.span(),
+                String::from("at least one dataset is required"),
+            ));
+        }
+
+        let mut datasets = Vec::new();
+        let mut seen = HashSet::new();
+        for dataset in raw.datasets.get_ref() {
+            let name = dataset.get_ref();
+            if !is_valid_name(name) {
+                return Err((
+                    dataset.span(),
+                    format!("'{}' is not a valid dataset name", name),
+                ));
+            }
+            if !seen.insert(name) {
+                return Err((
+                    dataset.span(),
+                    format!("'{}' is listed more than once", name),
+                ));
+            }
+            datasets.push(name.clone());
+        }
+
+        let mut mapping = BTreeMap::new();
+        for (source, target) in &raw.mapping {
+            let name = target.get_ref();
+            if backup_pools.len() > 1 {
+                return Err((
+                    target.span(),
+                    String::from("mappings can only be used with a single backup pool"),
+                ));
+            }
+            let backup_pool = &backup_pools[0];
+            if !is_valid_name(source) {
+                return Err((
+                    target.span(),
+                    format!("'{}' is not a valid dataset name", source),
+                ));
+            }
+            if !is_valid_name(name) || !name.starts_with(&format!("{}/", backup_pool)) {
+                return Err((
+                    target.span(),
+                    format!(
+                        "'{}' must be a dataset inside the '{}' pool",
+                        name, backup_pool
+                    ),
+                ));
+            }
+            mapping.insert(source.clone(), name.clone());
+        }
+
+        let mut send_flags = Vec::new();
+        for flag in &raw.send_flags {
+            if !flag.get_ref().starts_with('-') {
+                return Err((flag.span(), format!("'{}' is not a flag", flag.get_ref())));
+            }
+            send_flags.push(flag.get_ref().clone());
+        }
+
+        let retention = match &raw.retention {
+            Some(retention) if *retention.keep.get_ref() == 0 => {
+                return Err((
+                    retention.keep.span(),
+                    String::from("must keep at least one snapshot"),
+                ));
+            }
+            Some(retention) => Some(Retention {
+                keep: *retention.keep.get_ref(),
+            }),
+            None => None,
+        };
+
+        let schedule = match &raw.schedule {
+            Some(schedule) => Some(
+                schedule
+                    .get_ref()
+                    .parse::<Schedule>()
+                    .map_err(|e| (schedule.span(), e))?,
+            ),
+            None => None,
+        };
+
+        let mut pool_guids = BTreeMap::new();
+        for (pool, guids) in &raw.pool_guids {
+            if !backup_pools.contains(pool) {
+                return Err((
+                    guids.span(),
+                    format!("'{}' is not one of the backup pools of this job", pool),
+                ));
+            }
+            if guids.get_ref().is_empty() {
+                return Err((guids.span(), String::from("at least one GUID is required")));
+            }
+            let mut values = Vec::new();
+            for guid in guids.get_ref() {
+                values.push(parse_guid(guid)?);
+            }
+            pool_guids.insert(pool.clone(), values);
+        }
+
+        let import = match &raw.import {
+            Some(import) => Some(Self::validate_import(import, &backup_pools, &pool_guids)?),
+            None => None,
+        };
+
+        let health = match &raw.health {
+            Some(health) => Self::validate_health(health)?,
+            None => HealthPolicy::default(),
+        };
+
+        let scrub = match &raw.scrub {
+            Some(scrub) => Some(Self::validate_scrub(scrub)?),
+            None => None,
+        };
+
+        let parallelism = match &raw.parallel {
+            Some(parallel) => Self::validate_parallel(parallel)?,
+            None => Parallelism::default(),
+        };
+
+        let mut timeouts = Timeouts::default();
+        if let Some(raw_timeouts) = &raw.timeouts {
+            for (value, field) in [
+                (&raw_timeouts.dataset, &mut timeouts.dataset),
+                (&raw_timeouts.stall, &mut timeouts.stall),
+            ] {
+                if let Some(value) = value {
+                    *field = Some(
+                        helpers::parse_duration(value.get_ref()).map_err(|e| (value.span(), e))?,
+                    );
+                }
+            }
+        }
+
+        let mut retry = RetryPolicies::new();
+        for (class, policy) in &raw.retry {
+            let class = class
+                .parse::<ErrorClass>()
+                .ok()
+                .filter(ErrorClass::is_retryable)
+                .ok_or_else(|| {
+                    (
+                        policy.span(),
+                        format!(
+                            "'{}' is not an error class that can be retried (busy, connection, stalled or other)",
+                            class
+                        ),
+                    )
+                })?;
+            retry.insert(class, Self::validate_retry(policy.get_ref())?);
+        }
+
+        let mut bandwidth = Bandwidth::default();
+        for (dataset, limit) in &raw.bandwidth {
+            if !datasets.contains(dataset) {
+                return Err((
+                    limit.span(),
+                    format!("'{}' is not one of the datasets of this job", dataset),
+                ));
+            }
+            bandwidth
+                .datasets
+                .insert(dataset.clone(), Self::validate_bandwidth(limit)?);
+        }
+
+        let mut hooks = Vec::new();
+        for hook in &raw.hooks {
+            if hook.command.get_ref().trim().is_empty() {
+                return Err((
+                    hook.command.span(),
+                    String::from("the command can't be empty"),
+                ));
+            }
+            let timeout = match &hook.timeout {
+                Some(timeout) => Some(
+                    helpers::parse_duration(timeout.get_ref()).map_err(|e| (timeout.span(), e))?,
+                ),
+                None => None,
+            };
+            hooks.push(Hook {
+                when: hook.when,
+                command: hook.command.get_ref().clone(),
+                timeout,
+                on_failure: hook.on_failure,
+            });
+        }
+
+        let job = Job {
+            backup_pools,
+            label: label.clone(),
+            datasets,
+            mapping,
+            send_flags,
+            retention,
+            schedule,
+            snapshot: raw.snapshot,
+            adopt: raw.adopt,
+            tee: raw.tee,
+            import,
+            pool_guids,
+            health,
+            scrub,
+            parallelism,
+            bandwidth,
+            timeouts,
+            retry,
+            hooks,
+        };
+
+        if let Some((dataset, backup_dataset)) = job.find_overlap() {
+            let span = raw
+                .datasets
+                .get_ref()
+                .iter()
+                .find(|raw_dataset| raw_dataset.get_ref() == dataset)
+                .map_or_else(|| raw.datasets.span(), Spanned::span);
+            return Err((
+                span,
+                format!(
+                    "'{}' overlaps with the backup dataset '{}'",
+                    dataset, backup_dataset
+                ),
+            ));
+        }
+        Ok(job)
+    }
+
+    fn validate_scrub(raw: &RawScrub) -> Result<ScrubOptions, (Range<usize>, String)> {
+        let mut options = ScrubOptions::default();
+        if let Some(poll_interval) = &raw.poll_interval {
+            options.poll_interval = helpers::parse_duration(poll_interval.get_ref())
+                .map_err(|e| (poll_interval.span(), e))?;
+        }
+        if let Some(timeout) = &raw.timeout {
+            options.timeout =
+                Some(helpers::parse_duration(timeout.get_ref()).map_err(|e| (timeout.span(), e))?);
+        }
+        Ok(options)
+    }
+
+    fn validate_bandwidth(
+        raw: &Spanned<RawBandwidth>,
+    ) -> Result<BandwidthLimit, (Range<usize>, String)> {
+        let bandwidth = raw.get_ref();
+        if bandwidth.limit.is_none() && bandwidth.schedule.is_empty() {
+            return Err((
+                raw.span(),
+                String::from("'limit' or 'schedule' is required"),
+            ));
+        }
+        let parse_rate = |rate: &Spanned<String>| {
+            bandwidth::parse_rate(rate.get_ref()).map_err(|e| (rate.span(), e))
+        };
+
+        let mut limit = BandwidthLimit {
+            rate: None,
+            schedule: Vec::new(),
+        };
+        if let Some(rate) = &bandwidth.limit {
+            limit.rate = Some(parse_rate(rate)?);
+        }
+        for scheduled in &bandwidth.schedule {
+            let window = scheduled
+                .between
+                .get_ref()
+                .parse::<TimeWindow>()
+                .map_err(|e| (scheduled.between.span(), e))?;
+            limit.schedule.push((window, parse_rate(&scheduled.limit)?));
+        }
+        Ok(limit)
+    }
+
+    fn validate_retry(raw: &RawRetry) -> Result<RetryPolicy, (Range<usize>, String)> {
+        let mut policy = RetryPolicy {
+            retries: *raw.retries.get_ref(),
+            ..RetryPolicy::default()
+        };
+        for (value, field) in [
+            (&raw.delay, &mut policy.delay),
+            (&raw.max_delay, &mut policy.max_delay),
+        ] {
+            if let Some(value) = value {
+                *field = helpers::parse_duration(value.get_ref()).map_err(|e| (value.span(), e))?;
+            }
+        }
+        match &raw.max_delay {
+            Some(max_delay) if policy.max_delay < policy.delay => {
+                return Err((
+                    max_delay.span(),
+                    String::from("'max_delay' must not be shorter than 'delay'"),
+                ));
+            }
+            Some(_) => {}
+            None => policy.max_delay = policy.max_delay.max(policy.delay),
+        }
+        Ok(policy)
+    }
+
+    fn validate_parallel(raw: &RawParallel) -> Result<Parallelism, (Range<usize>, String)> {
+        let mut parallelism = Parallelism::default();
+        for (name, value, field) in [
+            ("datasets", &raw.datasets, &mut parallelism.datasets),
+            ("per_pool", &raw.per_pool, &mut parallelism.per_pool),
+        ] {
+            if let Some(value) = value {
+                if *value.get_ref() == 0 {
+                    return Err((value.span(), format!("'{}' must be at least 1", name)));
+                }
+                *field = *value.get_ref();
+            }
+        }
+        Ok(parallelism)
+    }
+
+    fn validate_health(raw: &RawHealth) -> Result<HealthPolicy, (Range<usize>, String)> {
+        let mut policy = HealthPolicy::default();
+        if let Some(refuse) = &raw.refuse {
+            policy.refuse.clear();
+            for state in refuse {
+                let value = state.get_ref().to_uppercase();
+                if value == "ONLINE" {
+                    return Err((state.span(), String::from("healthy pools can't be refused")));
+                }
+                if !POOL_STATES.contains(&value.as_str()) {
+                    return Err((
+                        state.span(),
+                        format!(
+                            "'{}' is not a pool state (expected one of {})",
+                            state.get_ref(),
+                            POOL_STATES[1..].join(", ")
+                        ),
+                    ));
+                }
+                if !policy.refuse.contains(&value) {
+                    policy.refuse.push(value);
+                }
+            }
+        }
+        if let Some(max_capacity) = &raw.max_capacity {
+            if !(1..=100).contains(max_capacity.get_ref()) {
+                return Err((
+                    max_capacity.span(),
+                    String::from("'max_capacity' must be between 1 and 100"),
+                ));
+            }
+            policy.max_capacity = *max_capacity.get_ref();
+        }
+        Ok(policy)
+    }
+
+    fn validate_import(
+        raw: &RawImport,
+        backup_pools: &[String],
+        pool_guids: &BTreeMap<String, Vec<u64>>,
+    ) -> Result<ImportOptions, (Range<usize>, String)> {
+        let altroot = match &raw.altroot {
+            Some(altroot) if !altroot.get_ref().starts_with('/') => {
+                return Err((
+                    altroot.span(),
+                    format!("'{}' must be an absolute path", altroot.get_ref()),
+                ));
+            }
+            Some(altroot) => Some(PathBuf::from(altroot.get_ref())),
+            None => None,
+        };
+
+        let mut guids = BTreeMap::new();
+        for (pool, guid) in &raw.guids {
+            if !backup_pools.contains(pool) {
+                return Err((
+                    guid.span(),
+                    format!("'{}' is not one of the backup pools of this job", pool),
+                ));
+            }
+            let value = parse_guid(guid)?;
+            if pool_guids
+                .get(pool)
+                .is_some_and(|expected| !expected.contains(&value))
+            {
+                return Err((
+                    guid.span(),
+                    format!(
+                        "'{}' isn't one of the GUIDs registered for '{}'",
+                        value, pool
+                    ),
+                ));
+            }
+            guids.insert(pool.clone(), value);
+        }
+
+        Ok(ImportOptions {
+            altroot,
+            guids,
+            export: raw.export.unwrap_or(true),
+        })
+    }
+}
+
+fn parse_guid(guid: &Spanned<String>) -> Result<u64, (Range<usize>, String)> {
+    match guid.get_ref().parse::<u64>() {
+        Ok(value) if value != 0 => Ok(value),
+        _ => Err((
+            guid.span(),
+            format!("'{}' is not a valid pool GUID", guid.get_ref()),
+        )),
+    }
+}
+
+fn is_valid_pool_name(name: &str) -> bool {
+    is_valid_name(name) && !name.contains('/')
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('/')
+        && !name.ends_with('/')
+        && !name.contains('@')
+        && !name.contains(char::is_whitespace)
+}
+
+// Converts a byte offset into a 1-based line and column.
+fn get_line_and_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn get_example_config() -> &'static str {
+        r#"
+[jobs.nightly]
+backup_pool = "backup"
+label = "CHECKPOINT"
+datasets = ["tank/os/main", "tank/var/log"]
+send_flags = ["-w"]
+adopt = true
+
+[jobs.nightly.mapping]
+"tank/os" = "backup/os"
+
+[jobs.nightly.retention]
+keep = 30
+
+[jobs.nightly.snapshot]
+atomic = true
+
+[jobs.nightly.parallel]
+datasets = 4
+
+[jobs.nightly.bandwidth."tank/var/log"]
+limit = "10M"
+
+[[jobs.nightly.hooks]]
+when = "run_start"
+command = "/usr/local/bin/quiesce"
+timeout = "5m"
+
+[[jobs.nightly.hooks]]
+when = "after_send"
+command = "logger sent"
+on_failure = "ignore"
+
+[jobs.usb]
+backup_pools = ["usb1", "usb2"]
+label = "CHECKPOINT"
+datasets = ["tank/home"]
+tee = true
+pool_guids = { usb1 = ["111", "222"], usb2 = ["12345678901234567890"] }
+
+[jobs.usb.import]
+altroot = "/mnt/usb"
+guids = { usb2 = "12345678901234567890" }
+
+[jobs.usb.health]
+refuse = ["faulted", "SUSPENDED"]
+max_capacity = 90
+
+[jobs.usb.scrub]
+timeout = "12h"
+
+[jobs.usb.timeouts]
+dataset = "6h"
+stall = "10m"
+
+[jobs.usb.retry.busy]
+retries = 5
+delay = "1m"
+
+[[notifications]]
+type = "mail"
+to = ["root@localhost"]
+
+[[notifications]]
+type = "webhook"
+on = "change"
+url = "https://example.com/hooks/cantaloupe"
+timeout = "10s"
+
+[metrics]
+textfile = "/var/lib/node_exporter/cantaloupe.prom"
+listen = "127.0.0.1:9588"
+
+[bandwidth]
+schedule = [{ between = "08:00-18:00", limit = "50M" }]
+"#
+    }
+
+    #[test]
+    fn test_parse_should_return_jobs() {
+        let config = Config::parse("test.toml", get_example_config()).unwrap();
+
+        let nightly = &config.jobs["nightly"];
+        let usb = &config.jobs["usb"];
+
+        assert_eq!(config.jobs.len(), 2);
+        assert_eq!(nightly.backup_pools, vec!["backup"]);
+        assert_eq!(nightly.datasets, vec!["tank/os/main", "tank/var/log"]);
+        assert_eq!(nightly.send_flags, vec!["-w"]);
+        assert_eq!(nightly.retention, Some(Retention { keep: 30 }));
+        assert_eq!(
+            nightly.snapshot,
+            Some(SnapshotOptions {
+                recursive: false,
+                atomic: true
+            })
+        );
+        assert_eq!(usb.mapping.len(), 0);
+        assert_eq!(usb.backup_pools, vec!["usb1", "usb2"]);
+        assert!(usb.tee);
+        assert!(!nightly.tee);
+        assert_eq!(nightly.import, None);
+        assert!(nightly.pool_guids.is_empty());
+        assert_eq!(usb.pool_guids["usb1"], vec![111, 222]);
+        assert_eq!(nightly.health, HealthPolicy::default());
+        assert_eq!(nightly.scrub, None);
+        assert_eq!(
+            nightly.parallelism,
+            Parallelism {
+                datasets: 4,
+                per_pool: 1
+            }
+        );
+        assert_eq!(usb.parallelism, Parallelism::default());
+        assert_eq!(nightly.timeouts, Timeouts::default());
+        assert_eq!(
+            usb.timeouts,
+            Timeouts {
+                dataset: Some(Duration::from_secs(6 * 60 * 60)),
+                stall: Some(Duration::from_secs(10 * 60)),
+            }
+        );
+        assert!(nightly.retry.is_empty());
+        assert_eq!(
+            usb.retry[&ErrorClass::Busy],
+            RetryPolicy {
+                retries: 5,
+                delay: Duration::from_secs(60),
+                max_delay: Duration::from_secs(10 * 60),
+            }
+        );
+        let business_hours = BandwidthLimit {
+            rate: None,
+            schedule: vec![("08:00-18:00".parse().unwrap(), 50 << 20)],
+        };
+        assert_eq!(usb.bandwidth.global, Some(business_hours.clone()));
+        assert!(usb.bandwidth.datasets.is_empty());
+        assert_eq!(nightly.bandwidth.global, Some(business_hours));
+        assert_eq!(
+            nightly.bandwidth.datasets["tank/var/log"],
+            BandwidthLimit {
+                rate: Some(10 << 20),
+                schedule: Vec::new(),
+            }
+        );
+        assert_eq!(
+            usb.scrub,
+            Some(ScrubOptions {
+                poll_interval: Duration::from_secs(60),
+                timeout: Some(Duration::from_secs(12 * 60 * 60)),
+            })
+        );
+        assert_eq!(
+            usb.health,
+            HealthPolicy {
+                refuse: vec![String::from("FAULTED"), String::from("SUSPENDED")],
+                max_capacity: 90,
+            }
+        );
+        assert_eq!(
+            usb.import,
+            Some(ImportOptions {
+                altroot: Some(PathBuf::from("/mnt/usb")),
+                guids: BTreeMap::from([(String::from("usb2"), 12345678901234567890)]),
+                export: true,
+            })
+        );
+        assert_eq!(nightly.hooks.len(), 2);
+        assert_eq!(nightly.hooks[0].when, HookPoint::RunStart);
+        assert_eq!(nightly.hooks[0].timeout, Some(Duration::from_secs(300)));
+        assert_eq!(nightly.hooks[0].on_failure, HookFailurePolicy::Skip);
+        assert_eq!(nightly.hooks[1].on_failure, HookFailurePolicy::Ignore);
+        assert!(nightly.adopt);
+        assert!(!usb.adopt);
+        assert_eq!(usb.retention, None);
+        assert_eq!(usb.snapshot, None);
+        assert_eq!(config.state_dir, PathBuf::from(state::DEFAULT_STATE_DIR));
+        assert_eq!(
+            config.metrics.listen,
+            Some("127.0.0.1:9588".parse().unwrap())
+        );
+        assert_eq!(config.notifications.len(), 2);
+        assert_eq!(config.notifications[0].on, Trigger::Failure);
+        assert_eq!(
+            config.notifications[0].notifier,
+            Notifier::Mail {
+                to: vec![String::from("root@localhost")],
+                from: None,
+                sendmail: String::from(notify::DEFAULT_SENDMAIL),
+            }
+        );
+        assert_eq!(config.notifications[1].on, Trigger::Change);
+        assert_eq!(
+            config.notifications[1].notifier,
+            Notifier::Webhook {
+                url: String::from("https://example.com/hooks/cantaloupe"),
+                timeout: Duration::from_secs(10),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_should_report_line_of_syntax_error() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"backup\"\nlabel = \n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(3));
+    }
+
+    #[test]
+    fn test_parse_should_report_line_of_unknown_field() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"backup\"\nlabel = \"TEST\"\ndatasets = [\"tank\"]\ncolour = \"red\"\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(5));
+        assert!(error.message.contains("colour"));
+    }
+
+    #[test]
+    fn test_parse_should_reject_dataset_overlapping_backup_datasets() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"tank\"\nlabel = \"TEST\"\ndatasets = [\n  \"tank/data\",\n  \"tank/replica\",\n]\n\n[jobs.nightly.mapping]\n\"tank/data\" = \"tank/replica/tank/data\"\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(6));
+        assert_eq!(error.column, Some(3));
+        assert_eq!(
+            error.to_string(),
+            "test.toml:6:3: 'tank/replica' overlaps with the backup dataset 'tank/replica/tank/data'"
+        );
+    }
+
+    #[test]
+    fn test_parse_should_allow_dataset_beside_backup_datasets_in_backup_pool() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"tank\"\nlabel = \"TEST\"\ndatasets = [\"tank/data\"]\n\n[jobs.nightly.mapping]\n\"tank/data\" = \"tank/replica/tank/data\"\n";
+
+        let config = Config::parse("test.toml", contents).unwrap();
+
+        assert_eq!(
+            config.jobs["nightly"].get_backup_dataset("tank", "tank/data"),
+            "tank/replica/tank/data"
+        );
+    }
+
+    #[test]
+    fn test_parse_should_reject_mapping_outside_backup_pool() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"backup\"\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\n\n[jobs.nightly.mapping]\n\"tank/os\" = \"other/os\"\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(7));
+    }
+
+    #[test]
+    fn test_parse_should_reject_zero_retention() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"backup\"\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\nretention = { keep = 0 }\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(5));
+        assert_eq!(error.message, "must keep at least one snapshot");
+    }
+
+    #[test]
+    fn test_parse_should_reject_invalid_schedule() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"backup\"\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\nschedule = \"0 25 * * *\"\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(5));
+        assert!(error.message.contains("25"));
+    }
+
+    #[test]
+    fn test_parse_should_reject_unknown_hook_point() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"backup\"\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\n\n[[jobs.nightly.hooks]]\nwhen = \"whenever\"\ncommand = \"true\"\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(7));
+    }
+
+    #[test]
+    fn test_parse_should_reject_settings_of_other_notification_types() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"backup\"\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\n\n[[notifications]]\ntype = \"mail\"\nto = [\"root\"]\nurl = \"https://example.com\"\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(9));
+        assert!(error.message.contains("'url' doesn't apply to mail"));
+    }
+
+    #[test]
+    fn test_parse_should_require_webhook_url() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"backup\"\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\n\n[[notifications]]\ntype = \"webhook\"\non = \"always\"\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.message, "'url' is required");
+    }
+
+    #[test]
+    fn test_parse_should_reject_invalid_metrics_address() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"backup\"\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\n\n[metrics]\nlisten = \"localhost\"\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(7));
+    }
+
+    #[test]
+    fn test_parse_should_require_jobs() {
+        let error = Config::parse("test.toml", "jobs = {}\n").unwrap_err();
+
+        assert_eq!(error.line, Some(1));
+    }
+
+    #[test]
+    fn test_get_backup_dataset_should_use_mapping() {
+        let config = Config::parse("test.toml", get_example_config()).unwrap();
+        let nightly = &config.jobs["nightly"];
+
+        assert_eq!(
+            nightly.get_backup_dataset("backup", "tank/os/main"),
+            "backup/os/main"
+        );
+        assert_eq!(
+            nightly.get_backup_dataset("backup", "tank/var/log"),
+            "backup/tank/var/log"
+        );
+    }
+
+    #[test]
+    fn test_parse_should_reject_both_backup_pool_and_backup_pools() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"backup\"\nbackup_pools = [\"usb1\"]\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(2));
+        assert!(error.message.contains("not both"));
+    }
+
+    #[test]
+    fn test_parse_should_require_a_backup_pool() {
+        let contents = "[jobs.nightly]\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.message, "'backup_pool' or 'backup_pools' is required");
+    }
+
+    #[test]
+    fn test_parse_should_reject_dataset_overlapping_any_backup_pool() {
+        let contents = "[jobs.nightly]\nbackup_pools = [\"usb1\", \"usb2\"]\nlabel = \"TEST\"\ndatasets = [\"tank/os\", \"usb2/tank\"]\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(
+            error.message,
+            "'usb2/tank' overlaps with the backup dataset 'usb2/tank/os'"
+        );
+    }
+
+    #[test]
+    fn test_parse_should_reject_guid_of_unknown_pool() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"usb\"\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\n\n[jobs.nightly.import]\nguids = { backup = \"123\" }\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(7));
+        assert!(error
+            .message
+            .contains("'backup' is not one of the backup pools"));
+    }
+
+    #[test]
+    fn test_parse_should_reject_import_guid_that_is_not_registered() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"usb\"\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\npool_guids = { usb = [\"111\"] }\n\n[jobs.nightly.import]\nguids = { usb = \"222\" }\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(8));
+        assert_eq!(
+            error.message,
+            "'222' isn't one of the GUIDs registered for 'usb'"
+        );
+    }
+
+    #[test]
+    fn test_parse_should_reject_invalid_guid() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"usb\"\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\n\n[jobs.nightly.import]\nexport = false\nguids = { usb = \"0x1234\" }\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(8));
+        assert_eq!(error.message, "'0x1234' is not a valid pool GUID");
+    }
+
+    #[test]
+    fn test_parse_should_reject_unknown_pool_state() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"usb\"\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\n\n[jobs.nightly.health]\nrefuse = [\"BROKEN\"]\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(7));
+        assert!(error.message.starts_with("'BROKEN' is not a pool state"));
+    }
+
+    #[test]
+    fn test_parse_should_reject_invalid_max_capacity() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"usb\"\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\n\n[jobs.nightly.health]\nmax_capacity = 0\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(7));
+        assert_eq!(error.message, "'max_capacity' must be between 1 and 100");
+    }
+
+    #[test]
+    fn test_parse_should_reject_zero_parallelism() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"usb\"\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\n\n[jobs.nightly.parallel]\ndatasets = 4\nper_pool = 0\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(8));
+        assert_eq!(error.message, "'per_pool' must be at least 1");
+    }
+
+    #[test]
+    fn test_parse_should_reject_retrying_errors_that_cant_be_retried() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"usb\"\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\n\n[jobs.nightly.retry.no_space]\nretries = 3\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(6));
+        assert_eq!(
+            error.message,
+            "'no_space' is not an error class that can be retried (busy, connection, stalled or other)"
+        );
+    }
+
+    #[test]
+    fn test_parse_should_reject_bandwidth_of_unknown_dataset() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"usb\"\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\n\n[jobs.nightly.bandwidth.\"tank/home\"]\nlimit = \"10M\"\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(6));
+        assert_eq!(
+            error.message,
+            "'tank/home' is not one of the datasets of this job"
+        );
+    }
+
+    #[test]
+    fn test_parse_should_reject_invalid_bandwidth_window() {
+        let contents = "[jobs.nightly]\nbackup_pool = \"usb\"\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\n\n[bandwidth]\nschedule = [{ between = \"8am-6pm\", limit = \"10M\" }]\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(7));
+        assert!(error
+            .message
+            .starts_with("'8am-6pm' is not a valid time window"));
+    }
+
+    #[test]
+    fn test_parse_should_reject_mapping_with_several_backup_pools() {
+        let contents = "[jobs.nightly]\nbackup_pools = [\"usb1\", \"usb2\"]\nlabel = \"TEST\"\ndatasets = [\"tank/os\"]\n\n[jobs.nightly.mapping]\n\"tank/os\" = \"usb1/os\"\n";
+
+        let error = Config::parse("test.toml", contents).unwrap_err();
+
+        assert_eq!(error.line, Some(7));
+        assert!(error.message.contains("single backup pool"));
+    }
+
+    #[test]
+    fn test_set_backup_pools_should_validate_the_pools() {
+        let mut job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+
+        assert!(job.set_backup_pools(Vec::new()).is_err());
+        assert!(job.set_backup_pools(vec![String::from("usb/os")]).is_err());
+        assert!(job.set_backup_pools(vec![String::from("usb")]).is_ok());
+        assert_eq!(job.backup_pools, vec!["usb"]);
+    }
+
+    #[test]
+    fn test_set_backup_pools_should_refuse_to_move_a_mapped_job() {
+        let mut job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        job.mapping
+            .insert(String::from("tank/os"), String::from("backup/os"));
+
+        let error = job.set_backup_pools(vec![String::from("usb")]).unwrap_err();
+
+        assert!(error.contains("a job with a mapping"));
+        assert_eq!(job.backup_pools, vec!["backup"]);
+        assert!(job.set_backup_pools(vec![String::from("backup")]).is_ok());
+    }
+}
@@ -0,0 +1,456 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{Local, NaiveDateTime};
+
+use crate::config::{Config, ConfigError, Job};
+use crate::interrupt;
+
+// Runs the scheduled jobs of a configuration file until the process is
+// interrupted. Each job runs on its own thread so that a slow job doesn't
+// delay the others, but a job never overlaps with a previous run of itself.
+pub struct Daemon {
+    config_path: PathBuf,
+    config: Arc<Config>,
+    next_runs: BTreeMap<String, NaiveDateTime>,
+    running: Arc<Mutex<HashSet<String>>>,
+    pools: Arc<Mutex<BTreeMap<String, PoolUsers>>>,
+}
+
+// The running jobs that use a backup pool. A job that imports its backup
+// pools may export them again when it is done, so it has them to itself.
+// Any number of other jobs can share a pool.
+#[derive(Default)]
+struct PoolUsers {
+    shared: usize,
+    exclusive: bool,
+}
+
+// Marks a job as running for as long as it is alive. Dropping it (even while
+// unwinding from a panic) allows the job to be started again.
+pub struct RunGuard {
+    name: String,
+    running: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        self.running.lock().unwrap().remove(&self.name);
+    }
+}
+
+// Claims the backup pools of a job for as long as it is alive, so that no job
+// exports a pool while another job is still using it.
+pub struct PoolGuard {
+    backup_pools: Vec<String>,
+    exclusive: bool,
+    pools: Arc<Mutex<BTreeMap<String, PoolUsers>>>,
+}
+
+impl Drop for PoolGuard {
+    fn drop(&mut self) {
+        let mut pools = self.pools.lock().unwrap();
+        for backup_pool in &self.backup_pools {
+            if let Some(users) = pools.get_mut(backup_pool) {
+                if self.exclusive {
+                    users.exclusive = false;
+                } else {
+                    users.shared -= 1;
+                }
+                if !users.exclusive && users.shared == 0 {
+                    pools.remove(backup_pool);
+                }
+            }
+        }
+    }
+}
+
+// Claims every backup pool of the job at once. Returns None if one of them is
+// in use in a way that conflicts with the job.
+fn try_claim_pools(
+    pools: &Arc<Mutex<BTreeMap<String, PoolUsers>>>,
+    job: &Job,
+) -> Option<PoolGuard> {
+    let exclusive = job.import.is_some();
+    let mut users = pools.lock().unwrap();
+    let busy = job.backup_pools.iter().any(|backup_pool| {
+        users
+            .get(backup_pool)
+            .is_some_and(|users| users.exclusive || (exclusive && users.shared > 0))
+    });
+    if busy {
+        return None;
+    }
+    for backup_pool in &job.backup_pools {
+        let users = users.entry(backup_pool.clone()).or_default();
+        if exclusive {
+            users.exclusive = true;
+        } else {
+            users.shared += 1;
+        }
+    }
+    Some(PoolGuard {
+        backup_pools: job.backup_pools.clone(),
+        exclusive,
+        pools: Arc::clone(pools),
+    })
+}
+
+// Waits until the backup pools of the job are free. Returns None if the
+// daemon is stopped in the meantime.
+fn wait_for_pools(
+    pools: &Arc<Mutex<BTreeMap<String, PoolUsers>>>,
+    name: &str,
+    job: &Job,
+) -> Option<PoolGuard> {
+    let mut logged = false;
+    loop {
+        if let Some(guard) = try_claim_pools(pools, job) {
+            return Some(guard);
+        }
+        if interrupt::is_interrupted() {
+            return None;
+        }
+        if !logged {
+            log(&format!(
+                "Job '{}' is waiting for other jobs to finish with {}.",
+                name,
+                job.backup_pools.join(", ")
+            ));
+            logged = true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+// Daemon messages go to stderr so that stdout only contains the output of
+// the jobs themselves (which may be machine readable).
+pub fn log(message: &str) {
+    eprintln!("[{}] {}", Local::now().format("%Y-%m-%d %H:%M:%S"), message);
+}
+
+impl Daemon {
+    pub fn new(config_path: &Path) -> Result<Self, ConfigError> {
+        let config = Config::load(config_path)?;
+        Ok(Self::new_with_config(
+            config_path,
+            config,
+            Local::now().naive_local(),
+        ))
+    }
+
+    pub fn new_with_config(config_path: &Path, config: Config, now: NaiveDateTime) -> Self {
+        let mut daemon = Self {
+            config_path: config_path.to_path_buf(),
+            config: Arc::new(config),
+            next_runs: BTreeMap::new(),
+            running: Arc::new(Mutex::new(HashSet::new())),
+            pools: Arc::new(Mutex::new(BTreeMap::new())),
+        };
+        daemon.schedule_all(now);
+        daemon
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn get_next_runs(&self) -> &BTreeMap<String, NaiveDateTime> {
+        &self.next_runs
+    }
+
+    // Re-reads the configuration file. If the new file is invalid, the
+    // current configuration is kept.
+    pub fn reload(&mut self, now: NaiveDateTime) -> Result<(), ConfigError> {
+        self.config = Arc::new(Config::load(&self.config_path)?);
+        self.schedule_all(now);
+        Ok(())
+    }
+
+    // Gets the jobs that are due at the given time and schedules their
+    // next run.
+    pub fn get_due_jobs(&mut self, now: NaiveDateTime) -> Vec<(String, Job)> {
+        let mut due = Vec::new();
+        for (name, next_run) in self.next_runs.iter_mut() {
+            if *next_run > now {
+                continue;
+            }
+            let job = &self.config.jobs[name];
+            due.push((name.clone(), job.clone()));
+
+            match job.schedule.as_ref().and_then(|s| s.next_after(now)) {
+                Some(next) => *next_run = next,
+                None => *next_run = NaiveDateTime::MAX,
+            }
+        }
+        due
+    }
+
+    // Marks the job as running. Returns None if it is already running.
+    pub fn try_start(&self, name: &str) -> Option<RunGuard> {
+        if !self.running.lock().unwrap().insert(String::from(name)) {
+            return None;
+        }
+        Some(RunGuard {
+            name: String::from(name),
+            running: Arc::clone(&self.running),
+        })
+    }
+
+    // Claims the backup pools of the job. Returns None if another running
+    // job uses them in a way that conflicts with it.
+    pub fn try_claim_pools(&self, job: &Job) -> Option<PoolGuard> {
+        try_claim_pools(&self.pools, job)
+    }
+
+    pub fn run<F>(mut self, runner: F) -> Result<(), String>
+    where
+        F: Fn(&str, &Job, &Config) -> bool + Send + Sync + 'static,
+    {
+        if self.next_runs.is_empty() {
+            return Err(format!(
+                "No jobs in {} have a schedule.",
+                self.config_path.display()
+            ));
+        }
+
+        let reload = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reload))
+            .map_err(|e| format!("Failed to register the SIGHUP handler: {}", e))?;
+
+        let runner = Arc::new(runner);
+        self.log_schedule();
+
+        loop {
+            if interrupt::is_interrupted() {
+                self.stop();
+                return Ok(());
+            }
+            let now = Local::now().naive_local();
+
+            if reload.swap(false, Ordering::SeqCst) {
+                match self.reload(now) {
+                    Ok(()) => {
+                        log(&format!("Reloaded {}.", self.config_path.display()));
+                        self.log_schedule();
+                    }
+                    Err(error) => log(&format!(
+                        "Failed to reload the configuration. Keeping the current one. {}",
+                        error
+                    )),
+                }
+            }
+
+            for (name, job) in self.get_due_jobs(now) {
+                let guard = match self.try_start(&name) {
+                    Some(guard) => guard,
+                    None => {
+                        log(&format!(
+                            "Job '{}' is still running. Skipping this run.",
+                            name
+                        ));
+                        continue;
+                    }
+                };
+
+                let runner = Arc::clone(&runner);
+                // Jobs keep the configuration they were started with, even if
+                // it is reloaded while they run.
+                let config = Arc::clone(&self.config);
+                let pools = Arc::clone(&self.pools);
+                thread::spawn(move || {
+                    let _guard = guard;
+                    let _pools = match wait_for_pools(&pools, &name, &job) {
+                        Some(pools) => pools,
+                        None => {
+                            log(&format!("Job '{}' was stopped before it started.", name));
+                            return;
+                        }
+                    };
+                    let started = Instant::now();
+                    log(&format!("Starting job '{}'.", name));
+
+                    if runner(&name, &job, &config) {
+                        log(&format!(
+                            "Job '{}' finished successfully in {}s.",
+                            name,
+                            started.elapsed().as_secs()
+                        ));
+                    } else {
+                        log(&format!(
+                            "Job '{}' failed after {}s.",
+                            name,
+                            started.elapsed().as_secs()
+                        ));
+                    }
+                });
+            }
+
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    // Waits for the running jobs, which stop after the dataset they are on
+    // once interrupted.
+    fn stop(&self) {
+        let running = self.running.lock().unwrap().len();
+        if running > 0 {
+            log(&format!(
+                "Stopping. Waiting for {} running job(s) to finish.",
+                running
+            ));
+        }
+        while !self.running.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        log("Stopped.");
+    }
+
+    fn schedule_all(&mut self, now: NaiveDateTime) {
+        self.next_runs.clear();
+        for (name, job) in &self.config.jobs {
+            if let Some(next) = job.schedule.as_ref().and_then(|s| s.next_after(now)) {
+                self.next_runs.insert(name.clone(), next);
+            }
+        }
+    }
+
+    fn log_schedule(&self) {
+        for name in self.config.jobs.keys() {
+            match self.next_runs.get(name) {
+                Some(next) => log(&format!(
+                    "Job '{}' will next run at {}.",
+                    name,
+                    next.format("%Y-%m-%d %H:%M:%S")
+                )),
+                None => log(&format!("Job '{}' has no schedule. Ignoring.", name)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pools::ImportOptions;
+
+    fn at(value: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn get_example_daemon() -> Daemon {
+        let contents = r#"
+[jobs.nightly]
+backup_pool = "backup"
+label = "CHECKPOINT"
+datasets = ["tank/os"]
+schedule = "0 3 * * *"
+
+[jobs.often]
+backup_pool = "backup"
+label = "FREQUENT"
+datasets = ["tank/var/log"]
+schedule = "every 15m"
+
+[jobs.manual]
+backup_pool = "usb"
+label = "CHECKPOINT"
+datasets = ["tank/home"]
+"#;
+        let config = Config::parse("test.toml", contents).unwrap();
+        Daemon::new_with_config(Path::new("test.toml"), config, at("2022-09-01 00:00:00"))
+    }
+
+    #[test]
+    fn test_new_should_only_schedule_jobs_with_a_schedule() {
+        let daemon = get_example_daemon();
+
+        let next_runs = daemon.get_next_runs();
+
+        assert_eq!(next_runs.len(), 2);
+        assert_eq!(next_runs["nightly"], at("2022-09-01 03:00:00"));
+        assert_eq!(next_runs["often"], at("2022-09-01 00:15:00"));
+    }
+
+    #[test]
+    fn test_get_due_jobs_should_return_due_jobs_and_reschedule() {
+        let mut daemon = get_example_daemon();
+
+        let due = daemon.get_due_jobs(at("2022-09-01 00:15:00"));
+
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].0, "often");
+        assert_eq!(daemon.get_next_runs()["often"], at("2022-09-01 00:30:00"));
+        assert!(daemon.get_due_jobs(at("2022-09-01 00:15:01")).is_empty());
+    }
+
+    #[test]
+    fn test_try_start_should_prevent_overlapping_runs() {
+        let daemon = get_example_daemon();
+
+        let guard = daemon.try_start("often");
+
+        assert!(guard.is_some());
+        assert!(daemon.try_start("often").is_none());
+        assert!(daemon.try_start("nightly").is_some());
+
+        drop(guard);
+
+        assert!(daemon.try_start("often").is_some());
+    }
+
+    #[test]
+    fn test_try_claim_pools_should_give_importing_jobs_their_pools_to_themselves() {
+        let daemon = get_example_daemon();
+        let mut offsite = daemon.get_config().jobs["nightly"].clone();
+        offsite.import = Some(ImportOptions::default());
+
+        let nightly = daemon.try_claim_pools(&daemon.get_config().jobs["nightly"]);
+        let often = daemon.try_claim_pools(&daemon.get_config().jobs["often"]);
+
+        assert!(nightly.is_some());
+        assert!(often.is_some());
+        assert!(daemon.try_claim_pools(&offsite).is_none());
+
+        drop(nightly);
+        drop(often);
+        let claimed = daemon.try_claim_pools(&offsite);
+
+        assert!(claimed.is_some());
+        assert!(daemon
+            .try_claim_pools(&daemon.get_config().jobs["often"])
+            .is_none());
+        assert!(daemon
+            .try_claim_pools(&daemon.get_config().jobs["manual"])
+            .is_some());
+    }
+}
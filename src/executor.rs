@@ -0,0 +1,1414 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::bandwidth::{Bandwidth, Throttle};
+use crate::helpers;
+use crate::hooks::{HookPoint, HookRunner};
+use crate::plan::{Action, DatasetPlan, Plan, Send, TargetPlan};
+use crate::report::{ActionKind, ActionReport, AttemptReport, DatasetReport, Outcome, Reporter};
+use crate::retry::{self, RetryPolicies};
+use crate::traits::{SendOptions, SystemProvider};
+
+// How long the sends of a dataset may take before they are stopped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    // For all of the sends of the dataset together.
+    pub dataset: Option<Duration>,
+    // How long a send may go without moving any data.
+    pub stall: Option<Duration>,
+}
+
+// How many datasets are replicated at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Parallelism {
+    pub datasets: usize,
+    // How many of them may be sent from the same source pool.
+    pub per_pool: usize,
+}
+
+impl Default for Parallelism {
+    fn default() -> Self {
+        Self {
+            datasets: 1,
+            per_pool: 1,
+        }
+    }
+}
+
+// A backup dataset of the plan while it's being replicated into.
+struct Target<'a> {
+    plan: &'a TargetPlan,
+    dataset: DatasetReport,
+    started: Instant,
+}
+
+impl<'a> Target<'a> {
+    fn new(plan: &'a TargetPlan) -> Self {
+        let mut dataset = DatasetReport::new(&plan.dataset, &plan.backup_dataset);
+        dataset.source_snapshots = plan.source_snapshots;
+        dataset.backup_snapshots = plan.backup_snapshots;
+        dataset.latest_snapshot = plan.latest_snapshot.clone();
+        dataset.common_snapshot = plan.common_snapshot.clone();
+        dataset.replication_lag_seconds = plan.replication_lag_seconds;
+        Self {
+            plan,
+            dataset,
+            started: Instant::now(),
+        }
+    }
+}
+
+// How the plan is carried out.
+pub struct ExecuteOptions<'a> {
+    pub send_flags: &'a [String],
+    pub dry_run: bool,
+    pub parallelism: Parallelism,
+    pub bandwidth: &'a Bandwidth,
+    pub timeouts: Timeouts,
+    // Set when the run is interrupted. No more datasets are started once it
+    // is, and the sends that are running are stopped.
+    pub interrupted: Arc<AtomicBool>,
+    pub retry: &'a RetryPolicies,
+}
+
+// Applies the plan, running the before_send and after_send hooks around
+// each send and recording what happened to every backup dataset.
+pub fn execute(
+    system: &impl SystemProvider,
+    reporter: &mut Reporter,
+    hooks: &HookRunner,
+    plan: &Plan,
+    options: &ExecuteOptions,
+) {
+    let global = options
+        .bandwidth
+        .global
+        .clone()
+        .map(|limit| Arc::new(Throttle::new(limit)));
+    if options.parallelism.datasets > 1 && plan.datasets.len() > 1 {
+        execute_parallel(system, reporter, hooks, plan, options, global);
+    } else {
+        for (i, dataset) in plan.datasets.iter().enumerate() {
+            if options.interrupted.load(Ordering::SeqCst) {
+                for dataset in &plan.datasets[i..] {
+                    skip_dataset(reporter, dataset);
+                }
+                break;
+            }
+            let send_options = get_send_options(options, &global, dataset);
+            execute_dataset(
+                system,
+                reporter,
+                hooks,
+                &send_options,
+                options.dry_run,
+                plan,
+                dataset,
+            );
+        }
+    }
+    if options.interrupted.load(Ordering::SeqCst) {
+        describe_interruption(reporter);
+    }
+}
+
+// Replicates several datasets at once, each on its own thread with its own
+// prefixed output. A dataset only starts once its ancestors in the plan are
+// done, and when its source pool isn't already busy with as many datasets as
+// allowed. The reports are recorded in the order of the plan.
+fn execute_parallel(
+    system: &impl SystemProvider,
+    reporter: &mut Reporter,
+    hooks: &HookRunner,
+    plan: &Plan,
+    options: &ExecuteOptions,
+    global: Option<Arc<Throttle>>,
+) {
+    let parallelism = options.parallelism;
+    reporter.text(&format!(
+        "Replicating up to {} datasets at once ({} per source pool).",
+        parallelism.datasets, parallelism.per_pool
+    ));
+
+    let datasets = &plan.datasets;
+    let mut pending: Vec<usize> = (0..datasets.len()).collect();
+    let mut running: Vec<usize> = Vec::new();
+    let mut finished: Vec<Option<Reporter>> = datasets.iter().map(|_| None).collect();
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| loop {
+        while running.len() < parallelism.datasets && !options.interrupted.load(Ordering::SeqCst) {
+            let Some(position) = pending
+                .iter()
+                .position(|&i| is_ready(datasets, &pending, &running, i, parallelism.per_pool))
+            else {
+                break;
+            };
+            let i = pending.remove(position);
+            running.push(i);
+
+            let dataset = &datasets[i];
+            let mut fork = reporter.fork(&format!("[{}] ", dataset.dataset));
+            let send_options = get_send_options(options, &global, dataset);
+            let dry_run = options.dry_run;
+            let sender = sender.clone();
+            scope.spawn(move || {
+                execute_dataset(
+                    system,
+                    &mut fork,
+                    hooks,
+                    &send_options,
+                    dry_run,
+                    plan,
+                    dataset,
+                );
+                let _ = sender.send((i, fork));
+            });
+        }
+
+        if running.is_empty() {
+            break;
+        }
+        let (i, fork) = receiver.recv().expect("a dataset thread is still running");
+        running.retain(|&j| j != i);
+        finished[i] = Some(fork);
+    });
+
+    // Whatever didn't start was interrupted.
+    for i in pending {
+        let mut fork = reporter.fork(&format!("[{}] ", datasets[i].dataset));
+        skip_dataset(&mut fork, &datasets[i]);
+        finished[i] = Some(fork);
+    }
+
+    for fork in finished.into_iter().flatten() {
+        reporter.merge(fork);
+    }
+}
+
+// Records the targets of a dataset that wasn't started because the run was
+// interrupted.
+fn skip_dataset(reporter: &mut Reporter, dataset: &DatasetPlan) {
+    for target_plan in &dataset.targets {
+        skip_target(reporter, target_plan);
+    }
+}
+
+fn skip_target(reporter: &mut Reporter, target_plan: &TargetPlan) {
+    let mut target = Target::new(target_plan);
+    target.dataset.finish(
+        Outcome::Skipped,
+        Some("The run was interrupted."),
+        Duration::ZERO,
+    );
+    reporter.dataset_finished(target.dataset);
+}
+
+// Says which datasets were replicated before the run was interrupted, and
+// fails the run.
+fn describe_interruption(reporter: &mut Reporter) {
+    let mut completed = Vec::new();
+    let mut not_completed = Vec::new();
+    for dataset in &reporter.report().datasets {
+        let title = format!("{} -> {}", dataset.dataset, dataset.backup_dataset);
+        match dataset.outcome {
+            Outcome::Success | Outcome::UpToDate | Outcome::Planned
+                if dataset.errors.is_empty() =>
+            {
+                completed.push(title)
+            }
+            _ => not_completed.push(title),
+        }
+    }
+
+    reporter.text("");
+    reporter.text("The run was interrupted.");
+    for (heading, titles) in [("Completed", completed), ("Not completed", not_completed)] {
+        if !titles.is_empty() {
+            reporter.text(&format!("{}:", heading));
+            for title in titles {
+                reporter.text(&format!("  {}", title));
+            }
+        }
+    }
+    reporter.error("Interrupted before every dataset was replicated.");
+}
+
+// Gets the options of the sends of the dataset, which is about to start: the
+// send flags, the throttles of the global limit and of the dataset's own
+// limit, and the timeouts.
+fn get_send_options(
+    options: &ExecuteOptions,
+    global: &Option<Arc<Throttle>>,
+    dataset: &DatasetPlan,
+) -> SendOptions {
+    let own = options
+        .bandwidth
+        .datasets
+        .get(&dataset.dataset)
+        .map(|limit| Arc::new(Throttle::new(limit.clone())));
+    SendOptions {
+        send_flags: options.send_flags.to_vec(),
+        throttles: global.iter().cloned().chain(own).collect(),
+        deadline: options
+            .timeouts
+            .dataset
+            .map(|timeout| Instant::now() + timeout),
+        stall_timeout: options.timeouts.stall,
+        interrupted: Some(Arc::clone(&options.interrupted)),
+        retry: options.retry.clone(),
+    }
+}
+
+// Whether the dataset can start: none of its ancestors are still to be
+// replicated and its source pool has room for another send.
+fn is_ready(
+    datasets: &[DatasetPlan],
+    pending: &[usize],
+    running: &[usize],
+    i: usize,
+    per_pool: usize,
+) -> bool {
+    let dataset = datasets[i].dataset.as_str();
+    let has_ancestor = pending.iter().chain(running).any(|&j| {
+        dataset
+            .strip_prefix(datasets[j].dataset.as_str())
+            .is_some_and(|rest| rest.starts_with('/'))
+    });
+    let pool = helpers::get_source_pool_name(dataset);
+    let busy = running
+        .iter()
+        .filter(|&&j| helpers::get_source_pool_name(&datasets[j].dataset) == pool)
+        .count();
+    !has_ancestor && busy < per_pool
+}
+
+// Replicates a source dataset into each of the backup pools.
+fn execute_dataset(
+    system: &impl SystemProvider,
+    reporter: &mut Reporter,
+    hooks: &HookRunner,
+    options: &SendOptions,
+    dry_run: bool,
+    plan: &Plan,
+    dataset: &DatasetPlan,
+) {
+    if plan.tee {
+        let mut targets: Vec<Target> = dataset.targets.iter().map(Target::new).collect();
+        let results = execute_teed(system, reporter, hooks, options, dry_run, &mut targets);
+        for (target, result) in targets.into_iter().zip(results) {
+            finish_target(system, reporter, dry_run, target, result);
+        }
+        return;
+    }
+    for target_plan in &dataset.targets {
+        if is_interrupted(options) {
+            skip_target(reporter, target_plan);
+            continue;
+        }
+        let mut target = Target::new(target_plan);
+        print_heading(reporter, &get_target_title(plan, &target));
+        let result = match describe_target(reporter, &mut target) {
+            Ok(send) => send_to_targets(
+                system,
+                reporter,
+                hooks,
+                options,
+                dry_run,
+                &send,
+                vec![&mut target],
+            )
+            .remove(0),
+            Err(result) => result,
+        };
+        finish_target(system, reporter, dry_run, target, result);
+    }
+}
+
+// Describes every target first, then runs each distinct send once, teeing
+// the stream into all of the targets that planned the same send.
+fn execute_teed(
+    system: &impl SystemProvider,
+    reporter: &Reporter,
+    hooks: &HookRunner,
+    options: &SendOptions,
+    dry_run: bool,
+    targets: &mut [Target],
+) -> Vec<(Outcome, Option<String>)> {
+    let sends: Vec<_> = targets
+        .iter_mut()
+        .map(|target| {
+            print_heading(
+                reporter,
+                &format!(
+                    "{} -> {}",
+                    target.dataset.dataset, target.dataset.backup_dataset
+                ),
+            );
+            describe_target(reporter, target)
+        })
+        .collect();
+
+    let mut results = vec![None; targets.len()];
+    for (i, send) in sends.iter().enumerate() {
+        if results[i].is_some() {
+            continue;
+        }
+        let send = match send {
+            Ok(send) => send,
+            Err(result) => {
+                results[i] = Some(result.clone());
+                continue;
+            }
+        };
+
+        let group: Vec<usize> = (i..sends.len())
+            .filter(|&j| results[j].is_none() && sends[j].as_ref().ok() == Some(send))
+            .collect();
+        let group_targets: Vec<&mut Target> = targets
+            .iter_mut()
+            .enumerate()
+            .filter(|(j, _)| group.contains(j))
+            .map(|(_, target)| target)
+            .collect();
+        let backup_datasets: Vec<&str> = group_targets
+            .iter()
+            .map(|target| target.dataset.backup_dataset.as_str())
+            .collect();
+        print_heading(
+            reporter,
+            &format!(
+                "{} -> {}",
+                group_targets[0].dataset.dataset,
+                backup_datasets.join(", ")
+            ),
+        );
+
+        let outcomes = send_to_targets(
+            system,
+            reporter,
+            hooks,
+            options,
+            dry_run,
+            send,
+            group_targets,
+        );
+        for (j, outcome) in group.into_iter().zip(outcomes) {
+            results[j] = Some(outcome);
+        }
+    }
+    results.into_iter().flatten().collect()
+}
+
+fn print_heading(reporter: &Reporter, title: &str) {
+    // Prefixed lines are interleaved with those of other datasets, so keep
+    // the heading to a single line.
+    if reporter.is_forked() {
+        reporter.text(&format!("--- {} ---", title));
+        return;
+    }
+    reporter.text("\n---------------");
+    reporter.text(title);
+    reporter.text("---------------\n");
+}
+
+// The source dataset on its own when there is a single backup pool, or the
+// source and backup dataset when there are several.
+fn get_target_title(plan: &Plan, target: &Target) -> String {
+    if plan.backup_pools.len() == 1 {
+        target.dataset.dataset.clone()
+    } else {
+        format!(
+            "{} -> {}",
+            target.dataset.dataset, target.dataset.backup_dataset
+        )
+    }
+}
+
+// Prints what was found while planning the target. Returns the send to run,
+// or the outcome and, for skipped datasets, the reason when nothing can or
+// needs to be sent.
+fn describe_target(
+    reporter: &Reporter,
+    target: &mut Target,
+) -> Result<Send, (Outcome, Option<String>)> {
+    let plan = target.plan;
+    reporter.text(&format!(
+        "Source Snapshots Count: {}",
+        plan.source_snapshots
+    ));
+    reporter.text(&format!(
+        "Backup Snapshots Count: {}",
+        plan.backup_snapshots
+    ));
+
+    if let Some(latest_snapshot) = &plan.latest_snapshot {
+        reporter.text(&format!("Latest Snapshot: {}", latest_snapshot));
+        match &plan.common_snapshot {
+            Some(common_snapshot) if !plan.adopted => {
+                reporter.text(&format!("Common Snapshot: {}", common_snapshot));
+            }
+            _ => reporter.text("No common snapshot found."),
+        }
+    }
+
+    for action in &plan.actions {
+        match action {
+            Action::UpToDate => {
+                reporter.text("You are already up to date!");
+                return Err((Outcome::UpToDate, None));
+            }
+            Action::Skip { reason } => {
+                reporter.text(reason);
+                return Err((Outcome::Skipped, Some(reason.clone())));
+            }
+            Action::Fail { reason, error } => {
+                reporter.text(reason);
+                target.dataset.errors.push(error.clone());
+                return Err((Outcome::Failed, Some(reason.clone())));
+            }
+            _ => {}
+        }
+    }
+
+    if let (true, Some(common_snapshot)) = (plan.adopted, &plan.common_snapshot) {
+        reporter.text(&format!(
+            "Adopting the existing backup from {} ...",
+            common_snapshot
+        ));
+    }
+    plan.get_send().ok_or_else(|| {
+        (
+            Outcome::Skipped,
+            Some(String::from("Nothing to send. Skipping.")),
+        )
+    })
+}
+
+// Prunes the backup dataset of the target (if the plan says so) and records
+// its report.
+fn finish_target(
+    system: &impl SystemProvider,
+    reporter: &mut Reporter,
+    dry_run: bool,
+    mut target: Target,
+    (mut outcome, reason): (Outcome, Option<String>),
+) {
+    if matches!(
+        outcome,
+        Outcome::UpToDate | Outcome::Success | Outcome::Planned
+    ) && !prune(system, reporter, dry_run, &mut target)
+    {
+        outcome = Outcome::Failed;
+    }
+
+    target
+        .dataset
+        .finish(outcome, reason.as_deref(), target.started.elapsed());
+    reporter.dataset_finished(target.dataset);
+}
+
+// Runs the send into the backup dataset of every given target. With more
+// than one target the stream is only produced once and fed into all of
+// them. Returns the outcome of each target, in the same order.
+fn send_to_targets(
+    system: &impl SystemProvider,
+    reporter: &Reporter,
+    hooks: &HookRunner,
+    options: &SendOptions,
+    dry_run: bool,
+    send: &Send,
+    mut targets: Vec<&mut Target>,
+) -> Vec<(Outcome, Option<String>)> {
+    let mut results = vec![None; targets.len()];
+    for (target, result) in targets.iter_mut().zip(results.iter_mut()) {
+        let creates_tree = target
+            .plan
+            .actions
+            .iter()
+            .any(|action| matches!(action, Action::CreateDatasetTree { .. }));
+        if let Err(reason) = run_before_send_hooks(reporter, hooks, &mut target.dataset) {
+            *result = Some((Outcome::Skipped, Some(reason)));
+        } else if creates_tree && !create_dataset_tree(system, reporter, dry_run, target) {
+            run_after_send_hooks(reporter, hooks, &mut target.dataset, Outcome::Failed);
+            *result = Some((Outcome::Failed, None));
+        }
+    }
+
+    // Receives that an earlier run left partially done have to be finished
+    // first, so those targets don't share the stream.
+    let started = Instant::now();
+    let mut resumed = vec![None; targets.len()];
+    for ((target, result), resumed) in targets.iter().zip(&results).zip(resumed.iter_mut()) {
+        if result.is_some() || dry_run {
+            continue;
+        }
+        let backup_dataset = &target.dataset.backup_dataset;
+        if let Some(token) = system.get_resume_token(backup_dataset) {
+            let sent =
+                resume_earlier_receive(system, reporter, options, send, backup_dataset, &token);
+            *resumed = Some((sent, started.elapsed()));
+        }
+    }
+
+    let backup_datasets: Vec<String> = targets
+        .iter()
+        .zip(results.iter().zip(&resumed))
+        .filter(|(_, (result, resumed))| result.is_none() && resumed.is_none())
+        .map(|(target, _)| target.dataset.backup_dataset.clone())
+        .collect();
+    if backup_datasets.is_empty() && resumed.iter().all(Option::is_none) {
+        return results.into_iter().flatten().collect();
+    }
+
+    let kind = if send.from.is_some() {
+        "Incremental"
+    } else {
+        "Full"
+    };
+    match (&send.from, backup_datasets.is_empty()) {
+        (_, true) => {}
+        (Some(from), false) => reporter.text(&format!(
+            "Sending incremental backup for {} -> {} ...",
+            from, send.to
+        )),
+        (None, false) => reporter.text(&format!("Sending full backup for {} ...", send.to)),
+    }
+    if backup_datasets.len() > 1 {
+        reporter.text(&format!(
+            "Receiving into {} at once.",
+            backup_datasets.join(", ")
+        ));
+    }
+
+    let started = Instant::now();
+    let sent = if dry_run || backup_datasets.is_empty() {
+        vec![Ok(0); backup_datasets.len()]
+    } else if let [backup_dataset] = backup_datasets.as_slice() {
+        vec![match &send.from {
+            Some(from) => system.send_incremental_backup(from, &send.to, backup_dataset, options),
+            None => system.send_full_backup(&send.to, backup_dataset, options),
+        }]
+    } else {
+        system.send_backup_to_many(send.from.as_deref(), &send.to, &backup_datasets, options)
+    };
+    let duration = started.elapsed();
+    let teed = backup_datasets.len() > 1;
+
+    let mut sent = sent.into_iter();
+    for ((target, result), resumed) in targets.iter_mut().zip(results.iter_mut()).zip(resumed) {
+        if result.is_some() {
+            continue;
+        }
+        let backup_dataset = target.dataset.backup_dataset.clone();
+        let (sent, duration) = resumed.unwrap_or_else(|| {
+            let sent = sent
+                .next()
+                .unwrap_or_else(|| Err(String::from("no result for this backup dataset")));
+            (sent, duration)
+        });
+        let (sent, attempts, duration) = match sent {
+            Err(error) if !dry_run => retry_send(
+                system,
+                reporter,
+                options,
+                send,
+                &backup_dataset,
+                error,
+                duration,
+            ),
+            sent => (sent, Vec::new(), duration),
+        };
+        let action_kind = match &send.from {
+            Some(from) => ActionKind::SendIncremental {
+                from: from.clone(),
+                to: send.to.clone(),
+                backup_dataset,
+            },
+            None => ActionKind::SendFull {
+                snapshot: send.to.clone(),
+                backup_dataset,
+            },
+        };
+        let mut action = get_action_report(action_kind, dry_run, sent, duration);
+        action.attempts = attempts;
+        let outcome = finish_send(reporter, &mut target.dataset, action, kind, teed);
+        if outcome.0 == Outcome::Failed {
+            describe_partial_receive(system, reporter, &target.dataset.backup_dataset);
+        }
+        run_after_send_hooks(reporter, hooks, &mut target.dataset, outcome.0);
+        *result = Some(outcome);
+    }
+    results.into_iter().flatten().collect()
+}
+
+// Finishes the receive that an earlier run left partially done in the
+// backup dataset, and then sends whatever is still missing up to the
+// snapshot of the send. Returns the bytes sent for both.
+fn resume_earlier_receive(
+    system: &impl SystemProvider,
+    reporter: &Reporter,
+    options: &SendOptions,
+    send: &Send,
+    backup_dataset: &str,
+    token: &str,
+) -> Result<u64, String> {
+    reporter.text(&format!(
+        "Resuming the stream that an earlier run partially received into {} ...",
+        backup_dataset
+    ));
+    let resumed = system.resume_backup(token, backup_dataset, options)?;
+
+    // The resumed stream may have been for an older snapshot than the one
+    // to send now.
+    let received = system
+        .get_snapshot_guids(backup_dataset)?
+        .pop()
+        .and_then(|(snapshot, _)| snapshot.split_once('@').map(|(_, name)| name.to_owned()))
+        .ok_or_else(|| format!("{} has no snapshots after resuming", backup_dataset))?;
+    let (dataset, name) = send.to.split_once('@').unwrap_or((&send.to, ""));
+    if received == name {
+        return Ok(resumed);
+    }
+    let from = format!("{}@{}", dataset, received);
+    reporter.text(&format!(
+        "Sending incremental backup for {} -> {} ...",
+        from, send.to
+    ));
+    system
+        .send_incremental_backup(&from, &send.to, backup_dataset, options)
+        .map(|sent| resumed + sent)
+}
+
+// Tries a failed send into the backup dataset again for as long as the
+// policy for the class of its error allows, resuming from what the previous
+// attempt managed to receive when possible. Returns the result of the last
+// attempt, every attempt if there were several, and how long they all took
+// (waiting included).
+fn retry_send(
+    system: &impl SystemProvider,
+    reporter: &Reporter,
+    options: &SendOptions,
+    send: &Send,
+    backup_dataset: &str,
+    error: String,
+    duration: Duration,
+) -> (Result<u64, String>, Vec<AttemptReport>, Duration) {
+    let mut result = Err(error);
+    let mut attempts: Vec<AttemptReport> = Vec::new();
+    let mut resumed = false;
+    let mut duration = duration;
+    let mut total = duration;
+    loop {
+        let error = result.as_ref().err().cloned();
+        let class = error.as_deref().map(retry::classify);
+        let mut attempt = AttemptReport {
+            resumed,
+            success: result.is_ok(),
+            duration_ms: duration.as_millis(),
+            error,
+            error_class: class,
+            retry_delay_ms: None,
+        };
+
+        // Don't retry past the deadline of the dataset.
+        let retry = attempts.len() as u32 + 1;
+        let delay = class
+            .and_then(|class| options.retry.get(&class))
+            .filter(|policy| retry <= policy.retries)
+            .map(|policy| policy.get_delay(retry, retry::get_jitter()))
+            .filter(|&delay| {
+                options
+                    .deadline
+                    .is_none_or(|deadline| Instant::now() + delay < deadline)
+            });
+        let (Some(class), Some(delay)) = (class, delay) else {
+            if !attempts.is_empty() {
+                attempts.push(attempt);
+            }
+            return (result, attempts, total);
+        };
+
+        reporter.text(&format!(
+            "Attempt {} failed ({}): {}",
+            retry,
+            class.as_str(),
+            attempt.error.as_deref().unwrap_or_default()
+        ));
+        reporter.text(&format!(
+            "Retrying in {} ...",
+            helpers::format_duration(delay.as_secs())
+        ));
+        if !wait(delay, options) {
+            attempts.push(attempt);
+            return (result, attempts, total);
+        }
+        attempt.retry_delay_ms = Some(delay.as_millis());
+        attempts.push(attempt);
+        total += delay;
+
+        let started = Instant::now();
+        let token = system.get_resume_token(backup_dataset);
+        resumed = token.is_some();
+        result = match (&token, &send.from) {
+            (Some(token), _) => {
+                reporter.text("Resuming from the partially received stream ...");
+                system.resume_backup(token, backup_dataset, options)
+            }
+            (None, Some(from)) => {
+                system.send_incremental_backup(from, &send.to, backup_dataset, options)
+            }
+            (None, None) => system.send_full_backup(&send.to, backup_dataset, options),
+        };
+        duration = started.elapsed();
+        total += duration;
+    }
+}
+
+// Waits for the delay, unless the run is interrupted first. Returns whether
+// the whole delay passed.
+fn wait(delay: Duration, options: &SendOptions) -> bool {
+    let until = Instant::now() + delay;
+    loop {
+        if is_interrupted(options) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= until {
+            return true;
+        }
+        thread::sleep((until - now).min(Duration::from_millis(100)));
+    }
+}
+
+fn is_interrupted(options: &SendOptions) -> bool {
+    options
+        .interrupted
+        .as_ref()
+        .is_some_and(|interrupted| interrupted.load(Ordering::SeqCst))
+}
+
+// Creates the dataset hierarchy if needed. The target backup dataset needs
+// to exist before we attempt to send into it.
+fn create_dataset_tree(
+    system: &impl SystemProvider,
+    reporter: &Reporter,
+    dry_run: bool,
+    target: &mut Target,
+) -> bool {
+    let dataset = &mut target.dataset;
+    let backup_dataset = dataset.backup_dataset.clone();
+    reporter.text(&format!(
+        "Creating backup dataset hierarchy for {} (if needed) ...",
+        backup_dataset
+    ));
+
+    let action = run_action(
+        ActionKind::CreateDatasetTree {
+            backup_dataset: backup_dataset.clone(),
+        },
+        dry_run,
+        || {
+            if system.create_dataset_tree_if_needed(&backup_dataset) {
+                Ok(0)
+            } else {
+                Err(String::from("Failed to create backup dataset hierarchy. Perhaps your user doesn't have enough permissions for the 'zfs' command?"))
+            }
+        },
+    );
+    reporter.action(&dataset.dataset, &action);
+    let created = action.success;
+    if let Some(error) = &action.error {
+        reporter.text(error);
+    }
+    dataset.record(action);
+    created
+}
+
+fn get_dataset_environment(dataset: &DatasetReport) -> Vec<(&'static str, String)> {
+    vec![
+        ("DATASET", dataset.dataset.clone()),
+        ("BACKUP_DATASET", dataset.backup_dataset.clone()),
+        (
+            "COMMON_SNAPSHOT",
+            dataset.common_snapshot.clone().unwrap_or_default(),
+        ),
+        (
+            "LATEST_SNAPSHOT",
+            dataset.latest_snapshot.clone().unwrap_or_default(),
+        ),
+    ]
+}
+
+// Runs the before_send hooks. If one of them fails, the dataset is skipped
+// and the reason is returned.
+fn run_before_send_hooks(
+    reporter: &Reporter,
+    hooks: &HookRunner,
+    dataset: &mut DatasetReport,
+) -> Result<(), String> {
+    let environment = get_dataset_environment(dataset);
+    hooks
+        .run(HookPoint::BeforeSend, &environment, &|message| {
+            reporter.text(message)
+        })
+        .map_err(|error| {
+            dataset.errors.push(error);
+            let reason = "A before_send hook failed. Skipping.";
+            reporter.text(reason);
+            String::from(reason)
+        })
+}
+
+fn run_after_send_hooks(
+    reporter: &Reporter,
+    hooks: &HookRunner,
+    dataset: &mut DatasetReport,
+    outcome: Outcome,
+) {
+    let mut environment = get_dataset_environment(dataset);
+    environment.push(("OUTCOME", String::from(outcome.as_str())));
+    environment.push(("BYTES_SENT", dataset.bytes_sent.to_string()));
+
+    if let Err(error) = hooks.run(HookPoint::AfterSend, &environment, &|message| {
+        reporter.text(message)
+    }) {
+        dataset.errors.push(error);
+    }
+}
+
+// Destroys the labeled backup snapshots that the plan prunes. Returns false
+// if any of them couldn't be destroyed.
+fn prune(
+    system: &impl SystemProvider,
+    reporter: &Reporter,
+    dry_run: bool,
+    target: &mut Target,
+) -> bool {
+    for snapshot in target.plan.get_holds() {
+        reporter.text(&format!("Keeping {}, since it is held.", snapshot));
+        target.dataset.held_snapshots.push(String::from(snapshot));
+    }
+
+    let mut success = true;
+    for snapshot in target.plan.get_prunes() {
+        reporter.text(&format!("Pruning {} ...", snapshot));
+
+        let action = run_action(
+            ActionKind::Prune {
+                snapshot: String::from(snapshot),
+            },
+            dry_run,
+            || {
+                if system.destroy_snapshot(snapshot) {
+                    Ok(0)
+                } else {
+                    Err(format!("Failed to destroy {}.", snapshot))
+                }
+            },
+        );
+        reporter.action(&target.dataset.dataset, &action);
+        if let Some(error) = &action.error {
+            reporter.text(error);
+            success = false;
+        }
+        target.dataset.record(action);
+    }
+    success
+}
+
+// Runs the given action unless this is a dry run, timing it and capturing
+// the number of bytes sent or the error that occurred.
+fn run_action(
+    kind: ActionKind,
+    dry_run: bool,
+    action: impl FnOnce() -> Result<u64, String>,
+) -> ActionReport {
+    let started = Instant::now();
+    let result = if dry_run { Ok(0) } else { action() };
+    get_action_report(kind, dry_run, result, started.elapsed())
+}
+
+fn get_action_report(
+    kind: ActionKind,
+    dry_run: bool,
+    result: Result<u64, String>,
+    duration: Duration,
+) -> ActionReport {
+    ActionReport {
+        kind,
+        executed: !dry_run,
+        success: result.is_ok(),
+        bytes_sent: *result.as_ref().unwrap_or(&0),
+        duration_ms: duration.as_millis(),
+        error: result.err(),
+        attempts: Vec::new(),
+    }
+}
+
+fn finish_send(
+    reporter: &Reporter,
+    dataset: &mut DatasetReport,
+    action: ActionReport,
+    kind: &str,
+    teed: bool,
+) -> (Outcome, Option<String>) {
+    reporter.action(&dataset.dataset, &action);
+
+    // When the stream went into several backup datasets, say which one each
+    // result is about.
+    let target = if teed {
+        format!(" into {}", dataset.backup_dataset)
+    } else {
+        String::new()
+    };
+    let outcome = if !action.executed {
+        Outcome::Planned
+    } else if action.success {
+        reporter.text(&format!("{} backup{} finished successfully!", kind, target));
+        Outcome::Success
+    } else {
+        reporter.text(&format!(
+            "An error occurred while sending the {} backup{}.",
+            kind.to_lowercase(),
+            target
+        ));
+        if let Some(error) = &action.error {
+            reporter.text(error);
+        }
+        Outcome::Failed
+    };
+
+    dataset.record(action);
+    (outcome, None)
+}
+
+// Tells how to deal with what an interrupted receive kept, if anything.
+fn describe_partial_receive(
+    system: &impl SystemProvider,
+    reporter: &Reporter,
+    backup_dataset: &str,
+) {
+    if let Some(token) = system.get_resume_token(backup_dataset) {
+        reporter.text(&format!(
+            "The partially received stream was kept. Resume it with 'zfs send -t {} | zfs recv -s {}', or discard it with 'zfs recv -A {}'.",
+            token, backup_dataset, backup_dataset
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    use crate::config::{Job, Retention};
+    use crate::plan;
+    use crate::report::{OutputFormat, RunConfig};
+    use crate::snapshot::Snapshot;
+    use crate::testing::FakeSystem;
+
+    fn get_reporter() -> Reporter {
+        Reporter::new(
+            OutputFormat::Json,
+            RunConfig {
+                job: None,
+                backup_pool: String::from("backup"),
+                backup_pools: vec![String::from("backup")],
+                label: String::from("TEST"),
+                datasets: vec![String::from("tank/os")],
+                dry_run: false,
+            },
+        )
+    }
+
+    fn get_example_execute_options() -> ExecuteOptions<'static> {
+        static BANDWIDTH: Bandwidth = Bandwidth {
+            global: None,
+            datasets: BTreeMap::new(),
+        };
+        static RETRY: RetryPolicies = RetryPolicies::new();
+        ExecuteOptions {
+            send_flags: &[],
+            dry_run: false,
+            parallelism: Parallelism::default(),
+            bandwidth: &BANDWIDTH,
+            timeouts: Timeouts::default(),
+            interrupted: Arc::default(),
+            retry: &RETRY,
+        }
+    }
+
+    fn get_example_system() -> FakeSystem {
+        let mut system = FakeSystem::new();
+        system.datasets = vec![String::from("tank/os")];
+        system
+    }
+
+    fn get_example_plan(system: &FakeSystem, job: &Job, backup_pools: &[&str]) -> Plan {
+        let snapshots = vec![
+            Snapshot::new("tank/os@2022-09-01-0300-00-TEST"),
+            Snapshot::new("tank/os@2022-09-02-0300-00-TEST"),
+            Snapshot::new("backup/tank/os@2022-09-01-0300-00-TEST"),
+            Snapshot::new("usb/tank/os@2022-09-01-0300-00-TEST"),
+        ];
+        plan::plan_job(system, job, &snapshots, backup_pools)
+    }
+
+    #[test]
+    fn test_execute_should_send_and_prune() {
+        let system = get_example_system();
+        let mut job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        job.retention = Some(Retention { keep: 1 });
+        let plan = get_example_plan(&system, &job, &["backup"]);
+        let mut reporter = get_reporter();
+
+        execute(
+            &system,
+            &mut reporter,
+            &HookRunner::new(&[], &[], false),
+            &plan,
+            &get_example_execute_options(),
+        );
+        let dataset = &reporter.report().datasets[0];
+
+        assert_eq!(dataset.outcome, Outcome::Success);
+        assert_eq!(dataset.actions.len(), 2);
+        assert_eq!(
+            dataset.actions[1].kind,
+            ActionKind::Prune {
+                snapshot: String::from("backup/tank/os@2022-09-01-0300-00-TEST")
+            }
+        );
+    }
+
+    #[test]
+    fn test_execute_should_report_held_snapshots_instead_of_pruning_them() {
+        let mut system = get_example_system();
+        system.held_snapshots = Some(vec![String::from("backup/tank/os@2022-09-01-0300-00-TEST")]);
+        let mut job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        job.retention = Some(Retention { keep: 1 });
+        let plan = get_example_plan(&system, &job, &["backup"]);
+        let mut reporter = get_reporter();
+
+        execute(
+            &system,
+            &mut reporter,
+            &HookRunner::new(&[], &[], false),
+            &plan,
+            &get_example_execute_options(),
+        );
+        let dataset = &reporter.report().datasets[0];
+
+        assert_eq!(dataset.outcome, Outcome::Success);
+        assert_eq!(dataset.actions.len(), 1);
+        assert_eq!(
+            dataset.held_snapshots,
+            vec!["backup/tank/os@2022-09-01-0300-00-TEST"]
+        );
+    }
+
+    #[test]
+    fn test_execute_should_not_prune_after_failed_send() {
+        let mut system = get_example_system();
+        system.send_incremental_backup = false;
+        let mut job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        job.retention = Some(Retention { keep: 1 });
+        let plan = get_example_plan(&system, &job, &["backup"]);
+        let mut reporter = get_reporter();
+
+        execute(
+            &system,
+            &mut reporter,
+            &HookRunner::new(&[], &[], false),
+            &plan,
+            &get_example_execute_options(),
+        );
+        let dataset = &reporter.report().datasets[0];
+
+        assert_eq!(dataset.outcome, Outcome::Failed);
+        assert_eq!(dataset.actions.len(), 1);
+        assert!(!reporter.report().success);
+    }
+
+    #[test]
+    fn test_execute_should_not_start_datasets_after_interruption() {
+        let system = get_example_system();
+        let job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        let plan = get_example_plan(&system, &job, &["backup"]);
+        let mut reporter = get_reporter();
+
+        execute(
+            &system,
+            &mut reporter,
+            &HookRunner::new(&[], &[], false),
+            &plan,
+            &ExecuteOptions {
+                interrupted: Arc::new(AtomicBool::new(true)),
+                ..get_example_execute_options()
+            },
+        );
+        let report = reporter.report();
+
+        assert_eq!(report.datasets[0].outcome, Outcome::Skipped);
+        assert!(report.datasets[0].actions.is_empty());
+        assert!(!report.success);
+    }
+
+    #[test]
+    fn test_execute_should_retry_transient_failures() {
+        let system = get_example_system();
+        *system.send_errors.lock().unwrap() = vec![
+            String::from("'zfs recv' failed: cannot receive incremental stream: dataset is busy"),
+            String::from("'zfs recv' failed: cannot receive incremental stream: dataset is busy"),
+        ];
+        let job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        let plan = get_example_plan(&system, &job, &["backup"]);
+        let mut reporter = get_reporter();
+        let retry = RetryPolicies::from([(
+            retry::ErrorClass::Busy,
+            retry::RetryPolicy {
+                retries: 2,
+                delay: Duration::ZERO,
+                max_delay: Duration::ZERO,
+            },
+        )]);
+
+        execute(
+            &system,
+            &mut reporter,
+            &HookRunner::new(&[], &[], false),
+            &plan,
+            &ExecuteOptions {
+                retry: &retry,
+                ..get_example_execute_options()
+            },
+        );
+        let dataset = &reporter.report().datasets[0];
+        let attempts = &dataset.actions[0].attempts;
+
+        assert_eq!(dataset.outcome, Outcome::Success);
+        assert_eq!(attempts.len(), 3);
+        assert_eq!(attempts[0].error_class, Some(retry::ErrorClass::Busy));
+        assert_eq!(attempts[0].retry_delay_ms, Some(0));
+        assert!(attempts[2].success);
+    }
+
+    #[test]
+    fn test_execute_should_resume_retried_sends_from_their_token() {
+        let mut system = get_example_system();
+        system.failed_send_token = Some(String::from("1-abc"));
+        system.send_incremental_backup = false;
+        *system.send_errors.lock().unwrap() = vec![String::from(
+            "no data was transferred for 10m, so 'zfs send' and 'zfs recv' were stopped",
+        )];
+        let job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        let plan = get_example_plan(&system, &job, &["backup"]);
+        let mut reporter = get_reporter();
+        let retry = RetryPolicies::from([(
+            retry::ErrorClass::Stalled,
+            retry::RetryPolicy {
+                retries: 1,
+                delay: Duration::ZERO,
+                max_delay: Duration::ZERO,
+            },
+        )]);
+
+        execute(
+            &system,
+            &mut reporter,
+            &HookRunner::new(&[], &[], false),
+            &plan,
+            &ExecuteOptions {
+                retry: &retry,
+                ..get_example_execute_options()
+            },
+        );
+        let dataset = &reporter.report().datasets[0];
+
+        assert_eq!(dataset.outcome, Outcome::Success);
+        assert_eq!(*system.resumed.lock().unwrap(), vec![String::from("1-abc")]);
+        assert!(dataset.actions[0].attempts[1].resumed);
+    }
+
+    #[test]
+    fn test_execute_should_finish_receives_left_by_an_earlier_run() {
+        let mut system = get_example_system();
+        *system.resume_token.get_mut().unwrap() = Some(String::from("1-abc"));
+        system.send_incremental_backup = false;
+        system.guids.insert(
+            String::from("backup/tank/os"),
+            vec![
+                (String::from("backup/tank/os@2022-09-01-0300-00-TEST"), 1),
+                (String::from("backup/tank/os@2022-09-02-0300-00-TEST"), 2),
+            ],
+        );
+        let job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        let plan = get_example_plan(&system, &job, &["backup"]);
+        let mut reporter = get_reporter();
+
+        execute(
+            &system,
+            &mut reporter,
+            &HookRunner::new(&[], &[], false),
+            &plan,
+            &get_example_execute_options(),
+        );
+
+        assert_eq!(reporter.report().datasets[0].outcome, Outcome::Success);
+        assert_eq!(*system.resumed.lock().unwrap(), vec![String::from("1-abc")]);
+    }
+
+    #[test]
+    fn test_execute_should_send_what_the_resumed_receive_is_missing() {
+        let mut system = get_example_system();
+        *system.resume_token.get_mut().unwrap() = Some(String::from("1-abc"));
+        system.send_incremental_backup = false;
+        system.guids.insert(
+            String::from("backup/tank/os"),
+            vec![
+                (String::from("backup/tank/os@2022-09-01-0300-00-TEST"), 1),
+                (String::from("backup/tank/os@2022-09-01-1500-00-TEST"), 3),
+            ],
+        );
+        let job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        let plan = get_example_plan(&system, &job, &["backup"]);
+        let mut reporter = get_reporter();
+
+        execute(
+            &system,
+            &mut reporter,
+            &HookRunner::new(&[], &[], false),
+            &plan,
+            &get_example_execute_options(),
+        );
+
+        assert_eq!(reporter.report().datasets[0].outcome, Outcome::Failed);
+        assert_eq!(*system.resumed.lock().unwrap(), vec![String::from("1-abc")]);
+    }
+
+    #[test]
+    fn test_is_ready_should_wait_for_ancestors_and_busy_pools() {
+        let datasets: Vec<DatasetPlan> = ["tank/os", "tank/os/home", "tank/var", "data/db"]
+            .iter()
+            .map(|dataset| DatasetPlan {
+                dataset: String::from(*dataset),
+                targets: Vec::new(),
+            })
+            .collect();
+
+        assert!(is_ready(&datasets, &[1, 2, 3], &[], 0, 1));
+        assert!(!is_ready(&datasets, &[2, 3], &[0], 1, 2));
+        assert!(!is_ready(&datasets, &[1, 3], &[0], 2, 1));
+        assert!(is_ready(&datasets, &[1, 3], &[0], 2, 2));
+        assert!(is_ready(&datasets, &[1, 2], &[0], 3, 1));
+    }
+
+    #[test]
+    fn test_execute_should_record_parallel_datasets_in_plan_order() {
+        let mut system = get_example_system();
+        system
+            .datasets
+            .extend([String::from("tank/os/home"), String::from("data/db")]);
+        let mut job = Job::new(
+            "backup",
+            "TEST",
+            &[
+                String::from("tank/os"),
+                String::from("tank/os/home"),
+                String::from("data/db"),
+            ],
+        );
+        job.retention = Some(Retention { keep: 1 });
+        let snapshots = vec![
+            Snapshot::new("tank/os@2022-09-01-0300-00-TEST"),
+            Snapshot::new("tank/os/home@2022-09-01-0300-00-TEST"),
+            Snapshot::new("data/db@2022-09-01-0300-00-TEST"),
+        ];
+        let plan = plan::plan_job(&system, &job, &snapshots, &["backup"]);
+        let mut reporter = get_reporter();
+
+        execute(
+            &system,
+            &mut reporter,
+            &HookRunner::new(&[], &[], false),
+            &plan,
+            &ExecuteOptions {
+                parallelism: Parallelism {
+                    datasets: 3,
+                    per_pool: 1,
+                },
+                ..get_example_execute_options()
+            },
+        );
+        let datasets = &reporter.report().datasets;
+
+        assert_eq!(
+            datasets
+                .iter()
+                .map(|dataset| dataset.dataset.as_str())
+                .collect::<Vec<_>>(),
+            ["tank/os", "tank/os/home", "data/db"]
+        );
+        assert!(datasets
+            .iter()
+            .all(|dataset| dataset.outcome == Outcome::Success));
+        assert!(reporter.report().success);
+    }
+
+    #[test]
+    fn test_execute_should_tee_identical_sends() {
+        let system = get_example_system();
+        let mut job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        job.backup_pools.push(String::from("usb"));
+        job.tee = true;
+        let plan = get_example_plan(&system, &job, &["backup", "usb"]);
+        let mut reporter = get_reporter();
+
+        execute(
+            &system,
+            &mut reporter,
+            &HookRunner::new(&[], &[], true),
+            &plan,
+            &ExecuteOptions {
+                dry_run: true,
+                ..get_example_execute_options()
+            },
+        );
+        let datasets = &reporter.report().datasets;
+
+        assert!(plan.tee);
+        assert_eq!(datasets.len(), 2);
+        assert_eq!(datasets[0].backup_dataset, "backup/tank/os");
+        assert_eq!(datasets[1].backup_dataset, "usb/tank/os");
+        assert!(datasets
+            .iter()
+            .all(|dataset| dataset.outcome == Outcome::Planned));
+    }
+}
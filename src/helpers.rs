@@ -23,9 +23,19 @@
 // OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
 // SUCH DAMAGE.
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use clap::Parser;
+use chrono::NaiveDateTime;
+use clap::{Parser, Subcommand};
+
+use crate::bandwidth;
+use crate::report::OutputFormat;
 
 const APP_NAME: &str = "Cantaloupe";
 const APP_VERSION: &str = clap::crate_version!();
@@ -41,28 +51,272 @@ pub fn print_header() {
 }
 
 #[derive(Parser)]
-#[command(name = APP_NAME, version)]
+#[command(
+    name = APP_NAME,
+    version,
+    subcommand_negates_reqs = true,
+    override_usage = "cantaloupe [OPTIONS] <BACKUP_POOL> <LABEL> <DATASETS>...\n       cantaloupe [OPTIONS] <COMMAND>"
+)]
 pub struct Args {
     #[arg(
         short = 'n',
         long,
-        help = "Performs a dry run. Does not require root privileges."
+        global = true,
+        help = "Performs a dry run. Only requires root privileges to import backup pools (read-only)."
     )]
     pub dry_run: bool,
 
-    pub backup_pool: String,
+    #[arg(
+        short = 'o',
+        long,
+        value_enum,
+        global = true,
+        default_value_t = OutputFormat::Text,
+        help = "Output format. 'json' prints a single document at the end of the run, 'jsonl' prints one event per line as it happens."
+    )]
+    pub output: OutputFormat,
+
+    #[arg(
+        short = 'c',
+        long,
+        global = true,
+        help = "Path to the configuration file."
+    )]
+    pub config: Option<PathBuf>,
+
+    #[arg(
+        short = 's',
+        long,
+        global = true,
+        help = "Takes a new snapshot of each source dataset before replicating."
+    )]
+    pub snapshot: bool,
+
+    #[arg(
+        short = 'r',
+        long,
+        global = true,
+        help = "Also snapshots the descendants of each dataset when taking snapshots."
+    )]
+    pub recursive: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Takes the snapshots of all datasets atomically in a single 'zfs snapshot' call."
+    )]
+    pub atomic: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Continues backups made with another label from the newest snapshot both sides share (matched by GUID)."
+    )]
+    pub adopt: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Sends a single stream into every backup pool that needs the same snapshots, instead of one send per pool."
+    )]
+    pub tee: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Imports the backup pools that aren't imported yet (without mounting anything) and exports them again afterwards."
+    )]
+    pub import: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Imports the backup pools under this directory ('zpool import -R'). Implies --import."
+    )]
+    pub altroot: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Scrubs the backup pools once everything has been sent and fails the run if the scrub finds unrecoverable errors."
+    )]
+    pub scrub: bool,
+
+    #[arg(
+        short = 'j',
+        long,
+        global = true,
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "Replicates up to this many datasets at once. Datasets wait for their ancestors to be done."
+    )]
+    pub jobs: Option<u16>,
+
+    #[arg(
+        long,
+        global = true,
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "Replicates up to this many datasets of the same source pool at once. Defaults to 1."
+    )]
+    pub jobs_per_pool: Option<u16>,
+
+    #[arg(
+        long,
+        global = true,
+        value_parser = parse_duration,
+        help = "Stops the sends of a dataset that take longer than this altogether (e.g. 6h) and fails the dataset."
+    )]
+    pub timeout: Option<Duration>,
+
+    #[arg(
+        long,
+        global = true,
+        value_parser = parse_duration,
+        help = "Stops a send that moves no data for this long (e.g. 10m) and fails the dataset."
+    )]
+    pub stall_timeout: Option<Duration>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Retries sends that fail because a dataset is busy, a connection broke or the send stalled this many times, waiting longer each time. Replaces the retry policies of the configuration file."
+    )]
+    pub retries: Option<u32>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "RATE",
+        value_parser = bandwidth::parse_rate,
+        help = "Limits all of the sends together to this many bytes per second (e.g. 50M). Replaces the limit of the configuration file."
+    )]
+    pub bwlimit: Option<u64>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "Writes the commands that would be run to a POSIX shell script ('-' for stdout) instead of running them."
+    )]
+    pub emit_script: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+
+    #[arg(
+        required = true,
+        help = "The backup pool, or several separated by commas (e.g. usb1,usb2)."
+    )]
+    pub backup_pool: Option<String>,
+    #[arg(required = true)]
+    pub label: Option<String>,
+
+    #[arg(num_args = 1.., required = true)]
+    pub datasets: Vec<String>,
+}
+
+#[derive(Subcommand)]
+pub enum Commands {
+    #[command(about = "Runs one or all of the jobs in the configuration file.")]
+    Run(RunArgs),
+    #[command(
+        about = "Works out what a job would do without changing anything and saves it to a file."
+    )]
+    Plan(PlanArgs),
+    #[command(
+        about = "Applies a saved plan, refusing to if the pools or datasets changed since it was made."
+    )]
+    Apply(ApplyArgs),
+    #[command(about = "Runs the scheduled jobs in the configuration file until stopped.")]
+    Daemon,
+    #[command(about = "Takes a snapshot of each dataset without replicating it.")]
+    Snapshot(SnapshotArgs),
+    #[command(about = "Shows the replication state of each dataset without changing anything.")]
+    Status(StatusArgs),
+    #[command(about = "Shows which snapshots were last seen on each backup disk, by pool GUID.")]
+    Catalog,
+    #[command(
+        about = "Checks how old the newest backup of each dataset is. Usable as a Nagios/Icinga plugin."
+    )]
+    Check(CheckArgs),
+}
+
+#[derive(clap::Args)]
+pub struct CheckArgs {
+    #[arg(help = "Only checks the given job. Defaults to all of the jobs.")]
+    pub job: Option<String>,
+
+    #[arg(long, value_parser = parse_duration, help = "Warns when the newest backup is older than this (e.g. 26h).")]
+    pub warning: Duration,
+
+    #[arg(long, value_parser = parse_duration, help = "Goes critical when the newest backup is older than this (e.g. 3d).")]
+    pub critical: Duration,
+}
+
+#[derive(clap::Args)]
+pub struct PlanArgs {
+    pub job: String,
+
+    #[arg(long, help = "Where to save the plan.")]
+    pub out: PathBuf,
+}
+
+#[derive(clap::Args)]
+pub struct ApplyArgs {
+    #[arg(help = "A plan saved by the 'plan' command.")]
+    pub plan: PathBuf,
+}
+
+#[derive(clap::Args)]
+pub struct StatusArgs {
+    #[arg(help = "Only shows the given job. Defaults to all of the jobs.")]
+    pub job: Option<String>,
+}
+
+#[derive(clap::Args)]
+pub struct SnapshotArgs {
     pub label: String,
 
     #[arg(num_args = 1.., required = true)]
     pub datasets: Vec<String>,
 }
 
+#[derive(clap::Args)]
+pub struct RunArgs {
+    #[arg(required_unless_present = "all", conflicts_with = "all")]
+    pub job: Option<String>,
+
+    #[arg(short = 'a', long, help = "Runs all of the jobs.")]
+    pub all: bool,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Overrides the backup pools of the job. Several can be separated by commas."
+    )]
+    pub backup_pool: Vec<String>,
+
+    #[arg(long, help = "Overrides the label of the job.")]
+    pub label: Option<String>,
+}
+
+// Splits a comma-separated list of backup pools, dropping empty entries and
+// duplicates.
+pub fn get_backup_pools(value: &str) -> Vec<String> {
+    let mut backup_pools: Vec<String> = Vec::new();
+    for backup_pool in value.split(',').map(str::trim) {
+        if !backup_pool.is_empty() && !backup_pools.iter().any(|pool| pool == backup_pool) {
+            backup_pools.push(String::from(backup_pool));
+        }
+    }
+    backup_pools
+}
+
 pub fn get_source_pool_name(dataset_name: &str) -> &str {
     let splinters: Vec<_> = dataset_name.split("/").collect();
     splinters[0]
 }
 
-pub fn get_source_pool_names(datasets: &Vec<String>) -> HashSet<&str> {
+pub fn get_source_pool_names(datasets: &[String]) -> HashSet<&str> {
     datasets.iter().map(|x| get_source_pool_name(x)).collect()
 }
 
@@ -70,9 +324,200 @@ pub fn get_backup_dataset(backup_pool_name: &str, source_dataset_name: &str) ->
     format!("{}/{}", backup_pool_name, source_dataset_name)
 }
 
+// Gets the backup dataset for the given source dataset. If the source dataset
+// (or its closest ancestor) is in the mapping, the mapped dataset is used as
+// the base. Otherwise the source dataset is mirrored under the backup pool.
+pub fn get_mapped_backup_dataset(
+    backup_pool_name: &str,
+    source_dataset_name: &str,
+    mapping: &BTreeMap<String, String>,
+) -> String {
+    let mut ancestor = source_dataset_name;
+    loop {
+        if let Some(target) = mapping.get(ancestor) {
+            let remainder = &source_dataset_name[ancestor.len()..];
+            return format!("{}{}", target, remainder);
+        }
+        match ancestor.rsplit_once('/') {
+            Some((parent, _)) => ancestor = parent,
+            None => return get_backup_dataset(backup_pool_name, source_dataset_name),
+        }
+    }
+}
+
+// Whether one of the datasets is the other, or an ancestor of it.
+pub fn datasets_overlap(a: &str, b: &str) -> bool {
+    let contains = |ancestor: &str, dataset: &str| {
+        dataset
+            .strip_prefix(ancestor)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    };
+    contains(a, b) || contains(b, a)
+}
+
+// Gets the name of a snapshot of the given dataset in the format shared with
+// Honeydew: YYYY-mm-dd-HHMM-ss-LABEL.
+pub fn get_snapshot_name(dataset_name: &str, label: &str, time: &NaiveDateTime) -> String {
+    format!(
+        "{}@{}-{}",
+        dataset_name,
+        time.format("%Y-%m-%d-%H%M-%S"),
+        label
+    )
+}
+
+// How long a command that was asked to stop has to exit before it's killed.
+const STOP_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+// Runs the given command to completion, optionally feeding it input on
+// stdin. It's stopped if it runs for longer than the timeout, or if the run
+// is interrupted while it runs (commands started after the interruption,
+// such as cleanup, still get to finish). If the command was started in its
+// own process group, the whole group is stopped so that none of its children
+// outlive it.
+pub fn run_with_timeout(
+    mut command: Command,
+    input: Option<Vec<u8>>,
+    timeout: Option<Duration>,
+    interrupted: Option<&AtomicBool>,
+) -> Result<(), String> {
+    let interrupted = interrupted.filter(|flag| !flag.load(Ordering::SeqCst));
+    let mut child = command
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .spawn()
+        .map_err(|e| format!("failed to execute: {}", e))?;
+
+    // Write the input on its own thread so that a child which doesn't read
+    // all of it can't block us past the timeout.
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        thread::spawn(move || stdin.write_all(&input));
+    }
+
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => return Ok(()),
+            Ok(Some(status)) => return Err(format!("exited with {}", status)),
+            Ok(None) => {}
+            Err(e) => return Err(e.to_string()),
+        }
+
+        if let Some(timeout) = timeout {
+            if started.elapsed() >= timeout {
+                stop(&mut child);
+                return Err(format!("timed out after {}s", timeout.as_secs()));
+            }
+        }
+        if interrupted.is_some_and(|flag| flag.load(Ordering::SeqCst)) {
+            stop(&mut child);
+            return Err(String::from("interrupted"));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// Asks the child (and its process group, if it leads one) to terminate, and
+// kills it if it's still running after the grace period.
+fn stop(child: &mut Child) {
+    let pid = child.id().to_string();
+    let group = format!("-{}", pid);
+    send_signal("TERM", &[&group, &pid]);
+    let asked = Instant::now();
+    while asked.elapsed() < STOP_GRACE_PERIOD {
+        if !matches!(child.try_wait(), Ok(None)) {
+            // The child is gone, but what it started may not be. Its PID
+            // may be reused from now on, so only its group is signaled.
+            send_signal("KILL", &[&group]);
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    send_signal("KILL", &[&group]);
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+// Sends the signal to the given processes and process groups (negative
+// IDs). Ones that don't exist are quietly skipped.
+pub fn send_signal(signal: &str, targets: &[&str]) {
+    let _ = Command::new("kill")
+        .arg(format!("-{}", signal))
+        .arg("--")
+        .args(targets)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+}
+
+// Parses a human friendly duration such as "90s", "30m", "6h", "1d" or
+// "1h30m" into a Duration.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || {
+        format!(
+            "'{}' is not a valid duration (e.g. 90s, 30m, 6h, 1d)",
+            value
+        )
+    };
+    let mut total: u64 = 0;
+    let mut number = String::new();
+
+    for c in value.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let multiplier = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let amount: u64 = number.parse().map_err(|_| invalid())?;
+        total = amount
+            .checked_mul(multiplier)
+            .and_then(|seconds| total.checked_add(seconds))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+
+    if !number.is_empty() || total == 0 {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(total))
+}
+
+// Formats a number of seconds in the same units parse_duration accepts,
+// keeping the two most significant ones (e.g. "1d6h", "5m30s").
+pub fn format_duration(seconds: u64) -> String {
+    let units = [(24 * 60 * 60, 'd'), (60 * 60, 'h'), (60, 'm'), (1, 's')];
+    let mut remaining = seconds;
+    let mut parts = Vec::new();
+
+    for (size, unit) in units {
+        if remaining >= size && parts.len() < 2 {
+            parts.push(format!("{}{}", remaining / size, unit));
+            remaining %= size;
+        } else if !parts.is_empty() {
+            break;
+        }
+    }
+
+    if parts.is_empty() {
+        String::from("0s")
+    } else {
+        parts.concat()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::snapshot::Snapshot;
 
     #[test]
     fn test_get_source_pool_name_should_return_pool_name() {
@@ -95,6 +540,15 @@ mod tests {
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn test_get_backup_pools_should_split_on_commas() {
+        let expected = vec![String::from("usb1"), String::from("usb2")];
+
+        let backup_pools = get_backup_pools("usb1, usb2,,usb1");
+
+        assert_eq!(backup_pools, expected);
+    }
+
     #[test]
     fn test_get_backup_dataset_should_get_name() {
         let expected_name = "backup/tank/var/log";
@@ -103,4 +557,87 @@ mod tests {
 
         assert_eq!(name, expected_name);
     }
+
+    #[test]
+    fn test_get_mapped_backup_dataset_should_use_closest_ancestor() {
+        let mapping = BTreeMap::from([
+            (String::from("tank"), String::from("backup/everything")),
+            (String::from("tank/os"), String::from("backup/os")),
+        ]);
+
+        assert_eq!(
+            get_mapped_backup_dataset("backup", "tank/os/main", &mapping),
+            "backup/os/main"
+        );
+        assert_eq!(
+            get_mapped_backup_dataset("backup", "tank/os", &mapping),
+            "backup/os"
+        );
+        assert_eq!(
+            get_mapped_backup_dataset("backup", "tank/var/log", &mapping),
+            "backup/everything/var/log"
+        );
+        assert_eq!(
+            get_mapped_backup_dataset("backup", "zroot/home", &mapping),
+            "backup/zroot/home"
+        );
+    }
+
+    #[test]
+    fn test_get_mapped_backup_dataset_should_not_match_partial_names() {
+        let mapping = BTreeMap::from([(String::from("tank/os"), String::from("backup/os"))]);
+
+        assert_eq!(
+            get_mapped_backup_dataset("backup", "tank/osx", &mapping),
+            "backup/tank/osx"
+        );
+    }
+
+    #[test]
+    fn test_datasets_overlap_should_only_match_whole_names() {
+        assert!(datasets_overlap("tank/data", "tank/data"));
+        assert!(datasets_overlap("tank", "tank/replica/tank/data"));
+        assert!(datasets_overlap(
+            "tank/replica/tank/data/db",
+            "tank/replica"
+        ));
+        assert!(!datasets_overlap("tank/data", "tank/replica/tank/data"));
+        assert!(!datasets_overlap("tank/data", "tank/database"));
+    }
+
+    #[test]
+    fn test_get_snapshot_name_should_use_honeydew_format() {
+        let time =
+            NaiveDateTime::parse_from_str("2022-09-01 12:34:56", "%Y-%m-%d %H:%M:%S").unwrap();
+
+        let name = get_snapshot_name("tank/var/log", "ANIMALS", &time);
+
+        assert_eq!(name, "tank/var/log@2022-09-01-1234-56-ANIMALS");
+        assert_eq!(Snapshot::from_batch(&[name.as_str()]).len(), 1);
+    }
+
+    #[test]
+    fn test_parse_duration_should_parse_units() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(24 * 60 * 60)));
+    }
+
+    #[test]
+    fn test_parse_duration_should_reject_invalid_values() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("30").is_err());
+        assert!(parse_duration("0m").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("5 weeks").is_err());
+    }
+
+    #[test]
+    fn test_format_duration_should_keep_two_units() {
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(330), "5m30s");
+        assert_eq!(format_duration(3600), "1h");
+        assert_eq!(format_duration(24 * 60 * 60 + 6 * 60 * 60 + 59), "1d6h");
+    }
 }
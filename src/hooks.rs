@@ -0,0 +1,290 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::helpers;
+use crate::interrupt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookPoint {
+    // Before anything else happens (including taking snapshots).
+    RunStart,
+    // After every dataset has been processed.
+    RunEnd,
+    // Right before a dataset is sent to the backup pool.
+    BeforeSend,
+    // Right after a dataset was sent to the backup pool (successfully or not).
+    AfterSend,
+}
+
+impl Display for HookPoint {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let name = match self {
+            HookPoint::RunStart => "run_start",
+            HookPoint::RunEnd => "run_end",
+            HookPoint::BeforeSend => "before_send",
+            HookPoint::AfterSend => "after_send",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookFailurePolicy {
+    // A failing run_start hook aborts the run and a failing before_send hook
+    // skips the dataset. Failing post hooks are reported as errors.
+    #[default]
+    Skip,
+    // The failure is printed but otherwise ignored.
+    Ignore,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hook {
+    pub when: HookPoint,
+    // Run through 'sh -c'.
+    pub command: String,
+    pub timeout: Option<Duration>,
+    pub on_failure: HookFailurePolicy,
+}
+
+// Runs the hooks of a job, passing the context along as environment
+// variables. Every variable is prefixed with CANTALOUPE_.
+pub struct HookRunner<'a> {
+    hooks: &'a [Hook],
+    environment: Vec<(String, String)>,
+    dry_run: bool,
+}
+
+impl<'a> HookRunner<'a> {
+    pub fn new(hooks: &'a [Hook], environment: &[(&str, String)], dry_run: bool) -> Self {
+        Self {
+            hooks,
+            environment: Self::prefix(environment),
+            dry_run,
+        }
+    }
+
+    // Runs every hook registered for the given point, in order. Messages are
+    // passed to 'text'. Returns the errors of the hooks that failed with the
+    // 'skip' policy; failures of 'ignore' hooks are only printed.
+    pub fn run(
+        &self,
+        point: HookPoint,
+        environment: &[(&str, String)],
+        text: &dyn Fn(&str),
+    ) -> Result<(), String> {
+        let mut errors = Vec::new();
+        let mut variables = self.environment.clone();
+        variables.push((String::from("CANTALOUPE_HOOK"), point.to_string()));
+        variables.extend(Self::prefix(environment));
+
+        for hook in self.hooks.iter().filter(|hook| hook.when == point) {
+            text(&format!("Running {} hook: {} ...", point, hook.command));
+            if self.dry_run {
+                continue;
+            }
+
+            if let Err(error) = run_hook(hook, &variables, interrupt::get_flag()) {
+                let error = format!("The {} hook '{}' failed: {}", point, hook.command, error);
+                text(&error);
+                if hook.on_failure == HookFailurePolicy::Skip {
+                    errors.push(error);
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(" "))
+        }
+    }
+
+    fn prefix(environment: &[(&str, String)]) -> Vec<(String, String)> {
+        environment
+            .iter()
+            .map(|(key, value)| (format!("CANTALOUPE_{}", key), value.clone()))
+            .collect()
+    }
+}
+
+// Runs a single hook, stopping it if it exceeds its timeout or the run is
+// interrupted while it runs.
+pub fn run_hook(
+    hook: &Hook,
+    environment: &[(String, String)],
+    interrupted: &AtomicBool,
+) -> Result<(), String> {
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(&hook.command)
+        .envs(environment.iter().map(|(key, value)| (key, value)))
+        // Keep stdout free for Cantaloupe's own (possibly machine readable)
+        // output.
+        .stdout(io::stderr())
+        // Run the hook in its own process group so that stopping it also
+        // stops anything it started, not just the shell.
+        .process_group(0);
+
+    helpers::run_with_timeout(command, None, hook.timeout, Some(interrupted))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::sync::atomic::Ordering;
+    use std::time::Instant;
+
+    use super::*;
+
+    fn get_hook(when: HookPoint, command: &str) -> Hook {
+        Hook {
+            when,
+            command: String::from(command),
+            timeout: None,
+            on_failure: HookFailurePolicy::Skip,
+        }
+    }
+
+    #[test]
+    fn test_run_hook_should_pass_environment() {
+        let hook = get_hook(
+            HookPoint::AfterSend,
+            "test \"$CANTALOUPE_DATASET\" = tank/var/log",
+        );
+        let environment = vec![(
+            String::from("CANTALOUPE_DATASET"),
+            String::from("tank/var/log"),
+        )];
+
+        assert!(run_hook(&hook, &environment, &AtomicBool::new(false)).is_ok());
+        assert!(run_hook(&hook, &[], &AtomicBool::new(false)).is_err());
+    }
+
+    #[test]
+    fn test_run_hook_should_time_out() {
+        let mut hook = get_hook(HookPoint::RunStart, "sleep 5");
+        hook.timeout = Some(Duration::from_millis(100));
+
+        let started = Instant::now();
+        let result = run_hook(&hook, &[], &AtomicBool::new(false));
+
+        assert!(result.unwrap_err().contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_run_hook_should_kill_its_children_on_timeout() {
+        let marker = std::env::temp_dir().join(format!("cantaloupe-hook-{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+        let mut hook = get_hook(
+            HookPoint::RunStart,
+            &format!("(sleep 1; touch '{}') & wait", marker.display()),
+        );
+        hook.timeout = Some(Duration::from_millis(100));
+
+        let result = run_hook(&hook, &[], &AtomicBool::new(false));
+        std::thread::sleep(Duration::from_millis(1500));
+
+        assert!(result.unwrap_err().contains("timed out"));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn test_run_hook_should_stop_when_interrupted() {
+        let hook = get_hook(HookPoint::RunEnd, "sleep 5");
+        let interrupted = AtomicBool::new(false);
+
+        let started = Instant::now();
+        let result = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(100));
+                interrupted.store(true, Ordering::SeqCst);
+            });
+            run_hook(&hook, &[], &interrupted)
+        });
+
+        assert_eq!(result.unwrap_err(), "interrupted");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_run_hook_should_still_run_after_an_interruption() {
+        let hook = get_hook(HookPoint::RunEnd, "sleep 0.2");
+
+        let result = run_hook(&hook, &[], &AtomicBool::new(true));
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_runner_should_only_run_hooks_for_the_given_point() {
+        let hooks = vec![
+            get_hook(HookPoint::RunStart, "true"),
+            get_hook(HookPoint::BeforeSend, "false"),
+        ];
+        let runner = HookRunner::new(&hooks, &[("JOB", String::from("nightly"))], false);
+
+        assert!(runner.run(HookPoint::RunStart, &[], &|_| {}).is_ok());
+        assert!(runner.run(HookPoint::BeforeSend, &[], &|_| {}).is_err());
+        assert!(runner.run(HookPoint::AfterSend, &[], &|_| {}).is_ok());
+    }
+
+    #[test]
+    fn test_runner_should_not_fail_on_ignored_hooks() {
+        let mut hook = get_hook(HookPoint::BeforeSend, "false");
+        hook.on_failure = HookFailurePolicy::Ignore;
+        let hooks = vec![hook];
+        let messages = RefCell::new(Vec::new());
+        let runner = HookRunner::new(&hooks, &[], false);
+
+        let result = runner.run(HookPoint::BeforeSend, &[], &|message| {
+            messages.borrow_mut().push(String::from(message))
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(messages.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_runner_should_not_run_hooks_in_dry_run() {
+        let hooks = vec![get_hook(HookPoint::RunStart, "false")];
+        let runner = HookRunner::new(&hooks, &[], true);
+
+        assert!(runner.run(HookPoint::RunStart, &[], &|_| {}).is_ok());
+    }
+}
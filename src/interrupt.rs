@@ -0,0 +1,58 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+
+// The exit code of a run that was stopped by SIGINT or SIGTERM.
+pub const EXIT_CODE: i32 = 130;
+
+static INTERRUPTED: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+// Set once SIGINT or SIGTERM is received, after 'install'.
+pub fn get_flag() -> &'static Arc<AtomicBool> {
+    INTERRUPTED.get_or_init(Arc::default)
+}
+
+pub fn is_interrupted() -> bool {
+    get_flag().load(Ordering::SeqCst)
+}
+
+// Makes SIGINT and SIGTERM set the flag instead of killing the process, so
+// that runs can stop at the next dataset and clean up after themselves. A
+// second signal quits right away.
+pub fn install() -> Result<(), String> {
+    for signal in [SIGINT, SIGTERM] {
+        // Registered first, so that it only sees the flag set by an earlier
+        // signal.
+        flag::register_conditional_shutdown(signal, EXIT_CODE, Arc::clone(get_flag()))
+            .and_then(|_| flag::register(signal, Arc::clone(get_flag())))
+            .map_err(|e| format!("Failed to register the signal handlers: {}", e))?;
+    }
+    Ok(())
+}
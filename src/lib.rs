@@ -23,9 +23,28 @@
 // OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
 // SUCH DAMAGE.
 
+pub mod bandwidth;
+pub mod catalog;
+pub mod check;
+pub mod config;
+pub mod daemon;
+pub mod executor;
 pub mod helpers;
+pub mod hooks;
+pub mod interrupt;
+pub mod metrics;
+pub mod notify;
+pub mod plan;
+pub mod pools;
 pub mod providers;
+pub mod report;
+pub mod retry;
+pub mod runner;
+pub mod schedule;
+pub mod script;
 pub mod snapshot;
+pub mod state;
+pub mod status;
 pub mod testing;
 pub mod traits;
 
@@ -42,25 +61,43 @@ pub struct Cantaloupe {
 
 impl Cantaloupe {
     pub fn new(
-        pending_snapshots: &Vec<Snapshot>,
+        pending_snapshots: &[Snapshot],
         backup_pool_name: &str,
         source_dataset_name: &str,
         label: &str,
     ) -> Self {
-        let mut snapshots = pending_snapshots.clone();
-        snapshots.sort_unstable();
-
         let backup_dataset_name =
             helpers::get_backup_dataset(backup_pool_name, source_dataset_name);
+        Self::new_with_backup_dataset(
+            pending_snapshots,
+            backup_pool_name,
+            &backup_dataset_name,
+            source_dataset_name,
+            label,
+        )
+    }
+
+    // Same as new() but replicates into an explicit backup dataset rather
+    // than mirroring the source dataset's path under the backup pool.
+    pub fn new_with_backup_dataset(
+        pending_snapshots: &[Snapshot],
+        backup_pool_name: &str,
+        backup_dataset_name: &str,
+        source_dataset_name: &str,
+        label: &str,
+    ) -> Self {
+        let mut snapshots = pending_snapshots.to_vec();
+        snapshots.sort_unstable();
+
         let source_snapshots_labeled =
-            Self::get_snapshots(&snapshots, &source_dataset_name, &label, true);
+            Self::get_snapshots(&snapshots, source_dataset_name, label, true);
         let backup_snapshots_labeled =
-            Self::get_snapshots(&snapshots, &backup_dataset_name, &label, true);
+            Self::get_snapshots(&snapshots, backup_dataset_name, label, true);
 
         Self {
             snapshots,
             backup_pool_name: String::from(backup_pool_name),
-            backup_dataset_name: backup_dataset_name,
+            backup_dataset_name: String::from(backup_dataset_name),
             label: String::from(label),
             source_snapshots_labeled,
             backup_snapshots_labeled,
@@ -87,12 +124,25 @@ impl Cantaloupe {
         )
     }
 
+    // Gets all the snapshots of the given dataset (label ignored).
+    pub fn get_dataset_snapshots(&self, dataset_name: &str) -> Vec<Snapshot> {
+        Self::get_snapshots(&self.snapshots, dataset_name, &self.label, false)
+    }
+
+    pub fn get_backup_pool_name(&self) -> &str {
+        &self.backup_pool_name
+    }
+
+    pub fn get_backup_dataset_name(&self) -> &str {
+        &self.backup_dataset_name
+    }
+
     pub fn get_common_snapshot(&self) -> Option<&str> {
         for source_snapshot in self.source_snapshots_labeled.iter().rev() {
-            let backup_snapshot = format!("{}/{}", self.backup_pool_name, source_snapshot);
+            let backup_snapshot = self.get_backup_snapshot_name(&source_snapshot.name);
             if self
                 .backup_snapshots_labeled
-                .contains(&&Snapshot::new(&backup_snapshot))
+                .contains(&Snapshot::new(&backup_snapshot))
             {
                 return Some(&source_snapshot.name);
             }
@@ -104,8 +154,126 @@ impl Cantaloupe {
         self.source_snapshots_labeled.last().unwrap().name.as_str()
     }
 
+    // Gets the newest labeled snapshot on the backup dataset.
+    pub fn get_latest_backup_snapshot(&self) -> Option<&Snapshot> {
+        self.backup_snapshots_labeled.last()
+    }
+
+    // Gets how many labeled source snapshots haven't been replicated yet.
+    pub fn get_snapshots_behind(&self) -> usize {
+        match self.get_common_snapshot() {
+            Some(common) => self
+                .source_snapshots_labeled
+                .iter()
+                .filter(|snapshot| snapshot.name.as_str() > common)
+                .count(),
+            None => self.source_snapshots_labeled.len(),
+        }
+    }
+
+    // Whether the backup has labeled snapshots that don't follow from the
+    // source, meaning an incremental send can't continue from it as is.
+    pub fn has_diverged(&self) -> bool {
+        if self.backup_snapshots_labeled.is_empty() {
+            return false;
+        }
+        match self.get_common_snapshot() {
+            Some(common) => {
+                let common = self.get_backup_snapshot_name(common);
+                self.backup_snapshots_labeled
+                    .iter()
+                    .any(|snapshot| snapshot.name > common)
+            }
+            None => true,
+        }
+    }
+
+    // Finds the snapshot to adopt a backup from when the backup dataset only
+    // has snapshots with other labels: the newest source snapshot whose GUID
+    // is also on the backup. Both lists are (name, GUID) pairs, oldest first.
+    // The snapshot must be the newest one on the backup (so receiving
+    // doesn't roll anything back) and older than the latest labeled source
+    // snapshot.
+    pub fn get_adoptable_snapshot(
+        &self,
+        source_guids: &[(String, u64)],
+        backup_guids: &[(String, u64)],
+    ) -> Result<String, String> {
+        let latest = self
+            .source_snapshots_labeled
+            .last()
+            .ok_or_else(|| String::from("There are no labeled source snapshots to send."))?;
+
+        let shared = source_guids
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, (_, guid))| backup_guids.iter().any(|(_, other)| other == guid));
+        let (position, (name, guid)) = match shared {
+            Some(shared) => shared,
+            None => {
+                return Err(String::from(
+                    "The backup doesn't share any snapshot with the source.",
+                ))
+            }
+        };
+
+        if backup_guids.last().map(|(_, newest)| newest) != Some(guid) {
+            return Err(format!(
+                "The backup has snapshots newer than {}, the newest snapshot it shares with the source.",
+                name
+            ));
+        }
+
+        let latest_position = source_guids
+            .iter()
+            .position(|(source, _)| *source == latest.name);
+        match latest_position {
+            Some(latest_position) if position < latest_position => Ok(name.clone()),
+            _ => Err(format!(
+                "{} is not older than the latest labeled snapshot {}.",
+                name, latest.name
+            )),
+        }
+    }
+
+    // Gets how far the backup is behind the source: the time between the
+    // common snapshot and the latest source snapshot. None if there is no
+    // common snapshot.
+    pub fn get_replication_lag(&self) -> Option<i64> {
+        let latest = self.source_snapshots_labeled.last()?.get_time()?;
+        let common = Snapshot::new(self.get_common_snapshot()?).get_time()?;
+        Some((latest - common).num_seconds())
+    }
+
+    // Gets the labeled backup snapshots that fall outside of the newest
+    // 'keep' snapshots once the latest source snapshot has been replicated.
+    // The latest snapshot itself is never returned.
+    pub fn get_prunable_backup_snapshots(&self, keep: usize) -> Vec<Snapshot> {
+        let mut snapshots = self.backup_snapshots_labeled.clone();
+        if let Some(latest) = self.source_snapshots_labeled.last() {
+            let latest = Snapshot::new(&self.get_backup_snapshot_name(&latest.name));
+            if !snapshots.contains(&latest) {
+                snapshots.push(latest);
+            }
+        }
+        snapshots.sort_unstable();
+
+        let count = snapshots.len().saturating_sub(keep.max(1));
+        snapshots.truncate(count);
+        snapshots
+    }
+
+    // Converts a source snapshot name into the name it has in the backup.
+    fn get_backup_snapshot_name(&self, source_snapshot: &str) -> String {
+        let suffix = source_snapshot
+            .split_once('@')
+            .map_or("", |(_, suffix)| suffix);
+        format!("{}@{}", self.backup_dataset_name, suffix)
+    }
+
     fn get_snapshots(
-        snapshots: &Vec<Snapshot>,
+        snapshots: &[Snapshot],
         dataset_name: &str,
         label: &str,
         use_label: bool,
@@ -131,6 +299,7 @@ impl Cantaloupe {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::traits::SnapshotGuids;
 
     fn get_example_snapshots() -> Vec<Snapshot> {
         vec![
@@ -167,7 +336,7 @@ mod tests {
         assert_eq!(snapshots.len(), 3);
 
         for expected_snapshot in expected_snapshots {
-            assert!(snapshots.contains(&&expected_snapshot));
+            assert!(snapshots.contains(&expected_snapshot));
         }
     }
 
@@ -184,7 +353,7 @@ mod tests {
         assert_eq!(snapshots.len(), 2);
 
         for expected_snapshot in expected_snapshots {
-            assert!(snapshots.contains(&&expected_snapshot));
+            assert!(snapshots.contains(&expected_snapshot));
         }
     }
 
@@ -202,7 +371,7 @@ mod tests {
         assert_eq!(snapshots.len(), 3);
 
         for expected_snapshot in expected_snapshots {
-            assert!(snapshots.contains(&&expected_snapshot));
+            assert!(snapshots.contains(&expected_snapshot));
         }
     }
 
@@ -230,6 +399,151 @@ mod tests {
         assert!(common_snapshot.is_none());
     }
 
+    #[test]
+    fn test_get_common_snapshot_should_use_explicit_backup_dataset() {
+        let snapshots = vec![
+            Snapshot::new("tank/os/main@2021-06-03-1800-00-TEST"),
+            Snapshot::new("tank/os/main@2022-10-05-1953-12-TEST"),
+            Snapshot::new("backup/os/main@2021-06-03-1800-00-TEST"),
+        ];
+        let program = Cantaloupe::new_with_backup_dataset(
+            &snapshots,
+            "backup",
+            "backup/os/main",
+            "tank/os/main",
+            "TEST",
+        );
+
+        let common_snapshot = program.get_common_snapshot();
+
+        assert_eq!(
+            common_snapshot.unwrap(),
+            "tank/os/main@2021-06-03-1800-00-TEST"
+        );
+        assert_eq!(program.get_backup_snapshots_labeled().len(), 1);
+    }
+
+    #[test]
+    fn test_get_replication_lag_should_return_time_behind_source() {
+        let program = Cantaloupe::new(&get_example_snapshots(), "backup", "tank/var/log", "TEST");
+        let no_common = Cantaloupe::new(
+            &get_example_snapshots_no_common_snapshots(),
+            "backup",
+            "tank/var/log",
+            "TEST",
+        );
+
+        let lag = program.get_replication_lag();
+
+        assert_eq!(lag, Some(42256392));
+        assert_eq!(no_common.get_replication_lag(), None);
+    }
+
+    #[test]
+    fn test_get_snapshots_behind_should_count_unreplicated_snapshots() {
+        let program = Cantaloupe::new(&get_example_snapshots(), "backup", "tank/var/log", "TEST");
+        let no_common = Cantaloupe::new(
+            &get_example_snapshots_no_common_snapshots(),
+            "backup",
+            "tank/var/log",
+            "TEST",
+        );
+
+        let behind = program.get_snapshots_behind();
+
+        assert_eq!(behind, 1);
+        assert_eq!(
+            no_common.get_snapshots_behind(),
+            no_common.get_source_snapshots_labeled().len()
+        );
+    }
+
+    #[test]
+    fn test_has_diverged_should_detect_foreign_backup_snapshots() {
+        let mut snapshots = get_example_snapshots();
+        let program = Cantaloupe::new(&snapshots, "backup", "tank/var/log", "TEST");
+        snapshots.push(Snapshot::new("backup/tank/var/log@2022-01-01-0000-00-TEST"));
+        let diverged = Cantaloupe::new(&snapshots, "backup", "tank/var/log", "TEST");
+
+        assert!(!program.has_diverged());
+        assert!(diverged.has_diverged());
+        assert_eq!(
+            diverged.get_latest_backup_snapshot().unwrap().name,
+            "backup/tank/var/log@2022-01-01-0000-00-TEST"
+        );
+    }
+
+    fn get_adoption_program() -> Cantaloupe {
+        let snapshots = vec![
+            Snapshot::new("tank/var/log@2021-06-03-1800-00-TEST"),
+            Snapshot::new("tank/var/log@2022-10-05-1953-12-TEST"),
+            Snapshot::new("backup/tank/var/log@2021-07-23-0548-19-LOL"),
+        ];
+        Cantaloupe::new(&snapshots, "backup", "tank/var/log", "TEST")
+    }
+
+    fn get_adoption_guids() -> (SnapshotGuids, SnapshotGuids) {
+        let source = vec![
+            (String::from("tank/var/log@autosnap_2021-01-01"), 1),
+            (String::from("tank/var/log@2021-06-03-1800-00-TEST"), 2),
+            (String::from("tank/var/log@autosnap_2022-01-01"), 3),
+            (String::from("tank/var/log@2022-10-05-1953-12-TEST"), 4),
+        ];
+        let backup = vec![
+            (String::from("backup/tank/var/log@autosnap_2021-01-01"), 1),
+            (String::from("backup/tank/var/log@autosnap_2022-01-01"), 3),
+        ];
+        (source, backup)
+    }
+
+    #[test]
+    fn test_get_adoptable_snapshot_should_match_by_guid() {
+        let program = get_adoption_program();
+        let (source, backup) = get_adoption_guids();
+
+        let adoptable = program.get_adoptable_snapshot(&source, &backup);
+
+        assert_eq!(
+            adoptable,
+            Ok(String::from("tank/var/log@autosnap_2022-01-01"))
+        );
+    }
+
+    #[test]
+    fn test_get_adoptable_snapshot_should_not_roll_back_backup() {
+        let program = get_adoption_program();
+        let (source, mut backup) = get_adoption_guids();
+        backup.push((String::from("backup/tank/var/log@manual"), 99));
+
+        let adoptable = program.get_adoptable_snapshot(&source, &backup);
+        let unrelated = program.get_adoptable_snapshot(&source, &backup[2..]);
+
+        assert!(adoptable.unwrap_err().contains("newer than"));
+        assert!(unrelated.unwrap_err().contains("doesn't share"));
+    }
+
+    #[test]
+    fn test_get_prunable_backup_snapshots_should_keep_newest() {
+        let program = Cantaloupe::new(&get_example_snapshots(), "backup", "tank/var/log", "TEST");
+        let expected_snapshots = vec![
+            Snapshot::new("backup/tank/var/log@2020-05-13-0013-23-TEST"),
+            Snapshot::new("backup/tank/var/log@2021-06-03-1800-00-TEST"),
+        ];
+
+        let snapshots = program.get_prunable_backup_snapshots(1);
+
+        assert_eq!(snapshots, expected_snapshots);
+    }
+
+    #[test]
+    fn test_get_prunable_backup_snapshots_should_return_nothing_when_under_limit() {
+        let program = Cantaloupe::new(&get_example_snapshots(), "backup", "tank/var/log", "TEST");
+
+        let snapshots = program.get_prunable_backup_snapshots(3);
+
+        assert!(snapshots.is_empty());
+    }
+
     #[test]
     fn test_get_latest_source_snapshot_name_should_get_latest() {
         let snapshots = vec![
@@ -23,128 +23,640 @@
 // OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
 // SUCH DAMAGE.
 
+use std::fs;
+use std::net::TcpListener;
+use std::path::Path;
+
+use chrono::Local;
 use clap::Parser;
 
-use cantaloupe::helpers;
+use cantaloupe::bandwidth::BandwidthLimit;
+use cantaloupe::catalog::{self, CatalogReport};
+use cantaloupe::check::{self, CheckState, Thresholds};
+use cantaloupe::config::{Config, Job, SnapshotOptions};
+use cantaloupe::daemon::{self, Daemon};
+use cantaloupe::helpers::{
+    self, ApplyArgs, Args, CheckArgs, Commands, PlanArgs, SnapshotArgs, StatusArgs,
+};
+use cantaloupe::interrupt;
+use cantaloupe::metrics;
+use cantaloupe::notify;
+use cantaloupe::plan::{self, Action, SavedPlan};
+use cantaloupe::pools::{ImportOptions, ScrubOptions};
 use cantaloupe::providers::system::System;
+use cantaloupe::report::{JobsReport, OutputFormat, RunReport, SnapshotReport, SCHEMA_VERSION};
+use cantaloupe::retry::{ErrorClass, RetryPolicy};
+use cantaloupe::runner;
+use cantaloupe::script::Script;
+use cantaloupe::state::JobState;
+use cantaloupe::status::{self, StatusReport};
 use cantaloupe::traits::SystemProvider;
-use cantaloupe::Cantaloupe;
+
+// A job along with its name in the configuration file, if it came from one.
+type NamedJob = (Option<String>, Job);
 
 fn main() {
-    let args = helpers::Args::parse();
+    let args = Args::parse();
     let system = System::new();
 
-    helpers::print_header();
-
-    let backup_pool = &args.backup_pool;
-    let label = &args.label;
+    // Monitoring plugins must only print their own output.
+    if let Some(Commands::Check(check)) = &args.command {
+        std::process::exit(run_check(&system, &args, check));
+    }
 
-    // Check if the backup pool is imported.
-    system.check_pool_imported_or_exit(&system, &backup_pool);
+    // A script written to stdout must be the only output.
+    let script_to_stdout = args.emit_script.as_deref() == Some(Path::new("-"));
+    if args.output == OutputFormat::Text && !script_to_stdout {
+        helpers::print_header();
+    }
 
-    // Check if all of the source pools are imported.
-    for source_pool in helpers::get_source_pool_names(&args.datasets) {
-        if source_pool == backup_pool {
-            println!("All source datasets must live outside of the backup pool. Aborting.");
+    // Runs that replicate stop cleanly when interrupted. Everything else can
+    // simply be killed.
+    let replicates = args.emit_script.is_none()
+        && matches!(
+            &args.command,
+            Some(Commands::Run(_) | Commands::Apply(_) | Commands::Daemon) | None
+        );
+    if replicates {
+        if let Err(error) = interrupt::install() {
+            eprintln!("{}", error);
             std::process::exit(1);
         }
-        system.check_pool_imported_or_exit(&system, &source_pool);
     }
 
-    let snapshots = system.get_all_snapshots();
+    let success = match &args.command {
+        _ if args.emit_script.is_some() => run_emit_script(&system, &args),
+        Some(Commands::Plan(plan)) => run_plan(&system, &args, plan),
+        Some(Commands::Apply(apply)) => run_apply(&system, &args, apply),
+        Some(Commands::Daemon) => run_daemon(&args),
+        Some(Commands::Snapshot(snapshot)) => run_snapshot(&system, &args, snapshot),
+        Some(Commands::Status(status)) => run_status(&system, &args, status),
+        Some(Commands::Catalog) => run_catalog(&args),
+        Some(Commands::Check(_)) => unreachable!(),
+        Some(Commands::Run(_)) | None => run_jobs(&system, &args),
+    };
 
-    println!("Backup Pool: {}", backup_pool);
-    println!("Label: {}", label);
-    println!("Total Snapshots Count: {}", snapshots.len());
+    // The daemon stops cleanly on SIGTERM, like any other service.
+    let daemon = matches!(&args.command, Some(Commands::Daemon));
+    if interrupt::is_interrupted() && !daemon {
+        std::process::exit(interrupt::EXIT_CODE);
+    }
+    if !success {
+        std::process::exit(1);
+    }
+}
 
-    for source_dataset in &args.datasets {
-        println!("\n---------------");
-        println!("{}", source_dataset);
-        println!("---------------\n");
+fn run_jobs(system: &impl SystemProvider, args: &Args) -> bool {
+    let (config, jobs) = match load_jobs(args) {
+        Ok(result) => result,
+        Err(error) => {
+            eprintln!("{}", error);
+            return false;
+        }
+    };
 
-        let program = Cantaloupe::new(&snapshots, &backup_pool, &source_dataset, &label);
-        let source_snapshots = program.get_source_snapshots_labeled();
-        let backup_snapshots = program.get_backup_snapshots_labeled();
+    let mut reports = Vec::new();
+    for (name, job) in &jobs {
+        if interrupt::is_interrupted() {
+            break;
+        }
+        let report = runner::run_job(
+            system,
+            args.output,
+            args.dry_run,
+            name.as_deref(),
+            job,
+            config.as_ref().map(|config| config.state_dir.as_path()),
+        );
+        if let (Some(config), Some(name)) = (&config, name) {
+            record_result(config, name, &report);
+        }
+        reports.push(report);
+    }
+
+    let success = reports.iter().all(|report| report.success);
+    // Running all of the jobs prints a single document with every report.
+    match (&args.command, args.output) {
+        (Some(Commands::Run(run)), OutputFormat::Json) if run.all => println!(
+            "{}",
+            serde_json::to_string_pretty(&JobsReport::new(reports)).unwrap()
+        ),
+        _ => {
+            for report in &reports {
+                print_report(args.output, report);
+            }
+        }
+    }
+    success
+}
 
-        println!("Source Snapshots Count: {}", source_snapshots.len());
-        println!("Backup Snapshots Count: {}", backup_snapshots.len());
+// Prints the report of a run in JSON mode. The other formats print as the
+// run goes.
+fn print_report(output: OutputFormat, report: &RunReport) {
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(report).unwrap());
+    }
+}
+
+// Loads the configuration file when running jobs from it, along with the jobs
+// to run.
+fn load_jobs(args: &Args) -> Result<(Option<Config>, Vec<NamedJob>), String> {
+    let config = match &args.command {
+        Some(Commands::Run(_)) => Some(
+            get_config_path(args).and_then(|path| Config::load(path).map_err(|e| e.to_string()))?,
+        ),
+        _ => None,
+    };
+    let jobs = get_jobs(args, config.as_ref())?;
+    Ok((config, jobs))
+}
 
-        if source_snapshots.len() == 0 {
-            println!("No source snapshots available with the given dataset and label. Skipping.");
-            continue;
+// Gets the jobs to run, either from the configuration file (merged with any
+// overrides given on the command line) or directly from the command line.
+fn get_jobs(args: &Args, config: Option<&Config>) -> Result<Vec<NamedJob>, String> {
+    let (run, config) = match (&args.command, config) {
+        (Some(Commands::Run(run)), Some(config)) => (run, config),
+        _ => {
+            let backup_pools =
+                helpers::get_backup_pools(args.backup_pool.as_deref().unwrap_or_default());
+            let mut job = Job::new(
+                backup_pools.first().map(String::as_str).unwrap_or_default(),
+                args.label.as_deref().unwrap_or_default(),
+                &args.datasets,
+            );
+            job.set_backup_pools(backup_pools)?;
+            apply_overrides(args, &mut job);
+            return Ok(vec![(None, job)]);
         }
+    };
 
-        let latest_snapshot = program.get_latest_source_snapshot_name();
-        let backup_dataset = helpers::get_backup_dataset(&backup_pool, &source_dataset);
+    let mut jobs: Vec<NamedJob> = match &run.job {
+        Some(name) => match config.jobs.get(name) {
+            Some(job) => vec![(Some(name.clone()), job.clone())],
+            None => {
+                return Err(format!(
+                    "No job named '{}' in {}.",
+                    name,
+                    get_config_path(args)?.display()
+                ))
+            }
+        },
+        None => config
+            .jobs
+            .iter()
+            .map(|(name, job)| (Some(name.clone()), job.clone()))
+            .collect(),
+    };
 
-        println!("Latest Snapshot: {}", latest_snapshot);
+    for (name, job) in &mut jobs {
+        if !run.backup_pool.is_empty() {
+            job.set_backup_pools(helpers::get_backup_pools(&run.backup_pool.join(",")))
+                .map_err(|e| format!("Job '{}': {}", name.as_deref().unwrap_or_default(), e))?;
+        }
+        if let Some(label) = &run.label {
+            job.label = label.clone();
+        }
+        apply_overrides(args, job);
+    }
+
+    Ok(jobs)
+}
+
+fn apply_overrides(args: &Args, job: &mut Job) {
+    job.adopt |= args.adopt;
+    job.tee |= args.tee;
+    if args.import || args.altroot.is_some() {
+        job.import.get_or_insert_with(ImportOptions::default);
+    }
+    if let (Some(import), Some(altroot)) = (&mut job.import, &args.altroot) {
+        import.altroot = Some(altroot.clone());
+    }
+    if args.scrub {
+        job.scrub.get_or_insert_with(ScrubOptions::default);
+    }
+    if let Some(rate) = args.bwlimit {
+        job.bandwidth.global = Some(BandwidthLimit {
+            rate: Some(rate),
+            schedule: Vec::new(),
+        });
+    }
+    if let Some(timeout) = args.timeout {
+        job.timeouts.dataset = Some(timeout);
+    }
+    if let Some(stall_timeout) = args.stall_timeout {
+        job.timeouts.stall = Some(stall_timeout);
+    }
+    if let Some(retries) = args.retries {
+        let policy = RetryPolicy {
+            retries,
+            ..RetryPolicy::default()
+        };
+        job.retry = [
+            ErrorClass::Busy,
+            ErrorClass::Connection,
+            ErrorClass::Stalled,
+        ]
+        .into_iter()
+        .map(|class| (class, policy))
+        .collect();
+    }
+    if let Some(jobs) = args.jobs {
+        job.parallelism.datasets = usize::from(jobs);
+    }
+    if let Some(jobs_per_pool) = args.jobs_per_pool {
+        job.parallelism.per_pool = usize::from(jobs_per_pool);
+    }
+    if args.snapshot {
+        job.snapshot.get_or_insert_with(SnapshotOptions::default);
+    }
+    if let Some(snapshot) = &mut job.snapshot {
+        snapshot.recursive |= args.recursive;
+        snapshot.atomic |= args.atomic;
+    }
+}
 
-        if let Some(common_snapshot) = program.get_common_snapshot() {
-            println!("Common Snapshot: {}", common_snapshot);
+fn get_config_path(args: &Args) -> Result<&Path, String> {
+    args.config.as_deref().ok_or_else(|| {
+        String::from("A configuration file must be given with --config to run jobs.")
+    })
+}
+
+// Plans a job and saves the plan so that it can be reviewed and applied
+// later.
+fn run_plan(system: &impl SystemProvider, args: &Args, plan: &PlanArgs) -> bool {
+    let result = get_config_path(args)
+        .and_then(|path| {
+            let config = Config::load(path).map_err(|e| e.to_string())?;
+            config
+                .jobs
+                .get(&plan.job)
+                .cloned()
+                .ok_or_else(|| format!("No job named '{}' in {}.", plan.job, path.display()))
+        })
+        .and_then(|mut job| {
+            apply_overrides(args, &mut job);
+            runner::create_plan(system, &plan.job, &job, Local::now(), &|warning| {
+                eprintln!("{}", warning)
+            })
+        })
+        .and_then(|saved| saved.save(&plan.out).map(|_| saved));
 
-            // If we are up to date, continue.
-            if common_snapshot == latest_snapshot {
-                println!("You are already up to date!");
-                continue;
+    let saved = match result {
+        Ok(saved) => saved,
+        Err(error) => {
+            eprintln!("{}", error);
+            return false;
+        }
+    };
+    match args.output {
+        OutputFormat::Text => {
+            plan::print_text(&saved.plan);
+            println!("Saved the plan to {}.", plan.out.display());
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&saved).unwrap()),
+        OutputFormat::Jsonl => println!("{}", serde_json::to_string(&saved).unwrap()),
+    }
+    true
+}
+
+// Applies a saved plan to the job it was made for.
+fn run_apply(system: &impl SystemProvider, args: &Args, apply: &ApplyArgs) -> bool {
+    let (config, saved, job) = match load_saved_plan(args, apply) {
+        Ok(result) => result,
+        Err(error) => {
+            eprintln!("{}", error);
+            return false;
+        }
+    };
+
+    let report = runner::apply_plan(
+        system,
+        args.output,
+        args.dry_run,
+        &job,
+        &saved,
+        Some(&config.state_dir),
+    );
+    record_result(&config, &saved.job, &report);
+    print_report(args.output, &report);
+    report.success
+}
+
+// Loads a saved plan with the job it was made for, which the plan decides
+// the backup pools and label of.
+fn load_saved_plan(args: &Args, apply: &ApplyArgs) -> Result<(Config, SavedPlan, Job), String> {
+    let path = get_config_path(args)?;
+    let config = Config::load(path).map_err(|e| e.to_string())?;
+    let saved = SavedPlan::load(&apply.plan)?;
+    let mut job = match config.jobs.get(&saved.job).cloned() {
+        Some(job) => job,
+        None => {
+            return Err(format!(
+                "No job named '{}' in {}.",
+                saved.job,
+                path.display()
+            ))
+        }
+    };
+    job.backup_pools = saved.plan.backup_pools.clone();
+    job.label = saved.plan.label.clone();
+    apply_overrides(args, &mut job);
+    Ok((config, saved, job))
+}
+
+// Writes the commands that running the jobs (or applying a saved plan) would
+// run to a shell script instead of running them.
+fn run_emit_script(system: &impl SystemProvider, args: &Args) -> bool {
+    let path = args.emit_script.as_deref().unwrap_or(Path::new("-"));
+    let mut script = Script::new(&Local::now().to_rfc3339());
+
+    let warn = |warning: &str| eprintln!("{}", warning);
+    let plans = match &args.command {
+        Some(Commands::Apply(apply)) => load_saved_plan(args, apply).and_then(|(_, saved, job)| {
+            runner::check_job_pools(system, &job, &warn)?;
+            let drifts = saved.verify(system);
+            if drifts.is_empty() {
+                Ok(vec![(
+                    Some(saved.job),
+                    saved.send_flags,
+                    None,
+                    saved.plan,
+                    saved.pool_guids,
+                )])
+            } else {
+                Err(format!(
+                    "{}\nThe plan no longer matches the pools and datasets. Refusing to write it.",
+                    drifts.join("\n")
+                ))
             }
+        }),
+        Some(Commands::Run(_)) | None => load_jobs(args).and_then(|(_, jobs)| {
+            let now = Local::now().naive_local();
+            jobs.into_iter()
+                .map(|(name, job)| {
+                    // The snapshots are named after the time the script is
+                    // written, and planned as if they already existed.
+                    let snapshot = job.snapshot.map(|options| {
+                        let names = runner::get_snapshot_names(&job.datasets, &job.label, &now);
+                        (options, names)
+                    });
+                    let new_snapshots = snapshot.as_ref().map_or(&[][..], |(_, names)| names);
+                    runner::get_plan(system, &job, new_snapshots, &warn).map(
+                        |(plan, pool_guids)| {
+                            let pool_guids = pool_guids
+                                .into_iter()
+                                .map(|(pool, guid)| (pool, guid.to_string()))
+                                .collect();
+                            (name, job.send_flags, snapshot, plan, pool_guids)
+                        },
+                    )
+                })
+                .collect()
+        }),
+        _ => Err(String::from(
+            "--emit-script can only be used to run jobs or to apply a plan.",
+        )),
+    };
+    let plans = match plans {
+        Ok(plans) => plans,
+        Err(error) => {
+            eprintln!("{}", error);
+            return false;
+        }
+    };
 
-            // Send incremental snapshot.
-            println!(
-                "Sending incremental backup for {} -> {} ...",
-                common_snapshot, latest_snapshot
-            );
+    let mut success = true;
+    for (name, send_flags, snapshot, plan, pool_guids) in &plans {
+        let snapshot = snapshot
+            .as_ref()
+            .map(|(options, names)| (options, names.as_slice()));
+        script.add_plan(name.as_deref(), send_flags, snapshot, plan, pool_guids);
+        success &= !plan.get_targets().any(|target| {
+            target
+                .actions
+                .iter()
+                .any(|action| matches!(action, Action::Fail { .. }))
+        });
+    }
+
+    if path == Path::new("-") {
+        print!("{}", script.render());
+        return success;
+    }
+    if let Err(error) = fs::write(path, script.render()) {
+        eprintln!("Failed to write {}: {}", path.display(), error);
+        return false;
+    }
+    if args.output == OutputFormat::Text {
+        for (_, _, _, plan, _) in &plans {
+            plan::print_text(plan);
+        }
+        println!("Wrote the script to {}.", path.display());
+    }
+    success
+}
 
-            if !args.dry_run {
-                if system.send_incremental_backup(
-                    &common_snapshot,
-                    &latest_snapshot,
-                    &backup_dataset,
-                ) {
-                    println!("Incremental backup finished successfully!");
-                } else {
-                    println!("An error occurred while sending the incremental backup.");
-                }
+// Runs the scheduled jobs until the daemon is stopped by SIGINT or SIGTERM.
+fn run_daemon(args: &Args) -> bool {
+    let result = get_config_path(args)
+        .and_then(|path| Daemon::new(path).map_err(|e| e.to_string()))
+        .and_then(|daemon| {
+            let config = daemon.get_config();
+            if let Some(address) = config.metrics.listen {
+                let listener = TcpListener::bind(address)
+                    .map_err(|e| format!("Failed to listen on {}: {}", address, e))?;
+                metrics::serve(listener, config.state_dir.clone());
+                daemon::log(&format!("Serving metrics on http://{}/metrics.", address));
             }
-            continue;
+
+            let output = args.output;
+            let dry_run = args.dry_run;
+            daemon.run(move |name, job, config| {
+                let report = runner::run_job(
+                    &System::new(),
+                    output,
+                    dry_run,
+                    Some(name),
+                    job,
+                    Some(&config.state_dir),
+                );
+                record_result(config, name, &report);
+                print_report(output, &report);
+                report.success
+            })
+        });
+
+    match result {
+        Ok(()) => true,
+        Err(error) => {
+            eprintln!("{}", error);
+            false
         }
+    }
+}
+
+// Sends the notifications of the configuration file, remembers the result
+// of the run (so that state changes can be detected next time) and updates
+// the metrics. Nothing is sent or saved for dry runs.
+fn record_result(config: &Config, name: &str, report: &RunReport) {
+    if report.config.dry_run {
+        return;
+    }
 
-        println!("No common snapshot found.");
+    let previous = JobState::load(&config.state_dir, name);
+    let previous_success = previous.as_ref().map(|state| state.success);
+    for error in notify::notify(&config.notifications, name, report, previous_success) {
+        eprintln!("{}", error);
+    }
 
-        // Make sure we don't already have snapshots under this dataset since
-        // we are writing to the entire dataset and want to have labeled and
-        // direct incremental writes afterwards.
-        let backup_snapshots = program.get_backup_snapshots();
+    let state = JobState::new(report, previous.as_ref(), Local::now());
+    if let Err(error) = state.save(&config.state_dir, name) {
+        eprintln!("Failed to save the state of job '{}': {}", name, error);
+    }
 
-        if backup_snapshots.len() != 0 {
-            println!("Backup pool already contains ({}) snapshots for this dataset under a different label. Will not do a full send. Skipping.", backup_snapshots.len());
-            continue;
+    if let Some(textfile) = &config.metrics.textfile {
+        if let Err(error) = metrics::write_textfile(textfile, &config.state_dir, name) {
+            eprintln!("{}", error);
         }
+    }
+}
 
-        // Create the dataset hierarchy if needed. The target backup dataset needs
-        // to exist before we attempt to send into it.
-        println!(
-            "Creating backup dataset hierarchy for {} (if needed) ...",
-            backup_dataset
-        );
+// Checks the age of the newest backup of each dataset like a monitoring
+// plugin would. Returns the plugin exit code.
+fn run_check(system: &impl SystemProvider, args: &Args, check: &CheckArgs) -> i32 {
+    let unknown = |message: &str| {
+        println!("CANTALOUPE {} - {}", CheckState::Unknown.as_str(), message);
+        CheckState::Unknown.get_exit_code()
+    };
+
+    if check.warning > check.critical {
+        return unknown("the warning threshold must not be above the critical one");
+    }
+    let config = match get_config_path(args)
+        .and_then(|path| Config::load(path).map_err(|e| e.to_string()))
+    {
+        Ok(config) => config,
+        Err(error) => return unknown(&error),
+    };
+    let jobs: Vec<&Job> = match &check.job {
+        Some(name) => match config.jobs.get(name) {
+            Some(job) => vec![job],
+            None => return unknown(&format!("no job named '{}'", name)),
+        },
+        None => config.jobs.values().collect(),
+    };
+
+    let thresholds = Thresholds {
+        warning: check.warning,
+        critical: check.critical,
+    };
+    let now = Local::now().naive_local();
+    let checks: Vec<_> = jobs
+        .into_iter()
+        .flat_map(|job| check::check_job(system, job, &thresholds, now))
+        .collect();
+
+    let (state, output) = check::get_output(&checks, &thresholds);
+    println!("{}", output);
+    state.get_exit_code()
+}
+
+// Shows the replication state of each dataset of the configured jobs.
+fn run_status(system: &impl SystemProvider, args: &Args, status: &StatusArgs) -> bool {
+    let path = match get_config_path(args) {
+        Ok(path) => path,
+        Err(error) => {
+            eprintln!("{}", error);
+            return false;
+        }
+    };
+    let config = match Config::load(path) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            return false;
+        }
+    };
 
-        if !args.dry_run {
-            if !system.create_dataset_tree_if_needed(&backup_dataset) {
-                println!("Failed to create backup dataset hierarchy. Perhaps your user doesn't have enough permissions for the 'zfs' command?");
-                continue;
+    let jobs: Vec<_> = match &status.job {
+        Some(name) => match config.jobs.get_key_value(name) {
+            Some(job) => vec![job],
+            None => {
+                eprintln!("No job named '{}' in {}.", name, path.display());
+                return false;
             }
+        },
+        None => config.jobs.iter().collect(),
+    };
+
+    let now = Local::now().naive_local();
+    let report = StatusReport::new(
+        jobs.into_iter()
+            .flat_map(|(name, job)| status::get_job_statuses(system, name, job, now))
+            .collect(),
+    );
+    match args.output {
+        OutputFormat::Text => status::print_text(&report),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+        OutputFormat::Jsonl => println!("{}", serde_json::to_string(&report).unwrap()),
+    }
+    true
+}
+
+// Shows what was last seen on each backup disk.
+fn run_catalog(args: &Args) -> bool {
+    let config = match get_config_path(args)
+        .and_then(|path| Config::load(path).map_err(|e| e.to_string()))
+    {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            return false;
         }
+    };
 
-        // Doing full send.
-        println!("Sending full backup for {} ...", latest_snapshot);
+    let report = CatalogReport::new(catalog::load_all(&config.state_dir));
+    match args.output {
+        OutputFormat::Text => catalog::print_text(&report),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+        OutputFormat::Jsonl => println!("{}", serde_json::to_string(&report).unwrap()),
+    }
+    true
+}
 
-        if !args.dry_run {
-            if system.send_full_backup(&latest_snapshot, &backup_dataset) {
-                println!("Full backup finished successfully!");
-            } else {
-                println!("An error occurred while sending the full backup.");
-            }
+// Only takes snapshots, without replicating them.
+fn run_snapshot(system: &impl SystemProvider, args: &Args, snapshot: &SnapshotArgs) -> bool {
+    let options = SnapshotOptions {
+        recursive: args.recursive,
+        atomic: args.atomic,
+    };
+    let text = |message: &str| {
+        if args.output == OutputFormat::Text {
+            println!("{}", message);
         }
-        continue;
+    };
+
+    let (created_snapshots, errors) = runner::take_snapshots(
+        system,
+        &text,
+        &snapshot.datasets,
+        &snapshot.label,
+        &options,
+        args.dry_run,
+    );
+
+    let report = SnapshotReport {
+        schema_version: SCHEMA_VERSION,
+        version: String::from(clap::crate_version!()),
+        label: snapshot.label.clone(),
+        success: errors.is_empty(),
+        created_snapshots,
+        errors,
+    };
+    match args.output {
+        OutputFormat::Text => println!(),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+        OutputFormat::Jsonl => println!("{}", serde_json::to_string(&report).unwrap()),
     }
-    println!("");
+    report.success
 }
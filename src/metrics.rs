@@ -0,0 +1,308 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use chrono::DateTime;
+
+use crate::state::JobState;
+
+// A metric with all of its samples, in the Prometheus text format.
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    samples: Vec<(String, String)>,
+}
+
+impl Family {
+    fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            samples: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, labels: &str, value: impl ToString) {
+        self.samples.push((String::from(labels), value.to_string()));
+    }
+}
+
+// Renders the state of every job as Prometheus metrics.
+pub fn render(states: &BTreeMap<String, JobState>) -> String {
+    let mut job_success = Family::new(
+        "cantaloupe_job_success",
+        "Whether the last run of the job succeeded.",
+    );
+    let mut job_last_run = Family::new(
+        "cantaloupe_job_last_run_timestamp_seconds",
+        "When the last run of the job finished.",
+    );
+    let mut job_duration = Family::new(
+        "cantaloupe_job_duration_seconds",
+        "How long the last run of the job took.",
+    );
+    let mut job_bytes = Family::new(
+        "cantaloupe_job_bytes_sent",
+        "Bytes sent to the backup pool by the last run of the job.",
+    );
+    let mut last_success = Family::new(
+        "cantaloupe_dataset_last_success_timestamp_seconds",
+        "When the backup of the dataset was last brought up to date.",
+    );
+    let mut lag = Family::new(
+        "cantaloupe_dataset_replication_lag_seconds",
+        "Time between the latest source snapshot and the latest snapshot on the backup.",
+    );
+    let mut dataset_bytes = Family::new(
+        "cantaloupe_dataset_bytes_sent",
+        "Bytes sent for the dataset by the last run.",
+    );
+    let mut dataset_duration = Family::new(
+        "cantaloupe_dataset_duration_seconds",
+        "How long the dataset took during the last run.",
+    );
+    let mut source_snapshots = Family::new(
+        "cantaloupe_dataset_source_snapshots",
+        "Number of source snapshots with the job's label.",
+    );
+    let mut backup_snapshots = Family::new(
+        "cantaloupe_dataset_backup_snapshots",
+        "Number of backup snapshots with the job's label.",
+    );
+
+    for (job, state) in states {
+        let labels = format!(
+            "job=\"{}\",backup_pool=\"{}\",label=\"{}\"",
+            escape(job),
+            escape(&state.backup_pool),
+            escape(&state.label)
+        );
+        job_success.add(&labels, u8::from(state.success));
+        if let Some(timestamp) = get_timestamp(&state.finished_at) {
+            job_last_run.add(&labels, timestamp);
+        }
+        job_duration.add(&labels, get_seconds(state.duration_ms));
+        job_bytes.add(&labels, state.bytes_sent);
+
+        for (name, dataset) in &state.datasets {
+            // State files written before fan-out was supported are keyed by
+            // source dataset and don't name it in the entry.
+            let source = if dataset.dataset.is_empty() {
+                name
+            } else {
+                &dataset.dataset
+            };
+            let labels = format!(
+                "job=\"{}\",dataset=\"{}\",backup_dataset=\"{}\",label=\"{}\"",
+                escape(job),
+                escape(source),
+                escape(&dataset.backup_dataset),
+                escape(&state.label)
+            );
+            if let Some(timestamp) = dataset.last_success.as_deref().and_then(get_timestamp) {
+                last_success.add(&labels, timestamp);
+            }
+            if let Some(seconds) = dataset.replication_lag_seconds {
+                lag.add(&labels, seconds);
+            }
+            dataset_bytes.add(&labels, dataset.bytes_sent);
+            dataset_duration.add(&labels, get_seconds(dataset.duration_ms));
+            source_snapshots.add(&labels, dataset.source_snapshots);
+            backup_snapshots.add(&labels, dataset.backup_snapshots);
+        }
+    }
+
+    let mut output = String::new();
+    for family in [
+        job_success,
+        job_last_run,
+        job_duration,
+        job_bytes,
+        last_success,
+        lag,
+        dataset_bytes,
+        dataset_duration,
+        source_snapshots,
+        backup_snapshots,
+    ] {
+        let _ = writeln!(output, "# HELP {} {}", family.name, family.help);
+        let _ = writeln!(output, "# TYPE {} gauge", family.name);
+        for (labels, value) in &family.samples {
+            let _ = writeln!(output, "{}{{{}}} {}", family.name, labels, value);
+        }
+    }
+    output
+}
+
+// Writes the metrics of every job in the state directory to a
+// node_exporter textfile. The file is replaced atomically so that the
+// exporter never reads a partial file. 'writer' keeps concurrent writers
+// from sharing a temporary file.
+pub fn write_textfile(path: &Path, state_dir: &Path, writer: &str) -> Result<(), String> {
+    let contents = render(&JobState::load_all(state_dir));
+    let temporary = PathBuf::from(format!("{}.{}.tmp", path.display(), writer));
+    fs::write(&temporary, contents)
+        .and_then(|_| fs::rename(&temporary, path))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+// Serves the metrics of every job in the state directory on /metrics from a
+// background thread.
+pub fn serve(listener: TcpListener, state_dir: PathBuf) {
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = handle_request(stream, &state_dir);
+        }
+    });
+}
+
+fn handle_request(mut stream: TcpStream, state_dir: &Path) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = if request.starts_with("GET ") && path == "/metrics" {
+        ("200 OK", render(&JobState::load_all(state_dir)))
+    } else {
+        ("404 Not Found", String::from("Not Found\n"))
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn get_timestamp(value: &str) -> Option<i64> {
+    DateTime::parse_from_rfc3339(value)
+        .ok()
+        .map(|time| time.timestamp())
+}
+
+fn get_seconds(milliseconds: u128) -> f64 {
+    milliseconds as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::state::DatasetState;
+
+    fn get_example_states() -> BTreeMap<String, JobState> {
+        let mut datasets = BTreeMap::new();
+        datasets.insert(
+            String::from("backup/tank/os"),
+            DatasetState {
+                dataset: String::from("tank/os"),
+                backup_dataset: String::from("backup/tank/os"),
+                outcome: String::from("success"),
+                last_success: Some(String::from("2022-09-01T03:00:00+00:00")),
+                replication_lag_seconds: Some(0),
+                bytes_sent: 1024,
+                duration_ms: 1500,
+                source_snapshots: 10,
+                backup_snapshots: 7,
+            },
+        );
+
+        let mut states = BTreeMap::new();
+        states.insert(
+            String::from("nightly"),
+            JobState {
+                success: true,
+                finished_at: String::from("2022-09-01T03:00:00+00:00"),
+                backup_pool: String::from("backup"),
+                label: String::from("CHECKPOINT"),
+                bytes_sent: 1024,
+                duration_ms: 2000,
+                errors: Vec::new(),
+                datasets,
+            },
+        );
+        states
+    }
+
+    #[test]
+    fn test_render_should_export_job_and_dataset_metrics() {
+        let states = get_example_states();
+
+        let metrics = render(&states);
+
+        assert!(metrics.contains("# TYPE cantaloupe_job_success gauge\n"));
+        assert!(metrics.contains(
+            "cantaloupe_job_success{job=\"nightly\",backup_pool=\"backup\",label=\"CHECKPOINT\"} 1\n"
+        ));
+        assert!(metrics.contains("cantaloupe_dataset_last_success_timestamp_seconds{job=\"nightly\",dataset=\"tank/os\",backup_dataset=\"backup/tank/os\",label=\"CHECKPOINT\"} 1662001200\n"));
+        assert!(metrics.contains("cantaloupe_dataset_duration_seconds{job=\"nightly\",dataset=\"tank/os\",backup_dataset=\"backup/tank/os\",label=\"CHECKPOINT\"} 1.5\n"));
+        assert!(metrics.contains("cantaloupe_dataset_backup_snapshots{job=\"nightly\",dataset=\"tank/os\",backup_dataset=\"backup/tank/os\",label=\"CHECKPOINT\"} 7\n"));
+    }
+
+    #[test]
+    fn test_escape_should_escape_label_values() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn test_serve_should_answer_metrics_requests() {
+        let state_dir =
+            std::env::temp_dir().join(format!("cantaloupe-metrics-{}", std::process::id()));
+        for (name, state) in get_example_states() {
+            state.save(&state_dir, &name).unwrap();
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        serve(listener, state_dir.clone());
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let _ = fs::remove_dir_all(&state_dir);
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("cantaloupe_job_bytes_sent{job=\"nightly\""));
+    }
+}
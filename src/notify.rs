@@ -0,0 +1,443 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+use std::fmt::Write;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::helpers;
+use crate::report::{RunReport, SCHEMA_VERSION};
+
+pub const DEFAULT_SENDMAIL: &str = "/usr/sbin/sendmail";
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// When a notification is sent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    #[default]
+    Failure,
+    Success,
+    // Whenever a job starts failing or recovers.
+    Change,
+    Always,
+}
+
+impl Trigger {
+    // The previous result is None if the job never ran before.
+    pub fn matches(&self, success: bool, previous: Option<bool>) -> bool {
+        match self {
+            Trigger::Failure => !success,
+            Trigger::Success => success,
+            // A first run that works isn't news, but one that fails is.
+            Trigger::Change => previous.map_or(!success, |previous| previous != success),
+            Trigger::Always => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Notifier {
+    // Sends a plain text mail through a sendmail compatible binary.
+    Mail {
+        to: Vec<String>,
+        from: Option<String>,
+        sendmail: String,
+    },
+    // POSTs the JSON payload to the given URL.
+    Webhook {
+        url: String,
+        timeout: Duration,
+    },
+    // Runs the command through 'sh -c' with the JSON payload on stdin.
+    Command {
+        command: String,
+        timeout: Option<Duration>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Notification {
+    pub on: Trigger,
+    pub notifier: Notifier,
+}
+
+// What webhooks and commands receive.
+#[derive(Serialize)]
+pub struct Payload<'a> {
+    pub schema_version: u32,
+    pub job: &'a str,
+    pub status: &'static str,
+    pub previous_status: Option<&'static str>,
+    pub summary: String,
+    pub report: &'a RunReport,
+}
+
+pub fn get_status(success: bool) -> &'static str {
+    if success {
+        "success"
+    } else {
+        "failed"
+    }
+}
+
+fn get_verb(success: bool) -> &'static str {
+    if success {
+        "succeeded"
+    } else {
+        "failed"
+    }
+}
+
+// Sends every notification whose trigger matches the result of the run.
+// Returns the errors of the notifications that couldn't be sent.
+pub fn notify(
+    notifications: &[Notification],
+    job: &str,
+    report: &RunReport,
+    previous: Option<bool>,
+) -> Vec<String> {
+    let payload = Payload {
+        schema_version: SCHEMA_VERSION,
+        job,
+        status: get_status(report.success),
+        previous_status: previous.map(get_status),
+        summary: get_summary(job, report),
+        report,
+    };
+
+    notifications
+        .iter()
+        .filter(|notification| notification.on.matches(report.success, previous))
+        .filter_map(|notification| {
+            send(&notification.notifier, &payload)
+                .err()
+                .map(|error| format!("Failed to send notification: {}", error))
+        })
+        .collect()
+}
+
+pub fn send(notifier: &Notifier, payload: &Payload) -> Result<(), String> {
+    match notifier {
+        Notifier::Mail { to, from, sendmail } => {
+            let mut message = format!("To: {}\n", to.join(", "));
+            if let Some(from) = from {
+                let _ = writeln!(message, "From: {}", from);
+            }
+            let _ = write!(
+                message,
+                "Subject: [cantaloupe] Job '{}' {}\nContent-Type: text/plain; charset=utf-8\n\n{}",
+                payload.job,
+                get_verb(payload.report.success),
+                payload.summary
+            );
+
+            let mut command = Command::new(sendmail);
+            command.args(["-t", "-oi"]).stdout(Stdio::null());
+            helpers::run_with_timeout(
+                command,
+                Some(message.into_bytes()),
+                Some(DEFAULT_TIMEOUT),
+                None,
+            )
+            .map_err(|e| format!("{}: {}", sendmail, e))
+        }
+        Notifier::Webhook { url, timeout } => {
+            let agent: ureq::Agent = ureq::Agent::config_builder()
+                .timeout_global(Some(*timeout))
+                .build()
+                .into();
+            agent
+                .post(url)
+                .header("Content-Type", "application/json")
+                .send(serde_json::to_string(payload).unwrap())
+                .map(|_| ())
+                .map_err(|e| format!("{}: {}", url, e))
+        }
+        Notifier::Command { command, timeout } => {
+            let mut process = Command::new("sh");
+            process
+                .arg("-c")
+                .arg(command)
+                .env("CANTALOUPE_JOB", payload.job)
+                .env("CANTALOUPE_STATUS", payload.status)
+                .stdout(std::io::stderr());
+            let input = serde_json::to_vec(payload).unwrap();
+            helpers::run_with_timeout(process, Some(input), *timeout, None)
+                .map_err(|e| format!("'{}' {}", command, e))
+        }
+    }
+}
+
+// A human readable summary of the run, with one line per dataset.
+pub fn get_summary(job: &str, report: &RunReport) -> String {
+    let mut summary = format!("Job '{}' {}.\n\n", job, get_verb(report.success));
+    match report.config.backup_pools.as_slice() {
+        [backup_pool] => {
+            let _ = writeln!(summary, "Backup Pool: {}", backup_pool);
+        }
+        backup_pools => {
+            let _ = writeln!(summary, "Backup Pools: {}", backup_pools.join(", "));
+        }
+    }
+    let _ = writeln!(summary, "Label: {}", report.config.label);
+    let _ = writeln!(summary, "Bytes Sent: {}", report.bytes_sent);
+    let _ = writeln!(summary, "Duration: {}s", report.duration_ms / 1000);
+
+    if !report.datasets.is_empty() {
+        summary.push('\n');
+    }
+    for dataset in &report.datasets {
+        let _ = write!(
+            summary,
+            "{} -> {}: {}",
+            dataset.dataset,
+            dataset.backup_dataset,
+            dataset.outcome.as_str()
+        );
+        match &dataset.reason {
+            Some(reason) => {
+                let _ = writeln!(summary, " ({})", reason);
+            }
+            None => summary.push('\n'),
+        }
+    }
+
+    if !report.scrubs.is_empty() {
+        summary.push('\n');
+    }
+    for scrub in &report.scrubs {
+        let _ = writeln!(
+            summary,
+            "Scrub of {}: {} bytes repaired, {} errors",
+            scrub.pool, scrub.repaired_bytes, scrub.errors
+        );
+    }
+
+    if !report.errors.is_empty() {
+        summary.push_str("\nErrors:\n");
+        for error in &report.errors {
+            let _ = writeln!(summary, "- {}", error);
+        }
+    }
+    if !report.warnings.is_empty() {
+        summary.push_str("\nWarnings:\n");
+        for warning in &report.warnings {
+            let _ = writeln!(summary, "- {}", warning);
+        }
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::thread;
+
+    use super::*;
+    use crate::report::{DatasetReport, Outcome, RunConfig};
+
+    fn get_example_report(success: bool) -> RunReport {
+        let mut dataset = DatasetReport::new("tank/os", "backup/tank/os");
+        dataset.outcome = if success {
+            Outcome::Success
+        } else {
+            Outcome::Failed
+        };
+
+        RunReport {
+            schema_version: SCHEMA_VERSION,
+            version: String::from("test"),
+            config: RunConfig {
+                job: Some(String::from("nightly")),
+                backup_pool: String::from("backup"),
+                backup_pools: vec![String::from("backup")],
+                label: String::from("CHECKPOINT"),
+                datasets: vec![String::from("tank/os")],
+                dry_run: false,
+            },
+            total_snapshots: 0,
+            created_snapshots: Vec::new(),
+            pools: Vec::new(),
+            datasets: vec![dataset],
+            scrubs: Vec::new(),
+            bytes_sent: 0,
+            duration_ms: 0,
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            success,
+        }
+    }
+
+    fn get_temp_dir(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("cantaloupe-notify-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    // Accepts a single HTTP request, answers it with the given status and
+    // returns the request body.
+    fn serve_once(status: &'static str) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            String::from_utf8(body).unwrap()
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_trigger_should_match_state_changes() {
+        assert!(Trigger::Change.matches(false, Some(true)));
+        assert!(Trigger::Change.matches(true, Some(false)));
+        assert!(Trigger::Change.matches(false, None));
+        assert!(!Trigger::Change.matches(true, None));
+        assert!(!Trigger::Change.matches(false, Some(false)));
+        assert!(!Trigger::Failure.matches(true, Some(false)));
+        assert!(Trigger::Always.matches(true, Some(true)));
+    }
+
+    #[test]
+    fn test_notify_should_post_payload_to_webhook() {
+        let (url, handle) = serve_once("200 OK");
+        let notifications = vec![Notification {
+            on: Trigger::Failure,
+            notifier: Notifier::Webhook {
+                url,
+                timeout: DEFAULT_TIMEOUT,
+            },
+        }];
+
+        let errors = notify(&notifications, "nightly", &get_example_report(false), None);
+        let body: serde_json::Value = serde_json::from_str(&handle.join().unwrap()).unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(body["job"], "nightly");
+        assert_eq!(body["status"], "failed");
+        assert_eq!(body["report"]["datasets"][0]["outcome"], "failed");
+    }
+
+    #[test]
+    fn test_notify_should_report_webhook_errors() {
+        let (url, handle) = serve_once("500 Internal Server Error");
+        let notifications = vec![Notification {
+            on: Trigger::Always,
+            notifier: Notifier::Webhook {
+                url,
+                timeout: DEFAULT_TIMEOUT,
+            },
+        }];
+
+        let errors = notify(&notifications, "nightly", &get_example_report(true), None);
+        handle.join().unwrap();
+
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_notify_should_send_mail_through_sendmail() {
+        let directory = get_temp_dir("mail");
+        let sendmail = directory.join("sendmail");
+        let mail = directory.join("mail.txt");
+        fs::write(
+            &sendmail,
+            format!("#!/bin/sh\necho \"$@\" > {0}\ncat >> {0}\n", mail.display()),
+        )
+        .unwrap();
+        fs::set_permissions(&sendmail, fs::Permissions::from_mode(0o755)).unwrap();
+        let notifications = vec![Notification {
+            on: Trigger::Failure,
+            notifier: Notifier::Mail {
+                to: vec![String::from("root@localhost")],
+                from: None,
+                sendmail: sendmail.display().to_string(),
+            },
+        }];
+
+        let errors = notify(&notifications, "nightly", &get_example_report(false), None);
+        let message = fs::read_to_string(&mail).unwrap_or_default();
+        let _ = fs::remove_dir_all(&directory);
+
+        assert!(errors.is_empty());
+        assert!(message.starts_with("-t -oi\nTo: root@localhost\n"));
+        assert!(message.contains("Subject: [cantaloupe] Job 'nightly' failed"));
+        assert!(message.contains("tank/os -> backup/tank/os: failed"));
+    }
+
+    #[test]
+    fn test_notify_should_pass_payload_to_command() {
+        let directory = get_temp_dir("command");
+        let output = directory.join("payload.json");
+        let notifications = vec![Notification {
+            on: Trigger::Success,
+            notifier: Notifier::Command {
+                command: format!(
+                    "test \"$CANTALOUPE_STATUS\" = success && cat > {}",
+                    output.display()
+                ),
+                timeout: None,
+            },
+        }];
+
+        let skipped = notify(&notifications, "nightly", &get_example_report(false), None);
+        let skipped_output = output.exists();
+        let errors = notify(&notifications, "nightly", &get_example_report(true), None);
+        let payload = fs::read_to_string(&output).unwrap_or_default();
+        let _ = fs::remove_dir_all(&directory);
+
+        assert!(skipped.is_empty());
+        assert!(!skipped_output);
+        assert!(errors.is_empty());
+        assert!(payload.contains("\"status\":\"success\""));
+    }
+}
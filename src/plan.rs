@@ -0,0 +1,934 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Job, Retention};
+use crate::snapshot::Snapshot;
+use crate::traits::SystemProvider;
+use crate::Cantaloupe;
+
+// A single step of bringing a backup dataset up to date.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    // Creates the parents of the backup dataset so that a full send can be
+    // received into it.
+    CreateDatasetTree {
+        backup_dataset: String,
+    },
+    SendFull {
+        snapshot: String,
+        backup_dataset: String,
+    },
+    SendIncremental {
+        from: String,
+        to: String,
+        backup_dataset: String,
+    },
+    // Destroys a labeled backup snapshot that falls outside of the
+    // retention policy. Only done once the backup is up to date.
+    Prune {
+        snapshot: String,
+    },
+    // A backup snapshot that falls outside of the retention policy, but is
+    // kept since it has a user hold ('zfs hold').
+    Hold {
+        snapshot: String,
+    },
+    // The backup already has the latest snapshot.
+    UpToDate,
+    // Nothing can safely be sent.
+    Skip {
+        reason: String,
+    },
+    // Working out what to send failed.
+    Fail {
+        reason: String,
+        error: String,
+    },
+}
+
+impl Action {
+    pub fn describe(&self) -> String {
+        match self {
+            Action::CreateDatasetTree { backup_dataset } => {
+                format!("Create the dataset hierarchy for {}", backup_dataset)
+            }
+            Action::SendFull { snapshot, .. } => format!("Send {} in full", snapshot),
+            Action::SendIncremental { from, to, .. } => {
+                format!("Send {} incrementally from {}", to, from)
+            }
+            Action::Prune { snapshot } => format!("Prune {}", snapshot),
+            Action::Hold { snapshot } => format!("Keep {}, since it is held", snapshot),
+            Action::UpToDate => String::from("Nothing to do, already up to date"),
+            Action::Skip { reason } | Action::Fail { reason, .. } => reason.clone(),
+        }
+    }
+}
+
+// The send that brings a backup dataset up to date: incremental from the
+// given snapshot, or full when there is none.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Send {
+    pub from: Option<String>,
+    pub to: String,
+}
+
+// What has to happen to replicate a source dataset into the backup dataset
+// in one of the backup pools, along with what was found while planning it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TargetPlan {
+    pub dataset: String,
+    pub backup_pool: String,
+    pub backup_dataset: String,
+    pub source_snapshots: usize,
+    pub backup_snapshots: usize,
+    pub latest_snapshot: Option<String>,
+    pub common_snapshot: Option<String>,
+    pub replication_lag_seconds: Option<i64>,
+    // Whether the common snapshot was matched by GUID in order to continue
+    // a backup that was made with another label.
+    pub adopted: bool,
+    pub actions: Vec<Action>,
+}
+
+impl TargetPlan {
+    pub fn get_send(&self) -> Option<Send> {
+        self.actions.iter().find_map(|action| match action {
+            Action::SendFull { snapshot, .. } => Some(Send {
+                from: None,
+                to: snapshot.clone(),
+            }),
+            Action::SendIncremental { from, to, .. } => Some(Send {
+                from: Some(from.clone()),
+                to: to.clone(),
+            }),
+            _ => None,
+        })
+    }
+
+    pub fn get_prunes(&self) -> Vec<&str> {
+        self.actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Prune { snapshot } => Some(snapshot.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn get_holds(&self) -> Vec<&str> {
+        self.actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Hold { snapshot } => Some(snapshot.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+// A source dataset and what has to happen in each of the backup pools.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DatasetPlan {
+    pub dataset: String,
+    pub targets: Vec<TargetPlan>,
+}
+
+// Everything that has to happen to replicate the datasets of a job.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Plan {
+    pub label: String,
+    pub backup_pools: Vec<String>,
+    pub total_snapshots: usize,
+    // Whether identical sends into several backup pools share one stream.
+    pub tee: bool,
+    pub datasets: Vec<DatasetPlan>,
+}
+
+impl Plan {
+    pub fn get_targets(&self) -> impl Iterator<Item = &TargetPlan> {
+        self.datasets.iter().flat_map(|dataset| &dataset.targets)
+    }
+}
+
+// The version of the format of saved plans, which changes independently of
+// the reports. Bump this whenever a field is renamed or removed, or its
+// meaning changes, since 'apply' refuses plans of any other version.
+pub const PLAN_SCHEMA_VERSION: u32 = 1;
+
+// A plan written to a file by the 'plan' command, together with the state
+// of the pools and datasets it was based on, so that 'apply' can refuse to
+// run it once anything has changed. GUIDs are kept as strings like in the
+// catalog.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedPlan {
+    pub schema_version: u32,
+    pub version: String,
+    // RFC 3339 timestamp of when the plan was made.
+    pub created_at: String,
+    pub job: String,
+    pub send_flags: Vec<String>,
+    // The GUID of each backup pool.
+    pub pool_guids: BTreeMap<String, String>,
+    // Every snapshot of each backup dataset that is written to, by name.
+    // These must not change at all.
+    pub backup_guids: BTreeMap<String, BTreeMap<String, String>>,
+    // The source snapshots that the sends are based on, by name. These must
+    // still exist, but new snapshots may be taken in the meantime.
+    pub source_guids: BTreeMap<String, BTreeMap<String, String>>,
+    pub plan: Plan,
+}
+
+impl SavedPlan {
+    // Records the state that the plan is based on.
+    pub fn new(
+        system: &impl SystemProvider,
+        name: &str,
+        job: &Job,
+        plan: Plan,
+        created_at: &str,
+    ) -> Result<Self, String> {
+        // Without the GUID, applying the plan couldn't tell whether the disk
+        // was swapped in the meantime.
+        let mut pool_guids = BTreeMap::new();
+        for backup_pool in &plan.backup_pools {
+            let guid = system.get_pool_guid(backup_pool).map_err(|e| {
+                format!("Failed to get the GUID of the {} pool: {}", backup_pool, e)
+            })?;
+            pool_guids.insert(backup_pool.clone(), guid.to_string());
+        }
+
+        let mut backup_guids = BTreeMap::new();
+        let mut source_guids: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+        for target in plan.get_targets() {
+            let send = target.get_send();
+            if send.is_none() && target.get_prunes().is_empty() {
+                continue;
+            }
+            backup_guids.insert(
+                target.backup_dataset.clone(),
+                get_snapshot_guids(system, &target.backup_dataset),
+            );
+
+            let Some(send) = send else {
+                continue;
+            };
+            let guids = system.get_snapshot_guids(&target.dataset).map_err(|e| {
+                format!(
+                    "Failed to get the snapshot GUIDs of {}: {}",
+                    target.dataset, e
+                )
+            })?;
+            let recorded = source_guids.entry(target.dataset.clone()).or_default();
+            for snapshot in send.from.iter().chain([&send.to]) {
+                let guid = guids
+                    .iter()
+                    .find(|(name, _)| name == snapshot)
+                    .map(|(_, guid)| guid.to_string())
+                    .ok_or_else(|| format!("{} doesn't exist.", snapshot))?;
+                recorded.insert(snapshot.clone(), guid);
+            }
+        }
+
+        Ok(Self {
+            schema_version: PLAN_SCHEMA_VERSION,
+            version: String::from(clap::crate_version!()),
+            created_at: String::from(created_at),
+            job: String::from(name),
+            send_flags: job.send_flags.clone(),
+            pool_guids,
+            backup_guids,
+            source_guids,
+            plan,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let saved: Self = serde_json::from_str(&contents)
+            .map_err(|e| format!("{} is not a valid plan: {}", path.display(), e))?;
+        if saved.schema_version != PLAN_SCHEMA_VERSION {
+            return Err(format!(
+                "{} was written by an incompatible version of Cantaloupe ({}).",
+                path.display(),
+                saved.version
+            ));
+        }
+        // Applying a plan needs at least one backup pool to write to.
+        if saved.plan.backup_pools.is_empty() {
+            return Err(format!(
+                "{} is not a valid plan: it has no backup pools.",
+                path.display()
+            ));
+        }
+        Ok(saved)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let contents = serde_json::to_string_pretty(self).expect("plan is serializable");
+        fs::write(path, contents + "\n")
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    // Compares the pools and datasets with what the plan was based on.
+    // Returns everything that drifted since.
+    pub fn verify(&self, system: &impl SystemProvider) -> Vec<String> {
+        let mut drifts = Vec::new();
+        for (backup_pool, expected) in &self.pool_guids {
+            match system.get_pool_guid(backup_pool) {
+                Ok(guid) if guid.to_string() == *expected => {}
+                Ok(guid) => drifts.push(format!(
+                    "{} pool has GUID {}, but the plan was made for GUID {}.",
+                    backup_pool, guid, expected
+                )),
+                Err(error) => drifts.push(format!(
+                    "Can't verify the GUID of the {} pool: {}",
+                    backup_pool, error
+                )),
+            }
+        }
+
+        for (backup_dataset, expected) in &self.backup_guids {
+            if get_snapshot_guids(system, backup_dataset) != *expected {
+                drifts.push(format!(
+                    "The snapshots of {} changed since the plan was made.",
+                    backup_dataset
+                ));
+            }
+        }
+
+        for (dataset, expected) in &self.source_guids {
+            let snapshots = get_snapshot_guids(system, dataset);
+            for (snapshot, guid) in expected {
+                if snapshots.get(snapshot) != Some(guid) {
+                    drifts.push(format!(
+                        "{} was destroyed or replaced since the plan was made.",
+                        snapshot
+                    ));
+                }
+            }
+        }
+        drifts
+    }
+}
+
+// Gets the snapshots of the dataset with their GUIDs. Datasets that don't
+// exist (yet) have none.
+fn get_snapshot_guids(system: &impl SystemProvider, dataset: &str) -> BTreeMap<String, String> {
+    system
+        .get_snapshot_guids(dataset)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, guid)| (name, guid.to_string()))
+        .collect()
+}
+
+// Prints the actions of the plan in a human readable form.
+pub fn print_text(plan: &Plan) {
+    for target in plan.get_targets() {
+        println!("{} -> {}", target.dataset, target.backup_dataset);
+        for action in &target.actions {
+            println!("  {}", action.describe());
+        }
+        println!();
+    }
+}
+
+// Works out what has to happen to replicate every dataset of the job into
+// each of the given backup pools. Only reads from the system.
+pub fn plan_job(
+    system: &impl SystemProvider,
+    job: &Job,
+    snapshots: &[Snapshot],
+    backup_pools: &[&str],
+) -> Plan {
+    // Checked up front so that a misspelled dataset is an error, rather than
+    // looking like a dataset without snapshots.
+    let existing = system.get_all_datasets();
+
+    let datasets = job
+        .datasets
+        .iter()
+        .map(|source_dataset| DatasetPlan {
+            dataset: source_dataset.clone(),
+            targets: backup_pools
+                .iter()
+                .map(|backup_pool| {
+                    let backup_dataset = job.get_backup_dataset(backup_pool, source_dataset);
+                    match &existing {
+                        Ok(existing) if existing.contains(source_dataset) => {}
+                        Ok(_) => {
+                            return plan_failed_target(
+                                backup_pool,
+                                &backup_dataset,
+                                source_dataset,
+                                format!(
+                                    "{} does not exist. Check the name of the dataset.",
+                                    source_dataset
+                                ),
+                                format!("Source dataset {} does not exist.", source_dataset),
+                            )
+                        }
+                        Err(error) => {
+                            return plan_failed_target(
+                                backup_pool,
+                                &backup_dataset,
+                                source_dataset,
+                                format!("Failed to list the datasets: {}", error),
+                                error.clone(),
+                            )
+                        }
+                    }
+                    let program = Cantaloupe::new_with_backup_dataset(
+                        snapshots,
+                        backup_pool,
+                        &backup_dataset,
+                        source_dataset,
+                        &job.label,
+                    );
+                    plan_target(system, job, &program, source_dataset)
+                })
+                .collect(),
+        })
+        .collect();
+
+    Plan {
+        label: job.label.clone(),
+        backup_pools: backup_pools
+            .iter()
+            .map(|pool| String::from(*pool))
+            .collect(),
+        total_snapshots: snapshots.len(),
+        tee: job.tee && backup_pools.len() > 1,
+        datasets,
+    }
+}
+
+// Works out what has to happen to bring the backup dataset of the program up
+// to date with the source dataset.
+pub fn plan_target(
+    system: &impl SystemProvider,
+    job: &Job,
+    program: &Cantaloupe,
+    source_dataset: &str,
+) -> TargetPlan {
+    let backup_dataset = program.get_backup_dataset_name();
+    let mut plan = TargetPlan {
+        dataset: String::from(source_dataset),
+        backup_pool: String::from(program.get_backup_pool_name()),
+        backup_dataset: String::from(backup_dataset),
+        source_snapshots: program.get_source_snapshots_labeled().len(),
+        backup_snapshots: program.get_backup_snapshots_labeled().len(),
+        latest_snapshot: None,
+        common_snapshot: None,
+        replication_lag_seconds: None,
+        adopted: false,
+        actions: Vec::new(),
+    };
+    plan_send(system, job, program, &mut plan);
+
+    // Old backup snapshots are only pruned once the backup is up to date.
+    if plan.actions.contains(&Action::UpToDate) || plan.get_send().is_some() {
+        if let Some(retention) = &job.retention {
+            plan_prunes(system, program, retention, &mut plan);
+        }
+    }
+    plan
+}
+
+// Prunes the backup snapshots that fall outside of the retention policy,
+// except for the held ones that 'zfs destroy' would refuse. When the holds
+// can't be listed, every snapshot is pruned and the held ones fail then.
+fn plan_prunes(
+    system: &impl SystemProvider,
+    program: &Cantaloupe,
+    retention: &Retention,
+    plan: &mut TargetPlan,
+) {
+    let prunable = program.get_prunable_backup_snapshots(retention.keep);
+    if prunable.is_empty() {
+        return;
+    }
+    let held = system
+        .get_held_snapshots(program.get_backup_dataset_name())
+        .unwrap_or_default();
+    for snapshot in prunable {
+        plan.actions.push(if held.contains(&snapshot.name) {
+            Action::Hold {
+                snapshot: snapshot.name,
+            }
+        } else {
+            Action::Prune {
+                snapshot: snapshot.name,
+            }
+        });
+    }
+}
+
+// A target that can't be planned at all, since its source dataset doesn't
+// exist or couldn't be looked up.
+fn plan_failed_target(
+    backup_pool: &str,
+    backup_dataset: &str,
+    source_dataset: &str,
+    reason: String,
+    error: String,
+) -> TargetPlan {
+    TargetPlan {
+        dataset: String::from(source_dataset),
+        backup_pool: String::from(backup_pool),
+        backup_dataset: String::from(backup_dataset),
+        source_snapshots: 0,
+        backup_snapshots: 0,
+        latest_snapshot: None,
+        common_snapshot: None,
+        replication_lag_seconds: None,
+        adopted: false,
+        actions: vec![Action::Fail { reason, error }],
+    }
+}
+
+fn plan_send(system: &impl SystemProvider, job: &Job, program: &Cantaloupe, plan: &mut TargetPlan) {
+    let backup_dataset = program.get_backup_dataset_name();
+    if plan.source_snapshots == 0 {
+        let unlabeled = program.get_dataset_snapshots(&plan.dataset).len();
+        let reason = if unlabeled == 0 {
+            String::from(
+                "No source snapshots available. The dataset has no snapshots yet. Skipping.",
+            )
+        } else {
+            format!(
+                "No source snapshots available with the label {}. The dataset only has ({}) snapshots with other labels. Skipping.",
+                job.label, unlabeled
+            )
+        };
+        plan.actions.push(Action::Skip { reason });
+        return;
+    }
+
+    let latest_snapshot = String::from(program.get_latest_source_snapshot_name());
+    plan.latest_snapshot = Some(latest_snapshot.clone());
+
+    if let Some(common_snapshot) = program.get_common_snapshot() {
+        plan.common_snapshot = Some(String::from(common_snapshot));
+        plan.replication_lag_seconds = program.get_replication_lag();
+        if common_snapshot == latest_snapshot {
+            plan.actions.push(Action::UpToDate);
+        } else {
+            plan.actions.push(Action::SendIncremental {
+                from: String::from(common_snapshot),
+                to: latest_snapshot,
+                backup_dataset: String::from(backup_dataset),
+            });
+        }
+        return;
+    }
+
+    // Make sure we don't already have snapshots under this dataset since
+    // we are writing to the entire dataset and want to have labeled and
+    // direct incremental writes afterwards.
+    let backup_snapshots = program.get_backup_snapshots();
+    if !backup_snapshots.is_empty() {
+        if job.adopt {
+            plan_adoption(system, program, plan);
+        } else {
+            plan.actions.push(Action::Skip {
+                reason: format!("Backup pool already contains ({}) snapshots for this dataset under a different label. Will not do a full send. Use --adopt to continue from a snapshot both sides share. Skipping.", backup_snapshots.len()),
+            });
+        }
+        return;
+    }
+
+    plan.actions.push(Action::CreateDatasetTree {
+        backup_dataset: String::from(backup_dataset),
+    });
+    plan.actions.push(Action::SendFull {
+        snapshot: latest_snapshot,
+        backup_dataset: String::from(backup_dataset),
+    });
+}
+
+// Continues a backup that was made with another label by sending
+// incrementally from the newest snapshot the source and backup share.
+// Afterwards the backup has the latest labeled snapshot, so later runs
+// continue with the labeled chain.
+fn plan_adoption(system: &impl SystemProvider, program: &Cantaloupe, plan: &mut TargetPlan) {
+    let guids = system
+        .get_snapshot_guids(&plan.dataset)
+        .and_then(|source| Ok((source, system.get_snapshot_guids(&plan.backup_dataset)?)));
+    let (source_guids, backup_guids) = match guids {
+        Ok(guids) => guids,
+        Err(error) => {
+            plan.actions.push(Action::Fail {
+                reason: format!("Failed to get the snapshot GUIDs: {}", error),
+                error,
+            });
+            return;
+        }
+    };
+
+    match program.get_adoptable_snapshot(&source_guids, &backup_guids) {
+        Ok(common_snapshot) => {
+            plan.common_snapshot = Some(common_snapshot.clone());
+            plan.adopted = true;
+            plan.actions.push(Action::SendIncremental {
+                from: common_snapshot,
+                to: String::from(program.get_latest_source_snapshot_name()),
+                backup_dataset: plan.backup_dataset.clone(),
+            });
+        }
+        Err(error) => plan.actions.push(Action::Skip {
+            reason: format!("Can't adopt the existing backup. {} Skipping.", error),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Retention;
+    use crate::testing::FakeSystem;
+
+    fn get_example_snapshots() -> Vec<Snapshot> {
+        vec![
+            Snapshot::new("tank/os@2022-09-01-0300-00-TEST"),
+            Snapshot::new("tank/os@2022-09-02-0300-00-TEST"),
+            Snapshot::new("tank/os@2022-09-03-0300-00-TEST"),
+            Snapshot::new("backup/tank/os@2022-09-01-0300-00-TEST"),
+            Snapshot::new("backup/tank/os@2022-09-02-0300-00-TEST"),
+            Snapshot::new("tank/var@2022-09-03-0300-00-TEST"),
+            Snapshot::new("tank/home@2022-09-03-0300-00-TEST"),
+            Snapshot::new("backup/tank/home@2022-08-01-0300-00-OTHER"),
+        ]
+    }
+
+    fn get_actions(plan: &Plan, dataset: usize) -> &[Action] {
+        &plan.datasets[dataset].targets[0].actions
+    }
+
+    #[test]
+    fn test_plan_job_should_plan_each_kind_of_send() {
+        let mut system = FakeSystem::new_with_snaps(get_example_snapshots());
+        system.datasets = vec![String::from("tank/empty")];
+        let job = Job::new(
+            "backup",
+            "TEST",
+            &[
+                String::from("tank/os"),
+                String::from("tank/var"),
+                String::from("tank/home"),
+                String::from("tank/empty"),
+            ],
+        );
+
+        let plan = plan_job(&system, &job, &get_example_snapshots(), &["backup"]);
+
+        assert_eq!(plan.total_snapshots, 8);
+        assert!(!plan.tee);
+        assert_eq!(
+            get_actions(&plan, 0),
+            [Action::SendIncremental {
+                from: String::from("tank/os@2022-09-02-0300-00-TEST"),
+                to: String::from("tank/os@2022-09-03-0300-00-TEST"),
+                backup_dataset: String::from("backup/tank/os"),
+            }]
+        );
+        assert_eq!(
+            get_actions(&plan, 1),
+            [
+                Action::CreateDatasetTree {
+                    backup_dataset: String::from("backup/tank/var"),
+                },
+                Action::SendFull {
+                    snapshot: String::from("tank/var@2022-09-03-0300-00-TEST"),
+                    backup_dataset: String::from("backup/tank/var"),
+                },
+            ]
+        );
+        assert!(matches!(
+            get_actions(&plan, 2),
+            [Action::Skip { reason }] if reason.contains("Will not do a full send")
+        ));
+        assert!(matches!(
+            get_actions(&plan, 3),
+            [Action::Skip { reason }] if reason.starts_with("No source snapshots")
+        ));
+    }
+
+    #[test]
+    fn test_plan_job_should_tell_missing_datasets_from_ones_without_snapshots() {
+        let mut system = FakeSystem::new();
+        system.datasets = vec![String::from("tank/os"), String::from("tank/empty")];
+        let job = Job::new(
+            "backup",
+            "OTHER",
+            &[
+                String::from("tank/os"),
+                String::from("tank/empty"),
+                String::from("tank/typo"),
+            ],
+        );
+
+        let plan = plan_job(&system, &job, &get_example_snapshots(), &["backup"]);
+
+        assert!(matches!(
+            get_actions(&plan, 0),
+            [Action::Skip { reason }] if reason.contains("only has (3) snapshots with other labels")
+        ));
+        assert!(matches!(
+            get_actions(&plan, 1),
+            [Action::Skip { reason }] if reason.contains("has no snapshots yet")
+        ));
+        assert_eq!(
+            get_actions(&plan, 2),
+            [Action::Fail {
+                reason: String::from("tank/typo does not exist. Check the name of the dataset."),
+                error: String::from("Source dataset tank/typo does not exist."),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_plan_job_should_fail_every_target_when_the_datasets_cannot_be_listed() {
+        let mut system = FakeSystem::new_with_snaps(get_example_snapshots());
+        system.list_datasets = false;
+        let job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+
+        let plan = plan_job(&system, &job, &get_example_snapshots(), &["backup", "usb"]);
+
+        for target in plan.get_targets() {
+            assert_eq!(
+                target.actions,
+                [Action::Fail {
+                    reason: String::from("Failed to list the datasets: fake failure"),
+                    error: String::from("fake failure"),
+                }]
+            );
+        }
+        assert_eq!(plan.get_targets().count(), 2);
+    }
+
+    #[test]
+    fn test_plan_target_should_prune_once_up_to_date() {
+        let system = FakeSystem::new();
+        let snapshots = get_example_snapshots();
+        let mut job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        job.retention = Some(Retention { keep: 2 });
+        let program = Cantaloupe::new(&snapshots, "backup", "tank/os", "TEST");
+
+        let plan = plan_target(&system, &job, &program, "tank/os");
+
+        assert_eq!(
+            plan.get_send(),
+            Some(Send {
+                from: Some(String::from("tank/os@2022-09-02-0300-00-TEST")),
+                to: String::from("tank/os@2022-09-03-0300-00-TEST"),
+            })
+        );
+        assert_eq!(
+            plan.get_prunes(),
+            vec!["backup/tank/os@2022-09-01-0300-00-TEST"]
+        );
+        assert_eq!(plan.replication_lag_seconds, Some(24 * 60 * 60));
+    }
+
+    #[test]
+    fn test_plan_target_should_keep_held_snapshots() {
+        let mut system = FakeSystem::new();
+        system.held_snapshots = Some(vec![String::from("backup/tank/os@2022-09-01-0300-00-TEST")]);
+        let snapshots = get_example_snapshots();
+        let mut job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        job.retention = Some(Retention { keep: 1 });
+        let program = Cantaloupe::new(&snapshots, "backup", "tank/os", "TEST");
+
+        let plan = plan_target(&system, &job, &program, "tank/os");
+
+        assert_eq!(
+            plan.get_holds(),
+            vec!["backup/tank/os@2022-09-01-0300-00-TEST"]
+        );
+        assert_eq!(
+            plan.get_prunes(),
+            vec!["backup/tank/os@2022-09-02-0300-00-TEST"]
+        );
+    }
+
+    #[test]
+    fn test_plan_target_should_adopt_backup_by_guid() {
+        let mut system = FakeSystem::new();
+        system.guids.insert(
+            String::from("tank/home"),
+            vec![
+                (String::from("tank/home@2022-08-01-0300-00-OTHER"), 7),
+                (String::from("tank/home@2022-09-03-0300-00-TEST"), 8),
+            ],
+        );
+        system.guids.insert(
+            String::from("backup/tank/home"),
+            vec![(String::from("backup/tank/home@2022-08-01-0300-00-OTHER"), 7)],
+        );
+        let mut job = Job::new("backup", "TEST", &[String::from("tank/home")]);
+        job.adopt = true;
+        let snapshots = get_example_snapshots();
+        let program = Cantaloupe::new(&snapshots, "backup", "tank/home", "TEST");
+
+        let plan = plan_target(&system, &job, &program, "tank/home");
+
+        assert!(plan.adopted);
+        assert_eq!(
+            plan.common_snapshot.as_deref(),
+            Some("tank/home@2022-08-01-0300-00-OTHER")
+        );
+        assert_eq!(
+            plan.get_send().and_then(|send| send.from),
+            Some(String::from("tank/home@2022-08-01-0300-00-OTHER"))
+        );
+    }
+
+    fn get_saved_plan(system: &FakeSystem) -> SavedPlan {
+        let job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        let plan = plan_job(system, &job, &get_example_snapshots(), &["backup"]);
+        SavedPlan::new(system, "nightly", &job, plan, "2022-09-03T04:00:00+00:00").unwrap()
+    }
+
+    fn get_guid_system() -> FakeSystem {
+        let mut system = FakeSystem::new_with_snaps(get_example_snapshots());
+        system.pool_guids.insert(String::from("backup"), 42);
+        system.guids.insert(
+            String::from("tank/os"),
+            vec![
+                (String::from("tank/os@2022-09-02-0300-00-TEST"), 2),
+                (String::from("tank/os@2022-09-03-0300-00-TEST"), 3),
+            ],
+        );
+        system.guids.insert(
+            String::from("backup/tank/os"),
+            vec![(String::from("backup/tank/os@2022-09-02-0300-00-TEST"), 2)],
+        );
+        system
+    }
+
+    #[test]
+    fn test_saved_plan_should_record_what_it_is_based_on() {
+        let system = get_guid_system();
+
+        let saved = get_saved_plan(&system);
+        let json = serde_json::to_string(&saved).unwrap();
+        let loaded: SavedPlan = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(saved.pool_guids["backup"], "42");
+        assert_eq!(saved.source_guids["tank/os"].len(), 2);
+        assert_eq!(saved.backup_guids["backup/tank/os"].len(), 1);
+        assert_eq!(loaded, saved);
+        assert!(saved.verify(&system).is_empty());
+    }
+
+    #[test]
+    fn test_saved_plan_should_require_the_guid_of_each_backup_pool() {
+        let mut system = get_guid_system();
+        system.pool_guids.clear();
+        let job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        let plan = plan_job(&system, &job, &get_example_snapshots(), &["backup"]);
+
+        let result = SavedPlan::new(&system, "nightly", &job, plan, "2022-09-03T04:00:00+00:00");
+
+        assert_eq!(
+            result.unwrap_err(),
+            "Failed to get the GUID of the backup pool: fake failure"
+        );
+    }
+
+    #[test]
+    fn test_saved_plan_should_detect_drift() {
+        let mut system = get_guid_system();
+        let saved = get_saved_plan(&system);
+        system.pool_guids.insert(String::from("backup"), 43);
+        system.guids.insert(
+            String::from("tank/os"),
+            vec![(String::from("tank/os@2022-09-02-0300-00-TEST"), 2)],
+        );
+
+        let drifts = saved.verify(&system);
+
+        assert_eq!(
+            drifts,
+            vec![
+                "backup pool has GUID 43, but the plan was made for GUID 42.",
+                "tank/os@2022-09-03-0300-00-TEST was destroyed or replaced since the plan was made.",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_saved_plan_load_should_reject_plans_without_backup_pools() {
+        let system = get_guid_system();
+        let mut saved = get_saved_plan(&system);
+        saved.plan.backup_pools.clear();
+        let path =
+            std::env::temp_dir().join(format!("cantaloupe-plan-{}.json", std::process::id()));
+        saved.save(&path).unwrap();
+
+        let result = SavedPlan::load(&path);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(
+            result.unwrap_err(),
+            format!(
+                "{} is not a valid plan: it has no backup pools.",
+                path.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_saved_plan_load_should_reject_other_schema_versions() {
+        let system = get_guid_system();
+        let mut saved = get_saved_plan(&system);
+        saved.schema_version = PLAN_SCHEMA_VERSION + 1;
+        let path = std::env::temp_dir().join(format!(
+            "cantaloupe-plan-version-{}.json",
+            std::process::id()
+        ));
+        saved.save(&path).unwrap();
+
+        let result = SavedPlan::load(&path);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(
+            result.unwrap_err(),
+            format!(
+                "{} was written by an incompatible version of Cantaloupe ({}).",
+                path.display(),
+                saved.version
+            )
+        );
+    }
+}
@@ -0,0 +1,701 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::helpers;
+use crate::traits::SystemProvider;
+
+// The states reported by 'zpool list -o health'.
+pub const POOL_STATES: [&str; 7] = [
+    "ONLINE",
+    "DEGRADED",
+    "FAULTED",
+    "OFFLINE",
+    "UNAVAIL",
+    "REMOVED",
+    "SUSPENDED",
+];
+
+// The health of a pool as reported by 'zpool list'.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PoolHealth {
+    pub pool: String,
+    // ONLINE, DEGRADED, FAULTED, ...
+    pub health: String,
+    // How full the pool is, in percent.
+    pub capacity: u8,
+    // How fragmented the free space is, in percent. Not every pool reports
+    // it.
+    pub fragmentation: Option<u8>,
+}
+
+impl PoolHealth {
+    pub fn is_online(&self) -> bool {
+        self.health == "ONLINE"
+    }
+
+    // Describes the health on a single line, e.g. 'ONLINE, 45% full, 3%
+    // fragmented'.
+    pub fn describe(&self) -> String {
+        match self.fragmentation {
+            Some(fragmentation) => format!(
+                "{}, {}% full, {}% fragmented",
+                self.health, self.capacity, fragmentation
+            ),
+            None => format!("{}, {}% full", self.health, self.capacity),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScrubState {
+    // The pool has never been scrubbed, or was resilvered since.
+    None,
+    Running,
+    Paused,
+    Canceled,
+    Finished,
+}
+
+// The last scrub of a pool as reported by 'zpool status'.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScrubStatus {
+    pub state: ScrubState,
+    pub repaired_bytes: u64,
+    // Unrecoverable errors the scrub found.
+    pub errors: u64,
+}
+
+// How to scrub the backup pools once everything has been sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScrubOptions {
+    // How often 'zpool status' is polled while waiting for the scrub.
+    pub poll_interval: Duration,
+    // How long to wait for the scrub before giving up on it. The scrub
+    // itself keeps running.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for ScrubOptions {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_secs(60),
+            timeout: None,
+        }
+    }
+}
+
+// The outcome of checking the health of a pool before a run.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct HealthCheck {
+    // None when the health couldn't be read.
+    pub health: Option<PoolHealth>,
+    pub warnings: Vec<String>,
+    // Why the pool mustn't be written to.
+    pub error: Option<String>,
+}
+
+// Which backup pools are written to, based on their health.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HealthPolicy {
+    // Backup pools in any of these states are never sent to.
+    pub refuse: Vec<String>,
+    // Pools that are at least this full (in percent) are warned about.
+    pub max_capacity: u8,
+}
+
+impl Default for HealthPolicy {
+    fn default() -> Self {
+        Self {
+            refuse: vec![
+                String::from("DEGRADED"),
+                String::from("FAULTED"),
+                String::from("SUSPENDED"),
+            ],
+            max_capacity: 80,
+        }
+    }
+}
+
+impl HealthPolicy {
+    // Whether data may be sent into a backup pool with the given health.
+    pub fn allows(&self, health: &PoolHealth) -> bool {
+        !self.refuse.contains(&health.health)
+    }
+
+    // Gets the warnings for a pool that can still be used.
+    pub fn get_warnings(&self, health: &PoolHealth) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if !health.is_online() {
+            warnings.push(format!("{} pool is {}.", health.pool, health.health));
+        }
+        if health.capacity >= self.max_capacity {
+            warnings.push(format!(
+                "{} pool is {}% full.",
+                health.pool, health.capacity
+            ));
+        }
+        warnings
+    }
+}
+
+// How removable backup pools are imported before a run and exported again
+// afterwards.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportOptions {
+    // Mounts everything under this directory instead of '/' ('zpool import
+    // -R'). Nothing is mounted by default anyway, but this also keeps the
+    // pool out of the cache file.
+    pub altroot: Option<PathBuf>,
+    // Pools that are imported by GUID rather than by name, for disks that
+    // share the same pool name.
+    pub guids: BTreeMap<String, u64>,
+    // Whether to export the pools that were imported once the run is over.
+    pub export: bool,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            altroot: None,
+            guids: BTreeMap::new(),
+            export: true,
+        }
+    }
+}
+
+// Imports the backup pools that aren't imported yet. Pools that already
+// were are left alone (and won't be exported afterwards). In a dry run the
+// pools are imported read-only so that their snapshots can be listed.
+// Returns the pools that were imported and the errors for the ones that
+// couldn't be.
+pub fn import_pools(
+    system: &impl SystemProvider,
+    text: &dyn Fn(&str),
+    backup_pools: &[String],
+    options: &ImportOptions,
+    dry_run: bool,
+) -> (Vec<String>, Vec<String>) {
+    let mut imported = Vec::new();
+    let mut errors = Vec::new();
+    for backup_pool in backup_pools {
+        if system.is_pool_imported(backup_pool) {
+            continue;
+        }
+
+        let guid = options.guids.get(backup_pool).copied();
+        match guid {
+            Some(guid) => text(&format!(
+                "Importing {} (GUID {}){} ...",
+                backup_pool,
+                guid,
+                if dry_run { " read-only" } else { "" }
+            )),
+            None => text(&format!(
+                "Importing {}{} ...",
+                backup_pool,
+                if dry_run { " read-only" } else { "" }
+            )),
+        }
+        match system.import_pool(backup_pool, guid, options.altroot.as_deref(), dry_run) {
+            Ok(()) => imported.push(backup_pool.clone()),
+            Err(error) => errors.push(format!("Failed to import {}: {}", backup_pool, error)),
+        }
+    }
+    (imported, errors)
+}
+
+// Exports the given pools so that their disks can be unplugged. Returns the
+// errors for the pools that couldn't be exported.
+pub fn export_pools(
+    system: &impl SystemProvider,
+    text: &dyn Fn(&str),
+    backup_pools: &[String],
+) -> Vec<String> {
+    let mut errors = Vec::new();
+    for backup_pool in backup_pools {
+        text(&format!("Exporting {} ...", backup_pool));
+        if let Err(error) = system.export_pool(backup_pool) {
+            errors.push(format!("Failed to export {}: {}", backup_pool, error));
+        }
+    }
+    errors
+}
+
+// Gets the GUID of an imported backup pool and makes sure it's one of the
+// expected ones (if any are registered), so that a different disk that
+// happens to use the same pool name is never written to. Returns None when
+// the GUID can't be read and nothing is expected.
+pub fn verify_pool_guid(
+    system: &impl SystemProvider,
+    backup_pool: &str,
+    expected: &[u64],
+) -> Result<Option<u64>, String> {
+    let guid = match system.get_pool_guid(backup_pool) {
+        Ok(guid) => guid,
+        Err(_) if expected.is_empty() => return Ok(None),
+        Err(error) => {
+            return Err(format!(
+                "Can't verify the GUID of the {} pool: {}",
+                backup_pool, error
+            ))
+        }
+    };
+
+    if !expected.is_empty() && !expected.contains(&guid) {
+        let expected: Vec<String> = expected.iter().map(u64::to_string).collect();
+        return Err(format!(
+            "{} pool has GUID {}, which isn't one of the GUIDs registered for it ({}).",
+            backup_pool,
+            guid,
+            expected.join(", ")
+        ));
+    }
+    Ok(Some(guid))
+}
+
+// Checks the health of a pool. Only backup pools are refused based on the
+// policy since reading from a degraded source pool is still worthwhile. A
+// health that can't be read is only warned about.
+pub fn check_pool_health(
+    system: &impl SystemProvider,
+    pool: &str,
+    policy: &HealthPolicy,
+    backup: bool,
+) -> HealthCheck {
+    let health = match system.get_pool_health(pool) {
+        Ok(health) => health,
+        Err(error) => {
+            return HealthCheck {
+                warnings: vec![format!(
+                    "Can't get the health of the {} pool: {}",
+                    pool, error
+                )],
+                ..HealthCheck::default()
+            };
+        }
+    };
+
+    if backup && !policy.allows(&health) {
+        return HealthCheck {
+            error: Some(format!("{} pool is {}.", pool, health.health)),
+            health: Some(health),
+            warnings: Vec::new(),
+        };
+    }
+
+    HealthCheck {
+        warnings: policy.get_warnings(&health),
+        health: Some(health),
+        error: None,
+    }
+}
+
+// Starts a scrub of the pool (or joins the one already running) and waits
+// for it to finish, polling 'zpool status'. Returns the result of the scrub,
+// which may still have found errors.
+pub fn scrub_pool(
+    system: &impl SystemProvider,
+    text: &dyn Fn(&str),
+    pool: &str,
+    options: &ScrubOptions,
+    interrupted: &AtomicBool,
+) -> Result<ScrubStatus, String> {
+    if system.get_scrub_status(pool)?.state == ScrubState::Running {
+        text(&format!(
+            "A scrub of {} is already running. Waiting for it ...",
+            pool
+        ));
+    } else {
+        text(&format!("Scrubbing {} ...", pool));
+        system.start_scrub(pool)?;
+    }
+
+    let started = Instant::now();
+    loop {
+        let status = system.get_scrub_status(pool)?;
+        match status.state {
+            ScrubState::Finished => return Ok(status),
+            ScrubState::Running => {}
+            ScrubState::Paused => return Err(String::from("the scrub was paused")),
+            ScrubState::Canceled => return Err(String::from("the scrub was canceled")),
+            ScrubState::None => return Err(String::from("the scrub didn't start")),
+        }
+        if let Some(timeout) = options.timeout {
+            if started.elapsed() >= timeout {
+                return Err(format!(
+                    "the scrub didn't finish within {} and is still running",
+                    helpers::format_duration(timeout.as_secs())
+                ));
+            }
+        }
+        if !wait(options.poll_interval, interrupted) {
+            return Err(String::from(
+                "interrupted while waiting for the scrub, which is still running",
+            ));
+        }
+    }
+}
+
+// Waits for the delay, unless interrupted first. Returns whether the whole
+// delay passed.
+fn wait(delay: Duration, interrupted: &AtomicBool) -> bool {
+    let until = Instant::now() + delay;
+    loop {
+        if interrupted.load(Ordering::SeqCst) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= until {
+            return true;
+        }
+        thread::sleep((until - now).min(Duration::from_millis(100)));
+    }
+}
+
+// Gets the state of the last scrub from the output of 'zpool status', e.g.
+//
+//   scan: scrub repaired 0B in 00:00:02 with 0 errors on Sun Oct 16 03:00:02 2022
+pub fn parse_scrub_status(output: &str) -> Result<ScrubStatus, String> {
+    let scan = output
+        .lines()
+        .map(str::trim)
+        .find_map(|line| line.strip_prefix("scan:"))
+        .map(str::trim);
+
+    let state = match scan {
+        Some(scan) if scan.starts_with("scrub in progress") => ScrubState::Running,
+        Some(scan) if scan.starts_with("scrub paused") => ScrubState::Paused,
+        Some(scan) if scan.starts_with("scrub canceled") => ScrubState::Canceled,
+        Some(scan) if scan.starts_with("scrub repaired") => ScrubState::Finished,
+        // No scrub has ever run, or the last scan was a resilver.
+        _ => ScrubState::None,
+    };
+    if state != ScrubState::Finished {
+        return Ok(ScrubStatus {
+            state,
+            repaired_bytes: 0,
+            errors: 0,
+        });
+    }
+
+    let scan = scan.unwrap_or_default();
+    let words: Vec<&str> = scan.split_whitespace().collect();
+    let invalid = || format!("unexpected scrub status: '{}'", scan);
+    let repaired_bytes = words
+        .get(2)
+        .and_then(|size| parse_size(size))
+        .ok_or_else(invalid)?;
+    let errors = words
+        .iter()
+        .position(|word| *word == "with")
+        .and_then(|index| words.get(index + 1))
+        .and_then(|errors| errors.parse().ok())
+        .ok_or_else(invalid)?;
+    Ok(ScrubStatus {
+        state,
+        repaired_bytes,
+        errors,
+    })
+}
+
+// Parses a size as printed by zpool, either exact ('1536') or human
+// readable ('1.50K').
+fn parse_size(value: &str) -> Option<u64> {
+    let (number, unit) = match value.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(index) => value.split_at(index),
+        None => (value, "B"),
+    };
+    let exponent = match unit {
+        "B" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        "P" => 5,
+        "E" => 6,
+        _ => return None,
+    };
+    let number: f64 = number.parse().ok()?;
+    Some((number * 1024_f64.powi(exponent)) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeSystem;
+
+    #[test]
+    fn test_import_pools_should_skip_imported_pools() {
+        let system = FakeSystem::new();
+
+        let (imported, errors) = import_pools(
+            &system,
+            &|_| {},
+            &[String::from("usb")],
+            &ImportOptions::default(),
+            false,
+        );
+
+        assert!(imported.is_empty());
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_import_pools_should_import_missing_pools() {
+        let mut system = FakeSystem::new();
+        system.is_pool_imported = false;
+
+        let (imported, errors) = import_pools(
+            &system,
+            &|_| {},
+            &[String::from("usb1"), String::from("usb2")],
+            &ImportOptions::default(),
+            false,
+        );
+
+        assert_eq!(imported, vec!["usb1", "usb2"]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_import_pools_should_report_failures() {
+        let mut system = FakeSystem::new();
+        system.is_pool_imported = false;
+        system.import_pool = false;
+
+        let (imported, errors) = import_pools(
+            &system,
+            &|_| {},
+            &[String::from("usb")],
+            &ImportOptions::default(),
+            false,
+        );
+
+        assert!(imported.is_empty());
+        assert_eq!(errors, vec!["Failed to import usb: fake failure"]);
+    }
+
+    #[test]
+    fn test_verify_pool_guid_should_accept_registered_guid() {
+        let mut system = FakeSystem::new();
+        system.pool_guids.insert(String::from("usb"), 42);
+
+        let guid = verify_pool_guid(&system, "usb", &[7, 42]);
+
+        assert_eq!(guid, Ok(Some(42)));
+    }
+
+    #[test]
+    fn test_verify_pool_guid_should_reject_other_disks() {
+        let mut system = FakeSystem::new();
+        system.pool_guids.insert(String::from("usb"), 13);
+
+        let error = verify_pool_guid(&system, "usb", &[7, 42]).unwrap_err();
+
+        assert_eq!(
+            error,
+            "usb pool has GUID 13, which isn't one of the GUIDs registered for it (7, 42)."
+        );
+    }
+
+    #[test]
+    fn test_verify_pool_guid_should_require_guid_when_pinned() {
+        let system = FakeSystem::new();
+
+        let unpinned = verify_pool_guid(&system, "usb", &[]);
+        let pinned = verify_pool_guid(&system, "usb", &[42]);
+
+        assert_eq!(unpinned, Ok(None));
+        assert!(pinned.is_err());
+    }
+
+    fn get_health(health: &str, capacity: u8) -> PoolHealth {
+        PoolHealth {
+            pool: String::from("usb"),
+            health: String::from(health),
+            capacity,
+            fragmentation: Some(10),
+        }
+    }
+
+    #[test]
+    fn test_health_policy_should_refuse_unhealthy_pools() {
+        let policy = HealthPolicy::default();
+
+        assert!(policy.allows(&get_health("ONLINE", 10)));
+        assert!(!policy.allows(&get_health("DEGRADED", 10)));
+        assert!(!policy.allows(&get_health("SUSPENDED", 10)));
+    }
+
+    #[test]
+    fn test_health_policy_should_warn_about_full_pools() {
+        let policy = HealthPolicy {
+            refuse: vec![String::from("FAULTED")],
+            max_capacity: 90,
+        };
+
+        let warnings = policy.get_warnings(&get_health("DEGRADED", 95));
+        let none = policy.get_warnings(&get_health("ONLINE", 89));
+
+        assert_eq!(
+            warnings,
+            vec!["usb pool is DEGRADED.", "usb pool is 95% full."]
+        );
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn test_check_pool_health_should_refuse_degraded_backup_pool() {
+        let mut system = FakeSystem::new();
+        system
+            .pool_health
+            .insert(String::from("usb"), get_health("DEGRADED", 10));
+
+        let backup = check_pool_health(&system, "usb", &HealthPolicy::default(), true);
+        let source = check_pool_health(&system, "usb", &HealthPolicy::default(), false);
+
+        assert_eq!(backup.error, Some(String::from("usb pool is DEGRADED.")));
+        assert!(backup.warnings.is_empty());
+        assert_eq!(source.error, None);
+        assert_eq!(source.warnings, vec!["usb pool is DEGRADED."]);
+        assert_eq!(source.health, Some(get_health("DEGRADED", 10)));
+    }
+
+    #[test]
+    fn test_parse_scrub_status_should_read_finished_scrub() {
+        let output = "  pool: backup\n state: ONLINE\n  scan: scrub repaired 1.50K in 00:00:02 with 3 errors on Sun Oct 16 03:00:02 2022\nconfig:\n";
+
+        let status = parse_scrub_status(output).unwrap();
+
+        assert_eq!(
+            status,
+            ScrubStatus {
+                state: ScrubState::Finished,
+                repaired_bytes: 1536,
+                errors: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_scrub_status_should_read_other_states() {
+        let running = "  scan: scrub in progress since Sun Oct 16 03:00:00 2022\n\t1.2G scanned\n";
+        let canceled = "  scan: scrub canceled on Sun Oct 16 03:00:00 2022\n";
+        let never = "  scan: none requested\n";
+        let exact =
+            "  scan: scrub repaired 0 in 00:00:01 with 0 errors on Sun Oct 16 03:00:01 2022\n";
+
+        assert_eq!(
+            parse_scrub_status(running).unwrap().state,
+            ScrubState::Running
+        );
+        assert_eq!(
+            parse_scrub_status(canceled).unwrap().state,
+            ScrubState::Canceled
+        );
+        assert_eq!(parse_scrub_status(never).unwrap().state, ScrubState::None);
+        assert_eq!(parse_scrub_status(exact).unwrap().errors, 0);
+        assert!(parse_scrub_status("  scan: scrub repaired lots\n").is_err());
+    }
+
+    #[test]
+    fn test_scrub_pool_should_give_up_after_timeout() {
+        let mut system = FakeSystem::new();
+        system.scrub_status.state = ScrubState::Running;
+        let options = ScrubOptions {
+            poll_interval: Duration::ZERO,
+            timeout: Some(Duration::ZERO),
+        };
+
+        let error = scrub_pool(
+            &system,
+            &|_| {},
+            "backup",
+            &options,
+            &AtomicBool::new(false),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error,
+            "the scrub didn't finish within 0s and is still running"
+        );
+    }
+
+    #[test]
+    fn test_scrub_pool_should_stop_waiting_when_interrupted() {
+        let mut system = FakeSystem::new();
+        system.scrub_status.state = ScrubState::Running;
+        let options = ScrubOptions {
+            poll_interval: Duration::from_secs(60),
+            timeout: None,
+        };
+
+        let started = Instant::now();
+        let error =
+            scrub_pool(&system, &|_| {}, "backup", &options, &AtomicBool::new(true)).unwrap_err();
+
+        assert_eq!(
+            error,
+            "interrupted while waiting for the scrub, which is still running"
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_scrub_pool_should_return_result() {
+        let mut system = FakeSystem::new();
+        system.scrub_status.errors = 2;
+
+        let status = scrub_pool(
+            &system,
+            &|_| {},
+            "backup",
+            &ScrubOptions::default(),
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+
+        assert_eq!(status.errors, 2);
+    }
+
+    #[test]
+    fn test_export_pools_should_report_failures() {
+        let mut system = FakeSystem::new();
+        system.export_pool = false;
+
+        let errors = export_pools(&system, &|_| {}, &[String::from("usb")]);
+
+        assert_eq!(errors, vec!["Failed to export usb: fake failure"]);
+    }
+}
@@ -23,24 +23,362 @@
 // OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
 // SUCH DAMAGE.
 
-use std::process::{Command, Stdio};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+use crate::bandwidth::Throttle;
+use crate::helpers;
+use crate::pools::{self, PoolHealth, ScrubStatus};
 use crate::snapshot::Snapshot;
-use crate::traits::SystemProvider;
+use crate::traits::{SendOptions, SnapshotGuids, SystemProvider};
+
+// How long the processes of a pipeline get to exit after being asked to,
+// before they are killed.
+const TERM_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+// How long the processes of a pipeline get to exit after being killed,
+// before we stop waiting for them.
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+// Why a pipeline was stopped when the run is interrupted.
+const INTERRUPTED: &str = "the run was interrupted";
 
 pub struct System;
 
+impl Default for System {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl System {
     pub fn new() -> Self {
         Self {}
     }
 
-    pub fn check_pool_imported_or_exit(&self, system: &impl SystemProvider, backup_pool: &str) {
-        if !system.is_pool_imported(backup_pool) {
-            println!("{} pool is not imported. Aborting.", backup_pool);
-            std::process::exit(1);
+    // Pipes the stream produced by the given sender into 'zfs recv' for the
+    // backup dataset. The stream is copied through this process so that we
+    // know how many bytes were sent. Returns the byte count on success, or
+    // the error output of whichever side failed.
+    fn transfer(
+        &self,
+        sender: Command,
+        backup_dataset: &str,
+        options: &SendOptions,
+    ) -> Result<u64, String> {
+        self.transfer_to_many(sender, &[String::from(backup_dataset)], options)
+            .remove(0)
+    }
+
+    // Same as 'transfer', but every chunk of the stream is written to one
+    // 'zfs recv' per backup dataset. A receiver that fails is dropped from
+    // the copy loop without interrupting the others. Every process of the
+    // pipeline is killed if it runs past its deadline or stalls.
+    fn transfer_to_many(
+        &self,
+        mut sender: Command,
+        backup_datasets: &[String],
+        options: &SendOptions,
+    ) -> Vec<Result<u64, String>> {
+        let fail_all = |error: String| vec![Err(error); backup_datasets.len()];
+
+        let mut sender = match sender.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn() {
+            Ok(sender) => sender,
+            Err(e) => return fail_all(format!("failed to execute 'zfs send': {}", e)),
+        };
+
+        // Receives that are cut short keep what they got so far, so that
+        // they can be resumed by a retry or the next run.
+        let mut receivers = Vec::new();
+        for backup_dataset in backup_datasets {
+            let receiver = Command::new("zfs")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped())
+                .arg("recv")
+                .arg("-s")
+                .arg("-vF")
+                .arg(backup_dataset)
+                .spawn()
+                .map_err(|e| format!("failed to execute 'zfs recv': {}", e));
+            receivers.push(receiver);
+        }
+
+        let sender_errors = Self::collect_stderr(sender.stderr.take());
+        let stream = sender.stdout.take().unwrap();
+        let mut receiver_errors = Vec::new();
+        let mut sinks = Vec::new();
+        for receiver in receivers.iter_mut() {
+            match receiver {
+                Ok(child) => {
+                    receiver_errors.push(Some(Self::collect_stderr(child.stderr.take())));
+                    sinks.push(child.stdin.take());
+                }
+                Err(_) => {
+                    receiver_errors.push(None);
+                    sinks.push(None);
+                }
+            }
+        }
+
+        // The stream is copied on its own thread, so that we can stop
+        // waiting for a pipeline whose processes hang even after being
+        // killed.
+        let progress = Arc::new(Mutex::new(Instant::now()));
+        let copier = {
+            let progress = Arc::clone(&progress);
+            let throttles = options.throttles.clone();
+            thread::spawn(move || Self::copy_stream(stream, sinks, &throttles, &progress))
+        };
+
+        // The sender comes first, followed by the receivers that started.
+        let mut children = vec![sender];
+        let receivers: Vec<Result<usize, String>> = receivers
+            .into_iter()
+            .map(|receiver| {
+                receiver.map(|child| {
+                    children.push(child);
+                    children.len() - 1
+                })
+            })
+            .collect();
+
+        let (statuses, killed) = Self::supervise(&mut children, &copier, &progress, options);
+        if let Some(reason) = killed {
+            // Whatever the stopped processes left behind may still hold
+            // their pipes open, so don't wait for the copy or the error
+            // output.
+            let error = format!("{}, so 'zfs send' and 'zfs recv' were stopped", reason);
+            return receivers
+                .into_iter()
+                .map(|receiver| receiver.and(Err(error.clone())))
+                .collect();
+        }
+
+        let (copied, copy_error) = copier
+            .join()
+            .unwrap_or_else(|_| (0, Some(String::from("failed to copy the send stream"))));
+        let sender_errors = sender_errors.join().unwrap_or_default();
+        let sender_failed = !statuses[0];
+
+        let mut results = Vec::new();
+        for (receiver, errors) in receivers.into_iter().zip(receiver_errors) {
+            let receiver = match receiver {
+                Ok(receiver) => receiver,
+                Err(error) => {
+                    results.push(Err(error));
+                    continue;
+                }
+            };
+            let receiver_errors = errors
+                .map(|e| e.join().unwrap_or_default())
+                .unwrap_or_default();
+
+            if !statuses[receiver] {
+                results.push(Err(Self::describe_failure("zfs recv", &receiver_errors)));
+            } else if sender_failed {
+                results.push(Err(Self::describe_failure("zfs send", &sender_errors)));
+            } else if let Some(error) = &copy_error {
+                results.push(Err(error.clone()));
+            } else {
+                results.push(Ok(copied));
+            }
+        }
+        results
+    }
+
+    // Copies the send stream into every receiver until it ends or none of
+    // them are left. Returns the byte count and the error that cut the copy
+    // short, if any.
+    fn copy_stream(
+        mut stream: ChildStdout,
+        mut sinks: Vec<Option<ChildStdin>>,
+        throttles: &[Arc<Throttle>],
+        progress: &Mutex<Instant>,
+    ) -> (u64, Option<String>) {
+        let mut copied: u64 = 0;
+        let mut buffer = vec![0; 128 * 1024];
+        loop {
+            let read = match stream.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    let error = format!("failed to copy the send stream: {}", e);
+                    return (copied, Some(error));
+                }
+            };
+            *lock(progress) = Instant::now();
+            for throttle in throttles {
+                throttle.wait(read, progress);
+            }
+            for sink in sinks.iter_mut() {
+                if let Some(writer) = sink {
+                    if writer.write_all(&buffer[..read]).is_err() {
+                        *sink = None;
+                    }
+                }
+            }
+            if sinks.iter().all(Option::is_none) {
+                break;
+            }
+            copied += read as u64;
         }
+        // Our ends of the pipes are dropped here, so that each side sees
+        // EOF / EPIPE.
+        (copied, None)
     }
+
+    // Waits for the copy and every process of a pipeline to finish. The
+    // processes are asked to terminate once the deadline passes, no data
+    // moved for too long or the run is interrupted. They are killed if they
+    // haven't exited a while later, and given up on if even that doesn't
+    // work. Returns whether each process succeeded, in order, and why they
+    // were stopped if they were.
+    fn supervise<T>(
+        children: &mut [Child],
+        copier: &JoinHandle<T>,
+        progress: &Mutex<Instant>,
+        options: &SendOptions,
+    ) -> (Vec<bool>, Option<String>) {
+        let mut statuses: Vec<Option<bool>> = vec![None; children.len()];
+        let mut killed: Option<(String, Instant)> = None;
+        let mut forced = false;
+        loop {
+            for (child, status) in children.iter_mut().zip(statuses.iter_mut()) {
+                if status.is_none() {
+                    *status = match child.try_wait() {
+                        Ok(Some(exit_status)) => Some(exit_status.success()),
+                        Ok(None) => None,
+                        Err(_) => Some(false),
+                    };
+                }
+            }
+            if copier.is_finished() && statuses.iter().all(Option::is_some) {
+                // Ctrl-C also reaches the processes of the pipeline, so they
+                // may have died of it before we noticed the interruption.
+                if killed.is_none()
+                    && statuses.contains(&Some(false))
+                    && Self::is_interrupted(options)
+                {
+                    killed = Some((String::from(INTERRUPTED), Instant::now()));
+                }
+                break;
+            }
+
+            let now = Instant::now();
+            match &killed {
+                Some((_, at))
+                    if now.duration_since(*at) >= TERM_GRACE_PERIOD + KILL_GRACE_PERIOD =>
+                {
+                    break
+                }
+                Some((_, at)) if now.duration_since(*at) >= TERM_GRACE_PERIOD && !forced => {
+                    for (child, _) in children
+                        .iter_mut()
+                        .zip(&statuses)
+                        .filter(|(_, status)| status.is_none())
+                    {
+                        let _ = child.kill();
+                    }
+                    forced = true;
+                }
+                Some(_) => {}
+                None => {
+                    if let Some(reason) = Self::check_limits(now, *lock(progress), options) {
+                        // Only processes that haven't been waited for yet,
+                        // since the PID of the others may be reused.
+                        let pids: Vec<String> = children
+                            .iter()
+                            .zip(&statuses)
+                            .filter(|(_, status)| status.is_none())
+                            .map(|(child, _)| child.id().to_string())
+                            .collect();
+                        let pids: Vec<&str> = pids.iter().map(String::as_str).collect();
+                        helpers::send_signal("TERM", &pids);
+                        killed = Some((reason, now));
+                    }
+                }
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        let statuses = statuses
+            .into_iter()
+            .map(|status| status.unwrap_or(false))
+            .collect();
+        (statuses, killed.map(|(reason, _)| reason))
+    }
+
+    // Returns why a pipeline should be stopped, given when data last moved
+    // through it.
+    fn check_limits(now: Instant, progress: Instant, options: &SendOptions) -> Option<String> {
+        if Self::is_interrupted(options) {
+            return Some(String::from(INTERRUPTED));
+        }
+        if options.deadline.is_some_and(|deadline| now >= deadline) {
+            return Some(String::from("the dataset ran out of time"));
+        }
+        let stall_timeout = options.stall_timeout?;
+        if now.saturating_duration_since(progress) < stall_timeout {
+            return None;
+        }
+        Some(format!(
+            "no data was transferred for {}",
+            helpers::format_duration(stall_timeout.as_secs())
+        ))
+    }
+
+    fn is_interrupted(options: &SendOptions) -> bool {
+        options
+            .interrupted
+            .as_ref()
+            .is_some_and(|interrupted| interrupted.load(Ordering::SeqCst))
+    }
+
+    fn collect_stderr(stderr: Option<ChildStderr>) -> JoinHandle<String> {
+        thread::spawn(move || {
+            let mut output = String::new();
+            if let Some(mut stderr) = stderr {
+                let _ = stderr.read_to_string(&mut output);
+            }
+            output
+        })
+    }
+
+    fn run_command(mut command: Command, name: &str) -> Result<(), String> {
+        let output = command
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("failed to execute '{}': {}", name, e))?;
+
+        if !output.status.success() {
+            return Err(Self::describe_failure(
+                name,
+                &String::from_utf8_lossy(&output.stderr),
+            ));
+        }
+        Ok(())
+    }
+
+    fn describe_failure(command: &str, stderr: &str) -> String {
+        let stderr = stderr.trim();
+        if stderr.is_empty() {
+            format!("'{}' failed", command)
+        } else {
+            format!("'{}' failed: {}", command, stderr)
+        }
+    }
+}
+
+// Locks the mutex, even if a thread panicked while holding it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
 }
 
 impl SystemProvider for System {
@@ -70,6 +408,37 @@ impl SystemProvider for System {
         Snapshot::from_batch(&unparsed_snapshots)
     }
 
+    fn get_all_datasets(&self) -> Result<Vec<String>, String> {
+        // Example
+        // -----------
+        // zfs list -H -o name,type
+        let output = Command::new("zfs")
+            .arg("list")
+            .arg("-H")
+            .arg("-o")
+            .arg("name,type")
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("failed to execute 'zfs list': {}", e))?;
+
+        if !output.status.success() {
+            return Err(Self::describe_failure(
+                "zfs list",
+                &String::from_utf8_lossy(&output.stderr),
+            ));
+        }
+
+        let mut datasets = Vec::new();
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            if let Some((name, kind)) = line.split_once('\t') {
+                if matches!(kind.trim(), "filesystem" | "volume") {
+                    datasets.push(String::from(name));
+                }
+            }
+        }
+        Ok(datasets)
+    }
+
     fn is_pool_imported(&self, pool_name: &str) -> bool {
         // Example
         // -----------
@@ -85,27 +454,159 @@ impl SystemProvider for System {
         status.success()
     }
 
-    fn send_full_backup(&self, latest_snapshot: &str, backup_dataset: &str) -> bool {
+    fn import_pool(
+        &self,
+        pool_name: &str,
+        guid: Option<u64>,
+        altroot: Option<&Path>,
+        readonly: bool,
+    ) -> Result<(), String> {
         // Example
         // -----------
-        // zfs send -p tank/ROOT/default@2022-09-27-0935-05-CHECKPOINT | zfs recv -vF backup/tank/ROOT/default
-        let sender = Command::new("zfs")
-            .stdout(Stdio::piped())
-            .arg("send")
+        // zpool import -N -R /mnt/backup 1234567890123456789 backup
+        let mut command = Command::new("zpool");
+        command.arg("import").arg("-N");
+        if let Some(altroot) = altroot {
+            command.arg("-R").arg(altroot);
+        }
+        if readonly {
+            command.arg("-o").arg("readonly=on");
+        }
+        match guid {
+            Some(guid) => command.arg(guid.to_string()).arg(pool_name),
+            None => command.arg(pool_name),
+        };
+        Self::run_command(command, "zpool import")
+    }
+
+    fn export_pool(&self, pool_name: &str) -> Result<(), String> {
+        // Example
+        // -----------
+        // zpool export backup
+        let mut command = Command::new("zpool");
+        command.arg("export").arg(pool_name);
+        Self::run_command(command, "zpool export")
+    }
+
+    fn get_pool_guid(&self, pool_name: &str) -> Result<u64, String> {
+        // Example
+        // -----------
+        // zpool get -H -p -o value guid backup
+        let output = Command::new("zpool")
+            .arg("get")
+            .arg("-H")
             .arg("-p")
-            .arg(latest_snapshot)
-            .spawn()
-            .expect("failed to execute process");
+            .arg("-o")
+            .arg("value")
+            .arg("guid")
+            .arg(pool_name)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("failed to execute 'zpool get': {}", e))?;
 
-        let receiver = Command::new("zfs")
-            .stdin(sender.stdout.unwrap())
-            .arg("recv")
-            .arg("-vF")
-            .arg(backup_dataset)
+        if !output.status.success() {
+            return Err(Self::describe_failure(
+                "zpool get",
+                &String::from_utf8_lossy(&output.stderr),
+            ));
+        }
+
+        let guid = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        guid.parse()
+            .map_err(|_| format!("'zpool get' returned an invalid GUID: '{}'", guid))
+    }
+
+    fn get_pool_health(&self, pool_name: &str) -> Result<PoolHealth, String> {
+        // Example
+        // -----------
+        // zpool list -H -p -o health,capacity,fragmentation backup
+        let output = Command::new("zpool")
+            .arg("list")
+            .arg("-H")
+            .arg("-p")
+            .arg("-o")
+            .arg("health,capacity,fragmentation")
+            .arg(pool_name)
+            .stdin(Stdio::null())
             .output()
-            .expect("failed to execute process");
+            .map_err(|e| format!("failed to execute 'zpool list': {}", e))?;
 
-        receiver.status.success()
+        if !output.status.success() {
+            return Err(Self::describe_failure(
+                "zpool list",
+                &String::from_utf8_lossy(&output.stderr),
+            ));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let fields: Vec<&str> = stdout.trim().split('\t').collect();
+        let capacity = fields
+            .get(1)
+            .and_then(|field| field.trim_end_matches('%').parse().ok());
+        match (fields.first(), capacity) {
+            (Some(health), Some(capacity)) if !health.is_empty() => Ok(PoolHealth {
+                pool: String::from(pool_name),
+                health: String::from(*health),
+                capacity,
+                // Pools without the spacemap_histogram feature report '-'.
+                fragmentation: fields
+                    .get(2)
+                    .and_then(|field| field.trim_end_matches('%').parse().ok()),
+            }),
+            _ => Err(format!(
+                "'zpool list' returned unexpected output: '{}'",
+                stdout.trim()
+            )),
+        }
+    }
+
+    fn start_scrub(&self, pool_name: &str) -> Result<(), String> {
+        // Example
+        // -----------
+        // zpool scrub backup
+        let mut command = Command::new("zpool");
+        command.arg("scrub").arg(pool_name);
+        Self::run_command(command, "zpool scrub")
+    }
+
+    fn get_scrub_status(&self, pool_name: &str) -> Result<ScrubStatus, String> {
+        // Example
+        // -----------
+        // zpool status -p backup
+        let output = Command::new("zpool")
+            .arg("status")
+            .arg("-p")
+            .arg(pool_name)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("failed to execute 'zpool status': {}", e))?;
+
+        if !output.status.success() {
+            return Err(Self::describe_failure(
+                "zpool status",
+                &String::from_utf8_lossy(&output.stderr),
+            ));
+        }
+        pools::parse_scrub_status(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    fn send_full_backup(
+        &self,
+        latest_snapshot: &str,
+        backup_dataset: &str,
+        options: &SendOptions,
+    ) -> Result<u64, String> {
+        // Example
+        // -----------
+        // zfs send -p tank/ROOT/default@2022-09-27-0935-05-CHECKPOINT | zfs recv -vF backup/tank/ROOT/default
+        let mut sender = Command::new("zfs");
+        sender
+            .arg("send")
+            .arg("-p")
+            .args(&options.send_flags)
+            .arg(latest_snapshot);
+
+        self.transfer(sender, backup_dataset, options)
     }
 
     fn send_incremental_backup(
@@ -113,31 +614,73 @@ impl SystemProvider for System {
         common_snapshot: &str,
         latest_snapshot: &str,
         backup_dataset: &str,
-    ) -> bool {
+        options: &SendOptions,
+    ) -> Result<u64, String> {
         // Example
         // -----------
         // zfs send -i \
         // tank/ROOT/default@2022-09-27-0935-05-CHECKPOINT \
         // tank/ROOT/default@2022-09-28-0935-05-CHECKPOINT | \
         // zfs recv -vF backup/tank/ROOT/default
-        let sender = Command::new("zfs")
-            .stdout(Stdio::piped())
+        let mut sender = Command::new("zfs");
+        sender
             .arg("send")
+            .args(&options.send_flags)
             .arg("-i")
             .arg(common_snapshot)
-            .arg(latest_snapshot)
-            .spawn()
-            .expect("failed to execute process");
+            .arg(latest_snapshot);
 
-        let receiver = Command::new("zfs")
-            .stdin(sender.stdout.unwrap())
-            .arg("recv")
-            .arg("-vF")
-            .arg(backup_dataset)
-            .output()
-            .expect("failed to execute process");
+        self.transfer(sender, backup_dataset, options)
+    }
+
+    fn send_backup_to_many(
+        &self,
+        ancestor_snapshot: Option<&str>,
+        latest_snapshot: &str,
+        backup_datasets: &[String],
+        options: &SendOptions,
+    ) -> Vec<Result<u64, String>> {
+        // Example
+        // -----------
+        // zfs send -i \
+        // tank/ROOT/default@2022-09-27-0935-05-CHECKPOINT \
+        // tank/ROOT/default@2022-09-28-0935-05-CHECKPOINT | \
+        // tee >(zfs recv -vF usb1/tank/ROOT/default) | \
+        // zfs recv -vF usb2/tank/ROOT/default
+        let mut sender = Command::new("zfs");
+        sender.arg("send");
+        match ancestor_snapshot {
+            Some(ancestor_snapshot) => {
+                sender
+                    .args(&options.send_flags)
+                    .arg("-i")
+                    .arg(ancestor_snapshot);
+            }
+            None => {
+                sender.arg("-p").args(&options.send_flags);
+            }
+        }
+        sender.arg(latest_snapshot);
+
+        self.transfer_to_many(sender, backup_datasets, options)
+    }
+
+    fn resume_backup(
+        &self,
+        token: &str,
+        backup_dataset: &str,
+        options: &SendOptions,
+    ) -> Result<u64, String> {
+        // Example
+        // -----------
+        // zfs send -t 1-c740b4779-f8-789c636064000310a500c4ec50360710e72765a526973030... | \
+        // zfs recv -vF -s backup/tank/ROOT/default
+        //
+        // The token already carries the flags of the interrupted send.
+        let mut sender = Command::new("zfs");
+        sender.arg("send").arg("-t").arg(token);
 
-        receiver.status.success()
+        self.transfer(sender, backup_dataset, options)
     }
 
     fn create_dataset_tree_if_needed(&self, backup_dataset: &str) -> bool {
@@ -155,4 +698,145 @@ impl SystemProvider for System {
 
         status.success()
     }
+
+    fn destroy_snapshot(&self, snapshot: &str) -> bool {
+        // Example
+        // -----------
+        // zfs destroy backup/tank/ROOT/default@2022-09-27-0935-05-CHECKPOINT
+        let status = Command::new("zfs")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .arg("destroy")
+            .arg(snapshot)
+            .status()
+            .expect("failed to execute process");
+
+        status.success()
+    }
+
+    fn create_snapshots(&self, snapshots: &[String], recursive: bool) -> Result<(), String> {
+        // Example
+        // -----------
+        // zfs snapshot -r \
+        // tank/ROOT/default@2022-09-27-0935-05-CHECKPOINT \
+        // tank/var/log@2022-09-27-0935-05-CHECKPOINT
+        //
+        // All of the snapshots given in a single call are taken atomically.
+        let mut command = Command::new("zfs");
+        command.arg("snapshot");
+        if recursive {
+            command.arg("-r");
+        }
+        let output = command
+            .args(snapshots)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("failed to execute 'zfs snapshot': {}", e))?;
+
+        if !output.status.success() {
+            return Err(Self::describe_failure(
+                "zfs snapshot",
+                &String::from_utf8_lossy(&output.stderr),
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_snapshot_guids(&self, dataset: &str) -> Result<SnapshotGuids, String> {
+        // Example
+        // -----------
+        // zfs list -H -p -t snapshot -o name,guid -s createtxg -d 1 tank/ROOT/default
+        let output = Command::new("zfs")
+            .arg("list")
+            .arg("-H")
+            .arg("-p")
+            .arg("-t")
+            .arg("snapshot")
+            .arg("-o")
+            .arg("name,guid")
+            .arg("-s")
+            .arg("createtxg")
+            .arg("-d")
+            .arg("1")
+            .arg(dataset)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("failed to execute 'zfs list': {}", e))?;
+
+        if !output.status.success() {
+            return Err(Self::describe_failure(
+                "zfs list",
+                &String::from_utf8_lossy(&output.stderr),
+            ));
+        }
+
+        let mut snapshots = Vec::new();
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            if let Some((name, guid)) = line.split_once('\t') {
+                if let Ok(guid) = guid.trim().parse() {
+                    snapshots.push((String::from(name), guid));
+                }
+            }
+        }
+        Ok(snapshots)
+    }
+
+    fn get_resume_token(&self, dataset: &str) -> Option<String> {
+        // Example
+        // -----------
+        // zfs get -H -o value receive_resume_token backup/tank/ROOT/default
+        let output = Command::new("zfs")
+            .arg("get")
+            .arg("-H")
+            .arg("-o")
+            .arg("value")
+            .arg("receive_resume_token")
+            .arg(dataset)
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+
+        let token = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !output.status.success() || token.is_empty() || token == "-" {
+            return None;
+        }
+        Some(token)
+    }
+
+    fn get_held_snapshots(&self, dataset: &str) -> Result<Vec<String>, String> {
+        // Example
+        // -----------
+        // zfs list -H -p -t snapshot -o name,userrefs -d 1 backup/tank/ROOT/default
+        let output = Command::new("zfs")
+            .arg("list")
+            .arg("-H")
+            .arg("-p")
+            .arg("-t")
+            .arg("snapshot")
+            .arg("-o")
+            .arg("name,userrefs")
+            .arg("-d")
+            .arg("1")
+            .arg(dataset)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("failed to execute 'zfs list': {}", e))?;
+
+        if !output.status.success() {
+            return Err(Self::describe_failure(
+                "zfs list",
+                &String::from_utf8_lossy(&output.stderr),
+            ));
+        }
+
+        let mut held = Vec::new();
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            if let Some((name, holds)) = line.split_once('\t') {
+                if holds.trim().parse::<u64>().is_ok_and(|holds| holds > 0) {
+                    held.push(String::from(name));
+                }
+            }
+        }
+        Ok(held)
+    }
 }
@@ -0,0 +1,575 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+use std::time::Duration;
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::pools::PoolHealth;
+use crate::retry::ErrorClass;
+
+// Bump this whenever a field is renamed or removed, or its meaning changes.
+// Adding new fields is not considered a breaking change.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Jsonl,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RunConfig {
+    pub job: Option<String>,
+    // The first backup pool, kept for consumers that only know about one.
+    pub backup_pool: String,
+    pub backup_pools: Vec<String>,
+    pub label: String,
+    pub datasets: Vec<String>,
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Planned,
+    UpToDate,
+    Success,
+    Skipped,
+    Failed,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Planned => "planned",
+            Outcome::UpToDate => "up_to_date",
+            Outcome::Success => "success",
+            Outcome::Skipped => "skipped",
+            Outcome::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ActionKind {
+    CreateDatasetTree {
+        backup_dataset: String,
+    },
+    SendFull {
+        snapshot: String,
+        backup_dataset: String,
+    },
+    SendIncremental {
+        from: String,
+        to: String,
+        backup_dataset: String,
+    },
+    Prune {
+        snapshot: String,
+    },
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ActionReport {
+    #[serde(flatten)]
+    pub kind: ActionKind,
+    pub executed: bool,
+    pub success: bool,
+    pub bytes_sent: u64,
+    pub duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // Every attempt at a send that was retried, the last one included. The
+    // fields above describe the last one.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attempts: Vec<AttemptReport>,
+}
+
+// A single attempt at a send that was retried.
+#[derive(Clone, Debug, Serialize)]
+pub struct AttemptReport {
+    // Whether it picked up where the attempt before it left off.
+    pub resumed: bool,
+    pub success: bool,
+    pub duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_class: Option<ErrorClass>,
+    // How long we waited before the next attempt.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_delay_ms: Option<u128>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DatasetReport {
+    pub dataset: String,
+    pub backup_dataset: String,
+    pub source_snapshots: usize,
+    pub backup_snapshots: usize,
+    pub latest_snapshot: Option<String>,
+    pub common_snapshot: Option<String>,
+    // How far the backup was behind the source before this run, in seconds.
+    pub replication_lag_seconds: Option<i64>,
+    pub outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub actions: Vec<ActionReport>,
+    // Backup snapshots that the retention policy would prune, but that were
+    // kept since they are held.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub held_snapshots: Vec<String>,
+    pub bytes_sent: u64,
+    pub duration_ms: u128,
+    pub errors: Vec<String>,
+}
+
+impl DatasetReport {
+    pub fn new(dataset: &str, backup_dataset: &str) -> Self {
+        Self {
+            dataset: String::from(dataset),
+            backup_dataset: String::from(backup_dataset),
+            source_snapshots: 0,
+            backup_snapshots: 0,
+            latest_snapshot: None,
+            common_snapshot: None,
+            replication_lag_seconds: None,
+            outcome: Outcome::Planned,
+            reason: None,
+            actions: Vec::new(),
+            held_snapshots: Vec::new(),
+            bytes_sent: 0,
+            duration_ms: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, action: ActionReport) {
+        self.bytes_sent += action.bytes_sent;
+        if let Some(error) = &action.error {
+            self.errors.push(error.clone());
+        }
+        self.actions.push(action);
+    }
+
+    pub fn finish(&mut self, outcome: Outcome, reason: Option<&str>, elapsed: Duration) {
+        self.outcome = outcome;
+        self.reason = reason.map(String::from);
+        self.duration_ms = elapsed.as_millis();
+    }
+}
+
+// The result of scrubbing a backup pool after the run.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ScrubReport {
+    pub pool: String,
+    pub repaired_bytes: u64,
+    // Unrecoverable errors the scrub found.
+    pub errors: u64,
+    pub duration_ms: u128,
+}
+
+// The JSON document of a run of all of the jobs of a configuration file.
+#[derive(Clone, Debug, Serialize)]
+pub struct JobsReport {
+    pub schema_version: u32,
+    pub version: String,
+    pub jobs: Vec<RunReport>,
+}
+
+impl JobsReport {
+    pub fn new(jobs: Vec<RunReport>) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            version: String::from(clap::crate_version!()),
+            jobs,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RunReport {
+    pub schema_version: u32,
+    pub version: String,
+    pub config: RunConfig,
+    pub total_snapshots: usize,
+    pub created_snapshots: Vec<String>,
+    // The health of the source and backup pools before anything was sent.
+    pub pools: Vec<PoolHealth>,
+    pub datasets: Vec<DatasetReport>,
+    pub scrubs: Vec<ScrubReport>,
+    pub bytes_sent: u64,
+    pub duration_ms: u128,
+    pub errors: Vec<String>,
+    // Problems that didn't stop the run, such as nearly full pools.
+    pub warnings: Vec<String>,
+    pub success: bool,
+}
+
+// The result of only taking snapshots, without replicating them.
+#[derive(Clone, Debug, Serialize)]
+pub struct SnapshotReport {
+    pub schema_version: u32,
+    pub version: String,
+    pub label: String,
+    pub created_snapshots: Vec<String>,
+    pub errors: Vec<String>,
+    pub success: bool,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    RunStarted {
+        schema_version: u32,
+        version: &'a str,
+        config: &'a RunConfig,
+    },
+    SnapshotsCreated {
+        schema_version: u32,
+        snapshots: &'a [String],
+    },
+    SnapshotsListed {
+        schema_version: u32,
+        total_snapshots: usize,
+    },
+    Action {
+        schema_version: u32,
+        dataset: &'a str,
+        #[serde(flatten)]
+        action: &'a ActionReport,
+    },
+    DatasetFinished {
+        schema_version: u32,
+        #[serde(flatten)]
+        dataset: &'a DatasetReport,
+    },
+    ScrubFinished {
+        schema_version: u32,
+        #[serde(flatten)]
+        scrub: &'a ScrubReport,
+    },
+    RunFinished {
+        #[serde(flatten)]
+        report: &'a RunReport,
+    },
+}
+
+// Collects everything that happens during a run and renders it in the
+// requested output format. In text mode the human readable messages are
+// printed as they happen, in JSON mode a single document is printed at the
+// end, and in JSON Lines mode one event is printed per line as it happens.
+pub struct Reporter {
+    format: OutputFormat,
+    report: RunReport,
+    // Put in front of every line of text, to tell apart the output of work
+    // that runs alongside other work.
+    prefix: String,
+}
+
+impl Reporter {
+    pub fn new(format: OutputFormat, config: RunConfig) -> Self {
+        let reporter = Self {
+            format,
+            report: RunReport {
+                schema_version: SCHEMA_VERSION,
+                version: String::from(clap::crate_version!()),
+                config,
+                total_snapshots: 0,
+                created_snapshots: Vec::new(),
+                pools: Vec::new(),
+                datasets: Vec::new(),
+                scrubs: Vec::new(),
+                bytes_sent: 0,
+                duration_ms: 0,
+                errors: Vec::new(),
+                warnings: Vec::new(),
+                success: true,
+            },
+            prefix: String::new(),
+        };
+        reporter.emit(&Event::RunStarted {
+            schema_version: SCHEMA_VERSION,
+            version: &reporter.report.version,
+            config: &reporter.report.config,
+        });
+        reporter
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.format == OutputFormat::Text
+    }
+
+    pub fn is_forked(&self) -> bool {
+        !self.prefix.is_empty()
+    }
+
+    // Prints a human readable message. Ignored by the machine readable formats.
+    pub fn text(&self, message: &str) {
+        if !self.is_text() {
+            return;
+        }
+        if self.prefix.is_empty() {
+            println!("{}", message);
+            return;
+        }
+        for line in message.lines().filter(|line| !line.is_empty()) {
+            println!("{}{}", self.prefix, line);
+        }
+    }
+
+    // Gets a reporter for work that runs alongside other work. Its text is
+    // prefixed and its events are emitted as they happen, while what it
+    // records is added to this reporter by 'merge'.
+    pub fn fork(&self, prefix: &str) -> Self {
+        let mut report = self.report.clone();
+        report.datasets.clear();
+        report.errors.clear();
+        report.warnings.clear();
+        report.bytes_sent = 0;
+        report.success = true;
+        Self {
+            format: self.format,
+            report,
+            prefix: String::from(prefix),
+        }
+    }
+
+    pub fn merge(&mut self, other: Reporter) {
+        let report = other.report;
+        self.report.datasets.extend(report.datasets);
+        self.report.errors.extend(report.errors);
+        self.report.warnings.extend(report.warnings);
+        self.report.bytes_sent += report.bytes_sent;
+        self.report.success &= report.success;
+    }
+
+    pub fn snapshots_created(&mut self, snapshots: &[String]) {
+        self.report.created_snapshots.extend_from_slice(snapshots);
+        self.emit(&Event::SnapshotsCreated {
+            schema_version: SCHEMA_VERSION,
+            snapshots,
+        });
+    }
+
+    pub fn snapshots_listed(&mut self, total_snapshots: usize) {
+        self.report.total_snapshots = total_snapshots;
+        self.emit(&Event::SnapshotsListed {
+            schema_version: SCHEMA_VERSION,
+            total_snapshots,
+        });
+    }
+
+    pub fn action(&self, dataset: &str, action: &ActionReport) {
+        self.emit(&Event::Action {
+            schema_version: SCHEMA_VERSION,
+            dataset,
+            action,
+        });
+    }
+
+    pub fn dataset_finished(&mut self, dataset: DatasetReport) {
+        self.emit(&Event::DatasetFinished {
+            schema_version: SCHEMA_VERSION,
+            dataset: &dataset,
+        });
+        if dataset.outcome == Outcome::Failed || !dataset.errors.is_empty() {
+            self.report.success = false;
+        }
+        self.report.bytes_sent += dataset.bytes_sent;
+        self.report.datasets.push(dataset);
+    }
+
+    pub fn scrub_finished(&mut self, scrub: ScrubReport) {
+        self.emit(&Event::ScrubFinished {
+            schema_version: SCHEMA_VERSION,
+            scrub: &scrub,
+        });
+        self.report.scrubs.push(scrub);
+    }
+
+    pub fn pool_checked(&mut self, health: PoolHealth) {
+        self.report.pools.push(health);
+    }
+
+    pub fn warning(&mut self, message: &str) {
+        self.text(message);
+        self.report.warnings.push(String::from(message));
+    }
+
+    pub fn error(&mut self, message: &str) {
+        self.text(message);
+        self.report.errors.push(String::from(message));
+        self.report.success = false;
+    }
+
+    pub fn report(&self) -> &RunReport {
+        &self.report
+    }
+
+    // Finalizes the run and prints the last event in JSON Lines mode. Returns
+    // the finished report, which is up to the caller to print in JSON mode
+    // since several of them may end up in a single document.
+    pub fn finish(mut self, elapsed: Duration) -> RunReport {
+        self.report.duration_ms = elapsed.as_millis();
+        self.emit(&Event::RunFinished {
+            report: &self.report,
+        });
+        self.report
+    }
+
+    fn emit(&self, event: &Event) {
+        if self.format == OutputFormat::Jsonl {
+            println!(
+                "{}",
+                serde_json::to_string(event).expect("event is serializable")
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_example_config() -> RunConfig {
+        RunConfig {
+            job: None,
+            backup_pool: String::from("backup"),
+            backup_pools: vec![String::from("backup")],
+            label: String::from("TEST"),
+            datasets: vec![String::from("tank/var/log")],
+            dry_run: false,
+        }
+    }
+
+    fn get_example_action(success: bool) -> ActionReport {
+        ActionReport {
+            kind: ActionKind::SendIncremental {
+                from: String::from("tank/var/log@2021-06-03-1800-00-TEST"),
+                to: String::from("tank/var/log@2022-10-05-1953-12-TEST"),
+                backup_dataset: String::from("backup/tank/var/log"),
+            },
+            executed: true,
+            success,
+            bytes_sent: 1024,
+            duration_ms: 5,
+            error: if success {
+                None
+            } else {
+                Some(String::from("'zfs recv' failed"))
+            },
+            attempts: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_dataset_report_record_should_accumulate_bytes_and_errors() {
+        let mut dataset = DatasetReport::new("tank/var/log", "backup/tank/var/log");
+
+        dataset.record(get_example_action(true));
+        dataset.record(get_example_action(false));
+
+        assert_eq!(dataset.actions.len(), 2);
+        assert_eq!(dataset.bytes_sent, 2048);
+        assert_eq!(dataset.errors, vec!["'zfs recv' failed"]);
+    }
+
+    #[test]
+    fn test_reporter_should_mark_run_failed_when_dataset_fails() {
+        let mut reporter = Reporter::new(OutputFormat::Text, get_example_config());
+        let mut dataset = DatasetReport::new("tank/var/log", "backup/tank/var/log");
+        dataset.record(get_example_action(false));
+        dataset.finish(Outcome::Failed, None, Duration::from_millis(10));
+
+        reporter.dataset_finished(dataset);
+        let report = reporter.finish(Duration::from_millis(20));
+
+        assert!(!report.success);
+        assert_eq!(report.bytes_sent, 1024);
+        assert_eq!(report.duration_ms, 20);
+    }
+
+    #[test]
+    fn test_merge_should_add_what_the_forked_reporter_recorded() {
+        let mut reporter = Reporter::new(OutputFormat::Text, get_example_config());
+        let mut fork = reporter.fork("[tank/var/log] ");
+        let mut dataset = DatasetReport::new("tank/var/log", "backup/tank/var/log");
+        dataset.record(get_example_action(false));
+        dataset.finish(Outcome::Failed, None, Duration::from_millis(10));
+
+        fork.dataset_finished(dataset);
+        reporter.merge(fork);
+        let report = reporter.finish(Duration::from_millis(20));
+
+        assert!(!report.success);
+        assert_eq!(report.datasets.len(), 1);
+        assert_eq!(report.bytes_sent, 1024);
+    }
+
+    #[test]
+    fn test_action_report_should_serialize_with_stable_field_names() {
+        let value = serde_json::to_value(get_example_action(true)).unwrap();
+
+        assert_eq!(value["action"], "send_incremental");
+        assert_eq!(value["from"], "tank/var/log@2021-06-03-1800-00-TEST");
+        assert_eq!(value["to"], "tank/var/log@2022-10-05-1953-12-TEST");
+        assert_eq!(value["backup_dataset"], "backup/tank/var/log");
+        assert_eq!(value["bytes_sent"], 1024);
+        assert!(value.get("error").is_none());
+    }
+
+    #[test]
+    fn test_run_report_should_include_schema_version() {
+        let reporter = Reporter::new(OutputFormat::Text, get_example_config());
+
+        let value = serde_json::to_value(reporter.report()).unwrap();
+
+        assert_eq!(value["schema_version"], SCHEMA_VERSION);
+        assert_eq!(value["config"]["backup_pool"], "backup");
+        assert_eq!(value["config"]["datasets"][0], "tank/var/log");
+    }
+
+    #[test]
+    fn test_jobs_report_should_hold_every_report_in_one_document() {
+        let reports = ["nightly", "weekly"].map(|job| {
+            let config = RunConfig {
+                job: Some(String::from(job)),
+                ..get_example_config()
+            };
+            Reporter::new(OutputFormat::Json, config).finish(Duration::ZERO)
+        });
+
+        let value = serde_json::to_value(JobsReport::new(reports.to_vec())).unwrap();
+
+        assert_eq!(value["schema_version"], SCHEMA_VERSION);
+        assert_eq!(value["jobs"][0]["config"]["job"], "nightly");
+        assert_eq!(value["jobs"][1]["config"]["job"], "weekly");
+    }
+}
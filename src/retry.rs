@@ -0,0 +1,222 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Serialize;
+
+// What kind of failure an error is, judging by the messages of 'zfs send',
+// 'zfs recv' and of the pipeline between them. Decides whether a failed send
+// is worth trying again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    // The dataset or pool is in use by something else for now.
+    Busy,
+    // The stream was cut short, e.g. by a dropped connection.
+    Connection,
+    // No data was transferred for too long.
+    Stalled,
+    // The backup pool is full. Never retried.
+    NoSpace,
+    // The run was interrupted or the dataset ran out of time. Never retried.
+    Stopped,
+    Other,
+}
+
+// Checked in order, against the lowercased error.
+const PATTERNS: &[(ErrorClass, &[&str])] = &[
+    (
+        ErrorClass::Stopped,
+        &["the run was interrupted", "ran out of time"],
+    ),
+    (ErrorClass::Stalled, &["no data was transferred"]),
+    (
+        ErrorClass::NoSpace,
+        &["out of space", "no space left", "quota exceeded"],
+    ),
+    (
+        ErrorClass::Busy,
+        &["dataset is busy", "pool is busy", "resource busy"],
+    ),
+    (
+        ErrorClass::Connection,
+        &[
+            "broken pipe",
+            "connection reset",
+            "connection refused",
+            "connection timed out",
+            "connection closed",
+            "network is unreachable",
+            "host is unreachable",
+            "incomplete stream",
+        ],
+    ),
+];
+
+impl ErrorClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorClass::Busy => "busy",
+            ErrorClass::Connection => "connection",
+            ErrorClass::Stalled => "stalled",
+            ErrorClass::NoSpace => "no_space",
+            ErrorClass::Stopped => "stopped",
+            ErrorClass::Other => "other",
+        }
+    }
+
+    // Retrying can't help when the pool is full, and would defeat the point
+    // of stopping.
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, ErrorClass::NoSpace | ErrorClass::Stopped)
+    }
+}
+
+impl FromStr for ErrorClass {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        [
+            ErrorClass::Busy,
+            ErrorClass::Connection,
+            ErrorClass::Stalled,
+            ErrorClass::NoSpace,
+            ErrorClass::Stopped,
+            ErrorClass::Other,
+        ]
+        .into_iter()
+        .find(|class| class.as_str() == value)
+        .ok_or_else(|| format!("'{}' is not an error class", value))
+    }
+}
+
+pub fn classify(error: &str) -> ErrorClass {
+    let error = error.to_lowercase();
+    PATTERNS
+        .iter()
+        .find(|(_, patterns)| patterns.iter().any(|pattern| error.contains(pattern)))
+        .map_or(ErrorClass::Other, |(class, _)| *class)
+}
+
+// How often and how patiently a failed send is tried again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    // On top of the first attempt.
+    pub retries: u32,
+    // Before the first retry. Doubles with every retry after it.
+    pub delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            delay: Duration::from_secs(30),
+            max_delay: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // How long to wait before the given retry (the first one is 1). The
+    // jitter, between 0 and 1, spreads the delay over its upper half so that
+    // sends that failed together don't all retry at the same moment.
+    pub fn get_delay(&self, retry: u32, jitter: f64) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        let delay = self.delay.saturating_mul(factor).min(self.max_delay);
+        delay.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+    }
+}
+
+// The retry policies of a job, by the class of the error they apply to.
+// Errors of other classes fail right away.
+pub type RetryPolicies = BTreeMap<ErrorClass, RetryPolicy>;
+
+// A random number between 0 and 1. Every hasher built by 'RandomState' has
+// its own random keys, which is all the randomness jitter needs.
+pub fn get_jitter() -> f64 {
+    RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_should_recognize_zfs_errors() {
+        assert_eq!(
+            classify("'zfs recv' failed: cannot receive incremental stream: dataset is busy"),
+            ErrorClass::Busy
+        );
+        assert_eq!(
+            classify("'zfs send' failed: warning: cannot send 'tank/os@a': Broken pipe"),
+            ErrorClass::Connection
+        );
+        assert_eq!(
+            classify("'zfs recv' failed: cannot receive new filesystem stream: out of space"),
+            ErrorClass::NoSpace
+        );
+        assert_eq!(
+            classify("no data was transferred for 10m, so 'zfs send' and 'zfs recv' were stopped"),
+            ErrorClass::Stalled
+        );
+        assert_eq!(
+            classify("the run was interrupted, so 'zfs send' and 'zfs recv' were stopped"),
+            ErrorClass::Stopped
+        );
+        assert_eq!(classify("'zfs recv' failed"), ErrorClass::Other);
+    }
+
+    #[test]
+    fn test_get_delay_should_back_off_exponentially_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            retries: 10,
+            delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(60),
+        };
+
+        assert_eq!(policy.get_delay(1, 1.0), Duration::from_secs(10));
+        assert_eq!(policy.get_delay(2, 1.0), Duration::from_secs(20));
+        assert_eq!(policy.get_delay(3, 1.0), Duration::from_secs(40));
+        assert_eq!(policy.get_delay(4, 1.0), Duration::from_secs(60));
+        assert_eq!(policy.get_delay(40, 1.0), Duration::from_secs(60));
+        assert_eq!(policy.get_delay(2, 0.0), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_error_class_should_parse_its_own_names() {
+        assert_eq!("busy".parse(), Ok(ErrorClass::Busy));
+        assert_eq!("no_space".parse(), Ok(ErrorClass::NoSpace));
+        assert!("flaky".parse::<ErrorClass>().is_err());
+        assert!(!ErrorClass::NoSpace.is_retryable());
+        assert!(ErrorClass::Connection.is_retryable());
+    }
+}
@@ -0,0 +1,809 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use chrono::{DateTime, Local, NaiveDateTime};
+
+use crate::catalog;
+use crate::config::{Job, SnapshotOptions};
+use crate::executor::{self, ExecuteOptions};
+use crate::helpers;
+use crate::hooks::{HookPoint, HookRunner};
+use crate::interrupt;
+use crate::plan::{self, Plan, SavedPlan};
+use crate::pools::{self, PoolHealth, ScrubOptions};
+use crate::report::{OutputFormat, Reporter, RunConfig, RunReport, ScrubReport};
+use crate::snapshot::Snapshot;
+use crate::traits::SystemProvider;
+
+// Takes a snapshot of each dataset named after the current time. Returns the
+// snapshots that were taken (or would be, in a dry run) and any errors.
+pub fn take_snapshots(
+    system: &impl SystemProvider,
+    text: &dyn Fn(&str),
+    datasets: &[String],
+    label: &str,
+    options: &SnapshotOptions,
+    dry_run: bool,
+) -> (Vec<String>, Vec<String>) {
+    let snapshots = get_snapshot_names(datasets, label, &Local::now().naive_local());
+
+    // Either a single batch with every snapshot, or one batch per snapshot.
+    let batches: Vec<&[String]> = if options.atomic {
+        vec![&snapshots]
+    } else {
+        snapshots.chunks(1).collect()
+    };
+
+    let mut created = Vec::new();
+    let mut errors = Vec::new();
+    for batch in batches {
+        for snapshot in batch {
+            text(&format!(
+                "Taking {}snapshot {} ...",
+                if options.recursive { "recursive " } else { "" },
+                snapshot
+            ));
+        }
+        if dry_run {
+            created.extend_from_slice(batch);
+            continue;
+        }
+        match system.create_snapshots(batch, options.recursive) {
+            Ok(()) => created.extend_from_slice(batch),
+            Err(error) => {
+                let error = format!("Failed to take snapshot(s) {}. {}", batch.join(", "), error);
+                text(&error);
+                errors.push(error);
+            }
+        }
+    }
+    (created, errors)
+}
+
+// Names a new snapshot of each dataset after the given time.
+pub fn get_snapshot_names(datasets: &[String], label: &str, now: &NaiveDateTime) -> Vec<String> {
+    datasets
+        .iter()
+        .map(|dataset| helpers::get_snapshot_name(dataset, label, now))
+        .collect()
+}
+
+// Runs a single job. Jobs from a configuration file also record what ends
+// up on each backup disk in the catalog under the given state directory.
+pub fn run_job(
+    system: &impl SystemProvider,
+    output: OutputFormat,
+    dry_run: bool,
+    name: Option<&str>,
+    job: &Job,
+    state_dir: Option<&Path>,
+) -> RunReport {
+    run(system, output, dry_run, name, job, state_dir, None)
+}
+
+// Applies a plan that was saved by 'plan' to the job it was made for, once
+// its backup pools and datasets are verified to still be as they were.
+pub fn apply_plan(
+    system: &impl SystemProvider,
+    output: OutputFormat,
+    dry_run: bool,
+    job: &Job,
+    saved: &SavedPlan,
+    state_dir: Option<&Path>,
+) -> RunReport {
+    run(
+        system,
+        output,
+        dry_run,
+        Some(&saved.job),
+        job,
+        state_dir,
+        Some(saved),
+    )
+}
+
+// Plans the job without changing anything, recording the state of the pools
+// and datasets that the plan is based on.
+pub fn create_plan(
+    system: &impl SystemProvider,
+    name: &str,
+    job: &Job,
+    now: DateTime<Local>,
+    warn: &dyn Fn(&str),
+) -> Result<SavedPlan, String> {
+    let (plan, _) = get_plan(system, job, &[], warn)?;
+    SavedPlan::new(system, name, job, plan, &now.to_rfc3339())
+}
+
+// Plans the job from the snapshots that exist right now, plus the given
+// snapshots that will be taken before the plan runs, once its pools pass the
+// same checks as a run. Returns the plan with the GUIDs of the backup pools.
+pub fn get_plan(
+    system: &impl SystemProvider,
+    job: &Job,
+    new_snapshots: &[String],
+    warn: &dyn Fn(&str),
+) -> Result<(Plan, Vec<(String, u64)>), String> {
+    let pool_guids = check_job_pools(system, job, warn)?;
+
+    let mut snapshots = system.get_all_snapshots();
+    snapshots.extend(new_snapshots.iter().map(|snapshot| Snapshot::new(snapshot)));
+    let backup_pools: Vec<&str> = job.backup_pools.iter().map(String::as_str).collect();
+    let plan = plan::plan_job(system, job, &snapshots, &backup_pools);
+    Ok((plan, pool_guids))
+}
+
+// Puts the pools of the job through the same checks as a run, passing its
+// warnings on. Every backup pool must be usable, since a plan that is made
+// or applied ahead of time covers all of them. Returns the GUIDs of the
+// backup pools that could be read.
+pub fn check_job_pools(
+    system: &impl SystemProvider,
+    job: &Job,
+    warn: &dyn Fn(&str),
+) -> Result<Vec<(String, u64)>, String> {
+    let preflight = check_pools(system, job);
+    let mut errors = Vec::new();
+    for finding in preflight.findings {
+        match finding {
+            Finding::Health(_) => {}
+            Finding::Warning(warning) => warn(&warning),
+            Finding::Unusable(error) | Finding::Fatal(error) => errors.push(error),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    Ok(preflight.pool_guids)
+}
+
+fn run(
+    system: &impl SystemProvider,
+    output: OutputFormat,
+    dry_run: bool,
+    name: Option<&str>,
+    job: &Job,
+    state_dir: Option<&Path>,
+    saved: Option<&SavedPlan>,
+) -> RunReport {
+    let started = Instant::now();
+    let label = &job.label;
+
+    let mut reporter = Reporter::new(
+        output,
+        RunConfig {
+            job: name.map(String::from),
+            backup_pool: job.backup_pools[0].clone(),
+            backup_pools: job.backup_pools.clone(),
+            label: label.clone(),
+            datasets: job.datasets.clone(),
+            dry_run,
+        },
+    );
+
+    if let Some(name) = name {
+        reporter.text(&format!("Job: {}", name));
+    }
+
+    // Import the removable backup pools first. Whatever happens during the
+    // run, the pools that were imported here are exported again at the end
+    // so that their disks can be unplugged.
+    let mut imported_pools = Vec::new();
+    if let Some(import) = &job.import {
+        let (imported, errors) = pools::import_pools(
+            system,
+            &|message| reporter.text(message),
+            &job.backup_pools,
+            import,
+            dry_run,
+        );
+        for error in errors {
+            reporter.error(&error);
+        }
+        imported_pools = imported;
+    }
+
+    let pool_guids = replicate_job(system, &mut reporter, dry_run, name, job, saved);
+    if let (Some(state_dir), false) = (state_dir, dry_run) {
+        update_catalog(system, state_dir, job, &pool_guids);
+    }
+
+    // A dry run imports the pools read-only, which would get in the way of
+    // the next real run, so it always exports them again.
+    if job
+        .import
+        .as_ref()
+        .is_some_and(|import| import.export || dry_run)
+    {
+        for error in pools::export_pools(system, &|message| reporter.text(message), &imported_pools)
+        {
+            reporter.error(&error);
+        }
+    }
+
+    reporter.text("");
+    reporter.finish(started.elapsed())
+}
+
+// Replicates the job into whichever of its backup pools are imported,
+// running the run_start and run_end hooks around it. Saved plans are only
+// applied when every backup pool is usable and nothing drifted. Returns the
+// GUIDs of the backup pools that were usable.
+fn replicate_job(
+    system: &impl SystemProvider,
+    reporter: &mut Reporter,
+    dry_run: bool,
+    name: Option<&str>,
+    job: &Job,
+    saved: Option<&SavedPlan>,
+) -> Vec<(String, u64)> {
+    let label = &job.label;
+
+    let preflight = check_pools(system, job);
+    for finding in preflight.findings {
+        match finding {
+            Finding::Health(health) => reporter.pool_checked(health),
+            Finding::Warning(warning) => reporter.warning(&warning),
+            Finding::Unusable(error) if job.backup_pools.len() == 1 => {
+                reporter.error(&format!("{} Aborting.", error))
+            }
+            Finding::Unusable(error) => reporter.error(&format!("{} Skipping it.", error)),
+            Finding::Fatal(error) => reporter.error(&format!("{} Aborting.", error)),
+        }
+    }
+    let pool_guids = preflight.pool_guids;
+    if preflight.aborted {
+        return pool_guids;
+    }
+    let backup_pools: Vec<&str> = preflight.backup_pools.iter().map(String::as_str).collect();
+
+    if let Some(saved) = saved {
+        reporter.text(&format!(
+            "Applying the plan made at {} ...",
+            saved.created_at
+        ));
+        let mut drifts = saved.verify(system);
+        if backup_pools.len() < job.backup_pools.len() {
+            drifts.push(String::from(
+                "Not all of the backup pools of the plan can be written to.",
+            ));
+        }
+        if !drifts.is_empty() {
+            for drift in drifts {
+                reporter.error(&drift);
+            }
+            reporter
+                .error("The plan no longer matches the pools and datasets. Refusing to apply it.");
+            return pool_guids;
+        }
+    }
+
+    let hooks = HookRunner::new(
+        &job.hooks,
+        &[
+            ("JOB", String::from(name.unwrap_or_default())),
+            ("BACKUP_POOL", job.backup_pools.join(",")),
+            ("LABEL", label.clone()),
+            ("DRY_RUN", dry_run.to_string()),
+        ],
+        dry_run,
+    );
+
+    match hooks.run(HookPoint::RunStart, &[], &|message| reporter.text(message)) {
+        Ok(()) => {
+            replicate_all(system, reporter, &hooks, dry_run, job, &backup_pools, saved);
+            // A scrub can take hours, so don't start one after an interruption.
+            if let (Some(options), false) = (&job.scrub, interrupt::is_interrupted()) {
+                scrub_pools(system, reporter, dry_run, &backup_pools, options);
+            }
+        }
+        Err(error) => reporter.error(&format!("{} Aborting.", error)),
+    }
+
+    let report = reporter.report();
+    let environment = [
+        (
+            "OUTCOME",
+            String::from(if report.success { "success" } else { "failed" }),
+        ),
+        ("BYTES_SENT", report.bytes_sent.to_string()),
+    ];
+    if let Err(error) = hooks.run(HookPoint::RunEnd, &environment, &|message| {
+        reporter.text(message)
+    }) {
+        reporter.error(&error);
+    }
+    pool_guids
+}
+
+// Something found while checking the pools of a job.
+enum Finding {
+    Health(PoolHealth),
+    Warning(String),
+    // A backup pool that can't be written to.
+    Unusable(String),
+    // Why the job can't run at all.
+    Fatal(String),
+}
+
+// The outcome of checking the pools of a job before planning or replicating
+// it.
+struct Preflight {
+    // The backup pools that can be written to, and the GUIDs of those whose
+    // GUID could be read.
+    backup_pools: Vec<String>,
+    pool_guids: Vec<(String, u64)>,
+    findings: Vec<Finding>,
+    aborted: bool,
+}
+
+// Checks that each backup pool is imported, healthy and is the disk we
+// expect, that no source lives inside of the backup datasets and that all of
+// the source pools are imported. When there are several backup pools, the
+// unusable ones are only skipped.
+fn check_pools(system: &impl SystemProvider, job: &Job) -> Preflight {
+    let mut preflight = Preflight {
+        backup_pools: Vec::new(),
+        pool_guids: Vec::new(),
+        findings: Vec::new(),
+        aborted: true,
+    };
+
+    for backup_pool in &job.backup_pools {
+        if !system.is_pool_imported(backup_pool) {
+            preflight.findings.push(Finding::Unusable(format!(
+                "{} pool is not imported.",
+                backup_pool
+            )));
+            continue;
+        }
+        if let Some(error) =
+            check_pool_health(system, &mut preflight.findings, job, backup_pool, true)
+        {
+            preflight.findings.push(Finding::Unusable(error));
+            continue;
+        }
+        let expected = job
+            .pool_guids
+            .get(backup_pool)
+            .map_or(&[][..], Vec::as_slice);
+        match pools::verify_pool_guid(system, backup_pool, expected) {
+            Ok(guid) => {
+                preflight.backup_pools.push(backup_pool.clone());
+                if let Some(guid) = guid {
+                    preflight.pool_guids.push((backup_pool.clone(), guid));
+                }
+            }
+            Err(error) => preflight.findings.push(Finding::Unusable(error)),
+        }
+    }
+    if preflight.backup_pools.is_empty() {
+        if job.backup_pools.len() > 1 {
+            preflight.findings.push(Finding::Fatal(String::from(
+                "None of the backup pools can be written to.",
+            )));
+        }
+        return preflight;
+    }
+
+    // Sources may live in a backup pool, but not where the backups go.
+    if let Some((dataset, backup_dataset)) = job.find_overlap() {
+        preflight.findings.push(Finding::Fatal(format!(
+            "{} overlaps with the backup dataset {}. Source datasets must live outside of the backup datasets.",
+            dataset, backup_dataset
+        )));
+        return preflight;
+    }
+
+    for source_pool in helpers::get_source_pool_names(&job.datasets) {
+        if !system.is_pool_imported(source_pool) {
+            preflight.findings.push(Finding::Fatal(format!(
+                "{} pool is not imported.",
+                source_pool
+            )));
+            return preflight;
+        }
+        check_pool_health(system, &mut preflight.findings, job, source_pool, false);
+    }
+
+    preflight.aborted = false;
+    preflight
+}
+
+// Scrubs the backup pools that were written to, one at a time, and fails the
+// run if any of them has unrecoverable errors.
+fn scrub_pools(
+    system: &impl SystemProvider,
+    reporter: &mut Reporter,
+    dry_run: bool,
+    backup_pools: &[&str],
+    options: &ScrubOptions,
+) {
+    reporter.text("");
+    for backup_pool in backup_pools {
+        if interrupt::is_interrupted() {
+            break;
+        }
+        if dry_run {
+            reporter.text(&format!("Scrubbing {} ...", backup_pool));
+            continue;
+        }
+
+        let started = Instant::now();
+        let result = pools::scrub_pool(
+            system,
+            &|message| reporter.text(message),
+            backup_pool,
+            options,
+            interrupt::get_flag(),
+        );
+        match result {
+            Ok(status) => {
+                reporter.text(&format!(
+                    "Scrub of {} finished. Repaired {} bytes with {} errors.",
+                    backup_pool, status.repaired_bytes, status.errors
+                ));
+                reporter.scrub_finished(ScrubReport {
+                    pool: String::from(*backup_pool),
+                    repaired_bytes: status.repaired_bytes,
+                    errors: status.errors,
+                    duration_ms: started.elapsed().as_millis(),
+                });
+                if status.errors > 0 {
+                    reporter.error(&format!(
+                        "{} pool has unrecoverable errors. See 'zpool status -v {}'.",
+                        backup_pool, backup_pool
+                    ));
+                }
+            }
+            Err(error) => reporter.error(&format!("Failed to scrub {}: {}", backup_pool, error)),
+        }
+    }
+}
+
+// Records the health of a pool and returns why it can't be written to, if
+// anything.
+fn check_pool_health(
+    system: &impl SystemProvider,
+    findings: &mut Vec<Finding>,
+    job: &Job,
+    pool: &str,
+    backup: bool,
+) -> Option<String> {
+    let check = pools::check_pool_health(system, pool, &job.health, backup);
+    findings.extend(check.warnings.into_iter().map(Finding::Warning));
+    if let Some(health) = check.health {
+        findings.push(Finding::Health(health));
+    }
+    check.error
+}
+
+// Records what is now on each of the backup disks in the catalog.
+fn update_catalog(
+    system: &impl SystemProvider,
+    state_dir: &Path,
+    job: &Job,
+    pool_guids: &[(String, u64)],
+) {
+    if pool_guids.is_empty() {
+        return;
+    }
+
+    let snapshots = system.get_all_snapshots();
+    let now = Local::now();
+    for (backup_pool, guid) in pool_guids {
+        let backup_datasets: Vec<String> = job
+            .datasets
+            .iter()
+            .map(|dataset| job.get_backup_dataset(backup_pool, dataset))
+            .collect();
+        if let Err(error) = catalog::record(
+            state_dir,
+            backup_pool,
+            *guid,
+            &backup_datasets,
+            &snapshots,
+            now,
+        ) {
+            eprintln!("Failed to update the catalog: {}", error);
+        }
+    }
+}
+
+// Applies the saved plan, or takes the snapshots (if needed) and then plans
+// and applies the replication of every dataset of the job into each of the
+// given backup pools.
+fn replicate_all(
+    system: &impl SystemProvider,
+    reporter: &mut Reporter,
+    hooks: &HookRunner,
+    dry_run: bool,
+    job: &Job,
+    backup_pools: &[&str],
+    saved: Option<&SavedPlan>,
+) {
+    let planned;
+    let (plan, send_flags) = match saved {
+        Some(saved) => (&saved.plan, &saved.send_flags),
+        None => {
+            planned = plan_all(system, reporter, dry_run, job, backup_pools);
+            (&planned, &job.send_flags)
+        }
+    };
+    reporter.snapshots_listed(plan.total_snapshots);
+
+    match job.backup_pools.as_slice() {
+        [backup_pool] => reporter.text(&format!("Backup Pool: {}", backup_pool)),
+        backup_pools => reporter.text(&format!("Backup Pools: {}", backup_pools.join(", "))),
+    }
+    reporter.text(&format!("Label: {}", job.label));
+    reporter.text(&format!("Total Snapshots Count: {}", plan.total_snapshots));
+
+    let options = ExecuteOptions {
+        send_flags,
+        dry_run,
+        parallelism: job.parallelism,
+        bandwidth: &job.bandwidth,
+        timeouts: job.timeouts,
+        interrupted: Arc::clone(interrupt::get_flag()),
+        retry: &job.retry,
+    };
+    executor::execute(system, reporter, hooks, plan, &options);
+}
+
+// Takes the snapshots (if needed) and plans the replication.
+fn plan_all(
+    system: &impl SystemProvider,
+    reporter: &mut Reporter,
+    dry_run: bool,
+    job: &Job,
+    backup_pools: &[&str],
+) -> Plan {
+    let label = &job.label;
+
+    let mut created_snapshots = Vec::new();
+    if let Some(options) = &job.snapshot {
+        let (created, errors) = take_snapshots(
+            system,
+            &|message| reporter.text(message),
+            &job.datasets,
+            label,
+            options,
+            dry_run,
+        );
+        for error in errors {
+            reporter.error(&error);
+        }
+        reporter.snapshots_created(&created);
+        created_snapshots = created;
+    }
+
+    // A single listing is shared by every backup pool.
+    let mut snapshots = system.get_all_snapshots();
+
+    // In a dry run the new snapshots don't actually exist, so pretend they
+    // do in order to show what would be replicated.
+    if dry_run {
+        for snapshot in &created_snapshots {
+            snapshots.push(Snapshot::new(snapshot));
+        }
+    }
+
+    plan::plan_job(system, job, &snapshots, backup_pools)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::Action;
+    use crate::pools::ImportOptions;
+    use crate::report::Outcome;
+    use crate::testing::FakeSystem;
+
+    fn get_example_system() -> FakeSystem {
+        let mut system = FakeSystem::new_with_snaps(vec![
+            Snapshot::new("tank/os@2022-09-01-0300-00-TEST"),
+            Snapshot::new("tank/os@2022-09-02-0300-00-TEST"),
+            Snapshot::new("backup/tank/os@2022-09-01-0300-00-TEST"),
+        ]);
+        system.pool_guids.insert(String::from("backup"), 42);
+        system.guids.insert(
+            String::from("tank/os"),
+            vec![
+                (String::from("tank/os@2022-09-01-0300-00-TEST"), 1),
+                (String::from("tank/os@2022-09-02-0300-00-TEST"), 2),
+            ],
+        );
+        system.guids.insert(
+            String::from("backup/tank/os"),
+            vec![(String::from("backup/tank/os@2022-09-01-0300-00-TEST"), 1)],
+        );
+        system
+    }
+
+    #[test]
+    fn test_run_job_should_replicate_every_dataset() {
+        let mut system = get_example_system();
+        system.datasets = vec![String::from("tank/var")];
+        let job = Job::new(
+            "backup",
+            "TEST",
+            &[String::from("tank/os"), String::from("tank/var")],
+        );
+
+        let report = run_job(&system, OutputFormat::Json, false, None, &job, None);
+
+        assert!(report.success);
+        assert_eq!(report.total_snapshots, 3);
+        assert_eq!(report.datasets[0].outcome, Outcome::Success);
+        assert_eq!(report.datasets[1].outcome, Outcome::Skipped);
+    }
+
+    #[test]
+    fn test_run_job_should_abort_without_backup_pool() {
+        let mut system = get_example_system();
+        system.is_pool_imported = false;
+        let job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+
+        let report = run_job(&system, OutputFormat::Json, false, None, &job, None);
+
+        assert!(!report.success);
+        assert!(report.datasets.is_empty());
+        assert_eq!(
+            report.errors,
+            vec!["backup pool is not imported. Aborting."]
+        );
+    }
+
+    #[test]
+    fn test_get_plan_should_refuse_a_backup_pool_that_a_run_would_skip() {
+        let mut system = get_example_system();
+        system.pool_guids.insert(String::from("backup"), 7);
+        let mut job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        job.pool_guids.insert(String::from("backup"), vec![42]);
+
+        let result = get_plan(&system, &job, &[], &|_| {});
+
+        assert_eq!(
+            result.unwrap_err(),
+            "backup pool has GUID 7, which isn't one of the GUIDs registered for it (42)."
+        );
+    }
+
+    #[test]
+    fn test_get_plan_should_return_the_guids_of_the_backup_pools() {
+        let system = get_example_system();
+        let job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+
+        let (plan, pool_guids) = get_plan(&system, &job, &[], &|_| {}).unwrap();
+
+        assert_eq!(plan.backup_pools, vec!["backup"]);
+        assert_eq!(pool_guids, vec![(String::from("backup"), 42)]);
+    }
+
+    #[test]
+    fn test_get_plan_should_send_the_snapshots_that_will_be_taken() {
+        let system = get_example_system();
+        let job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        let new_snapshots = [String::from("tank/os@2022-09-03-0300-00-TEST")];
+
+        let (plan, _) = get_plan(&system, &job, &new_snapshots, &|_| {}).unwrap();
+
+        let target = plan.get_targets().next().unwrap();
+        assert_eq!(
+            target.actions,
+            vec![Action::SendIncremental {
+                from: String::from("tank/os@2022-09-01-0300-00-TEST"),
+                to: String::from("tank/os@2022-09-03-0300-00-TEST"),
+                backup_dataset: String::from("backup/tank/os"),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_run_job_should_export_pools_imported_by_a_dry_run() {
+        let mut system = get_example_system();
+        system.is_pool_imported = false;
+        system.export_pool = false;
+        let mut job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        job.import = Some(ImportOptions {
+            export: false,
+            ..ImportOptions::default()
+        });
+
+        let report = run_job(&system, OutputFormat::Json, true, None, &job, None);
+
+        assert_eq!(
+            report.errors.last().unwrap(),
+            "Failed to export backup: fake failure"
+        );
+    }
+
+    #[test]
+    fn test_check_job_pools_should_refuse_a_backup_pool_that_is_not_imported() {
+        let mut system = get_example_system();
+        system.is_pool_imported = false;
+        let job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+
+        let result = check_job_pools(&system, &job, &|_| {});
+
+        assert_eq!(result.unwrap_err(), "backup pool is not imported.");
+    }
+
+    #[test]
+    fn test_create_plan_should_refuse_an_unhealthy_backup_pool() {
+        let mut system = get_example_system();
+        system.pool_health.insert(
+            String::from("backup"),
+            PoolHealth {
+                pool: String::from("backup"),
+                health: String::from("DEGRADED"),
+                capacity: 50,
+                fragmentation: None,
+            },
+        );
+        let job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+
+        let result = create_plan(&system, "nightly", &job, Local::now(), &|_| {});
+
+        assert_eq!(result.unwrap_err(), "backup pool is DEGRADED.");
+    }
+
+    #[test]
+    fn test_apply_plan_should_refuse_when_backup_changed() {
+        let mut system = get_example_system();
+        let job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        let saved = create_plan(&system, "nightly", &job, Local::now(), &|_| {}).unwrap();
+        system.guids.insert(
+            String::from("backup/tank/os"),
+            vec![(String::from("backup/tank/os@2022-09-02-0300-00-TEST"), 2)],
+        );
+
+        let report = apply_plan(&system, OutputFormat::Json, false, &job, &saved, None);
+
+        assert!(!report.success);
+        assert!(report.datasets.is_empty());
+        assert_eq!(
+            report.errors[0],
+            "The snapshots of backup/tank/os changed since the plan was made."
+        );
+    }
+
+    #[test]
+    fn test_apply_plan_should_run_saved_actions() {
+        let system = get_example_system();
+        let job = Job::new("backup", "TEST", &[String::from("tank/os")]);
+        let saved = create_plan(&system, "nightly", &job, Local::now(), &|_| {}).unwrap();
+
+        let report = apply_plan(&system, OutputFormat::Json, false, &job, &saved, None);
+
+        assert!(report.success);
+        assert_eq!(report.config.job.as_deref(), Some("nightly"));
+        assert_eq!(report.datasets[0].outcome, Outcome::Success);
+    }
+}
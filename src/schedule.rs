@@ -0,0 +1,296 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike};
+
+use crate::helpers;
+
+// When a job should run. Times are wall clock times in the local timezone.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Schedule {
+    // A standard five field cron expression: minute hour day month weekday.
+    Cron(Cron),
+    // Runs every given amount of time, starting when the daemon starts.
+    Interval(Duration),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if let Some(interval) = value.strip_prefix("every ") {
+            return Ok(Schedule::Interval(helpers::parse_duration(interval)?));
+        }
+
+        let expression = match value {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            _ => value,
+        };
+        Ok(Schedule::Cron(Cron::parse(expression)?))
+    }
+}
+
+impl Schedule {
+    // Gets the first time strictly after the given time at which the job
+    // should run.
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        match self {
+            Schedule::Cron(cron) => cron.next_after(after),
+            Schedule::Interval(interval) => {
+                after.checked_add_signed(chrono::Duration::from_std(*interval).ok()?)
+            }
+        }
+    }
+}
+
+impl Cron {
+    fn parse(expression: &str) -> Result<Self, String> {
+        let fields: Vec<_> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "'{}' is not a valid schedule. Expected a cron expression with five fields or 'every <duration>'",
+                expression
+            ));
+        }
+
+        let mut weekdays = Self::parse_field(fields[4], 0, 7)?;
+        // Both 0 and 7 mean Sunday.
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+
+        Ok(Self {
+            minutes: Self::parse_field(fields[0], 0, 59)?,
+            hours: Self::parse_field(fields[1], 0, 23)?,
+            days: Self::parse_field(fields[2], 1, 31)?,
+            months: Self::parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+
+    // Parses a single cron field (e.g. "*", "5", "1-5", "*/15", "1,15,30")
+    // into a bitmask of the allowed values.
+    fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+        let invalid = || format!("'{}' is not a valid cron field ({}-{})", field, min, max);
+        let mut mask = 0;
+
+        for part in field.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+                None => (part, 1),
+            };
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else if let Some((start, end)) = range.split_once('-') {
+                (
+                    start.parse().map_err(|_| invalid())?,
+                    end.parse().map_err(|_| invalid())?,
+                )
+            } else {
+                let value = range.parse().map_err(|_| invalid())?;
+                // "5/10" means starting at 5, every 10.
+                (value, if part.contains('/') { max } else { value })
+            };
+
+            if step == 0 || start < min || end > max || start > end {
+                return Err(invalid());
+            }
+            for value in (start..=end).step_by(step as usize) {
+                mask |= 1 << value;
+            }
+        }
+        Ok(mask)
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+
+        // Like cron, if both the day of month and the day of week are
+        // restricted, a day matching either of them is accepted. A field
+        // starting with "*" (e.g. "*/2") doesn't count as restricted, so
+        // both of them must match then.
+        if self.any_day || self.any_weekday {
+            day && weekday
+        } else {
+            day || weekday
+        }
+    }
+
+    fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut time = after
+            .with_second(0)?
+            .with_nanosecond(0)?
+            .checked_add_signed(chrono::Duration::minutes(1))?;
+
+        // Bounded so that impossible expressions (e.g. February 30th) don't
+        // loop forever. This is enough to cover several years.
+        for _ in 0..100_000 {
+            if self.months & (1 << time.month()) == 0 {
+                let (year, month) = if time.month() == 12 {
+                    (time.year() + 1, 1)
+                } else {
+                    (time.year(), time.month() + 1)
+                };
+                time = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if !self.matches_day(time.date()) {
+                time = time.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if self.hours & (1 << time.hour()) == 0 {
+                time = time.with_minute(0)? + chrono::Duration::hours(1);
+                continue;
+            }
+            if self.minutes & (1 << time.minute()) == 0 {
+                time += chrono::Duration::minutes(1);
+                continue;
+            }
+            return Some(time);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(value: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn next(schedule: &str, after: &str) -> NaiveDateTime {
+        let schedule: Schedule = schedule.parse().unwrap();
+        schedule.next_after(at(after)).unwrap()
+    }
+
+    #[test]
+    fn test_next_after_should_handle_daily_cron() {
+        assert_eq!(
+            next("30 3 * * *", "2022-09-01 12:00:00"),
+            at("2022-09-02 03:30:00")
+        );
+        assert_eq!(
+            next("30 3 * * *", "2022-09-01 03:29:59"),
+            at("2022-09-01 03:30:00")
+        );
+    }
+
+    #[test]
+    fn test_next_after_should_be_strictly_after() {
+        assert_eq!(
+            next("30 3 * * *", "2022-09-01 03:30:00"),
+            at("2022-09-02 03:30:00")
+        );
+    }
+
+    #[test]
+    fn test_next_after_should_handle_steps_and_lists() {
+        assert_eq!(
+            next("*/15 * * * *", "2022-09-01 12:01:00"),
+            at("2022-09-01 12:15:00")
+        );
+        assert_eq!(
+            next("0 1,13 * * *", "2022-09-01 02:00:00"),
+            at("2022-09-01 13:00:00")
+        );
+    }
+
+    #[test]
+    fn test_next_after_should_handle_weekdays_and_months() {
+        // 2022-09-01 is a Thursday.
+        assert_eq!(
+            next("0 0 * * 0", "2022-09-01 00:00:00"),
+            at("2022-09-04 00:00:00")
+        );
+        assert_eq!(
+            next("0 0 * * 7", "2022-09-01 00:00:00"),
+            at("2022-09-04 00:00:00")
+        );
+        assert_eq!(
+            next("0 0 1 1 *", "2022-09-01 00:00:00"),
+            at("2023-01-01 00:00:00")
+        );
+    }
+
+    #[test]
+    fn test_next_after_should_treat_stepped_wildcards_as_unrestricted() {
+        // 2022-09-05 and 2022-09-19 are Mondays on odd days of the month.
+        assert_eq!(
+            next("0 3 */2 * 1", "2022-09-01 00:00:00"),
+            at("2022-09-05 03:00:00")
+        );
+        assert_eq!(
+            next("0 3 */2 * 1", "2022-09-05 04:00:00"),
+            at("2022-09-19 03:00:00")
+        );
+    }
+
+    #[test]
+    fn test_next_after_should_handle_intervals() {
+        assert_eq!(
+            next("every 6h", "2022-09-01 12:00:00"),
+            at("2022-09-01 18:00:00")
+        );
+    }
+
+    #[test]
+    fn test_next_after_should_give_up_on_impossible_dates() {
+        let schedule: Schedule = "0 0 30 2 *".parse().unwrap();
+
+        assert!(schedule.next_after(at("2022-09-01 00:00:00")).is_none());
+    }
+
+    #[test]
+    fn test_parse_should_reject_invalid_schedules() {
+        assert!("0 3 * *".parse::<Schedule>().is_err());
+        assert!("60 3 * * *".parse::<Schedule>().is_err());
+        assert!("*/0 3 * * *".parse::<Schedule>().is_err());
+        assert!("every never".parse::<Schedule>().is_err());
+    }
+}
@@ -0,0 +1,405 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+use std::collections::BTreeMap;
+
+use crate::config::SnapshotOptions;
+use crate::plan::{Action, Plan, TargetPlan};
+
+// The commands shared by every script. POSIX sh has no pipefail, so the
+// exit status of each 'zfs send' is passed through a file and checked once
+// 'zfs recv' is done with the stream.
+const PREAMBLE: &str = r#"set -eu
+
+send_status=$(mktemp)
+trap 'rm -f "$send_status"' EXIT
+
+check_send() {
+    code=$(cat "$send_status")
+    if [ -n "$code" ]; then
+        echo "zfs send failed with exit status $code." >&2
+        exit "$code"
+    fi
+}
+"#;
+
+// A POSIX shell script with every command that replicating one or more
+// jobs would run, so that it can be reviewed and run by hand.
+pub struct Script {
+    header: String,
+    // The GUID of each backup pool that the plans were made for. They are
+    // checked before anything runs, since the script may be run later with
+    // another disk plugged in.
+    pool_guids: BTreeMap<String, String>,
+    contents: String,
+}
+
+impl Script {
+    pub fn new(created_at: &str) -> Self {
+        let mut header = String::from("#!/bin/sh\n");
+        header.push_str(&format!(
+            "# Written by Cantaloupe {} at {}.\n",
+            clap::crate_version!(),
+            created_at
+        ));
+        header
+            .push_str("# Review it before running it. It stops at the first command that fails.\n");
+        header.push_str(PREAMBLE);
+        Self {
+            header,
+            pool_guids: BTreeMap::new(),
+            contents: String::new(),
+        }
+    }
+
+    // Adds the commands of the plan of a job, after taking the snapshots that
+    // the plan expects when the job snapshots. Every backup pool gets its own
+    // send, even when the job tees them.
+    pub fn add_plan(
+        &mut self,
+        name: Option<&str>,
+        send_flags: &[String],
+        snapshot: Option<(&SnapshotOptions, &[String])>,
+        plan: &Plan,
+        pool_guids: &BTreeMap<String, String>,
+    ) {
+        self.pool_guids.extend(pool_guids.clone());
+        self.contents.push('\n');
+        match name {
+            Some(name) => self.push_comment(&format!("Job: {}", name)),
+            None => self.push_comment(&format!("Label: {}", plan.label)),
+        }
+        if let Some((options, snapshots)) = snapshot {
+            self.add_snapshots(options, snapshots);
+        }
+        for target in plan.get_targets() {
+            self.add_target(send_flags, target);
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut script = self.header.clone();
+        for (pool, guid) in &self.pool_guids {
+            script.push_str(&format!(
+                "\n[ \"$({})\" = {} ] || {{ echo {} >&2; exit 1; }}\n",
+                join(&["zpool", "get", "-H", "-o", "value", "guid", pool]),
+                guid,
+                quote(&format!(
+                    "{} pool isn't the disk that the plan was made for.",
+                    pool
+                ))
+            ));
+        }
+        script + &self.contents
+    }
+
+    // Takes the snapshots the same way a run does, either in a single
+    // 'zfs snapshot' call or one call per snapshot.
+    fn add_snapshots(&mut self, options: &SnapshotOptions, snapshots: &[String]) {
+        let batches: Vec<&[String]> = if options.atomic {
+            vec![snapshots]
+        } else {
+            snapshots.chunks(1).collect()
+        };
+        for batch in batches {
+            let mut command = vec!["zfs", "snapshot"];
+            if options.recursive {
+                command.push("-r");
+            }
+            command.extend(batch.iter().map(String::as_str));
+            self.push_command(&command);
+        }
+    }
+
+    fn add_target(&mut self, send_flags: &[String], target: &TargetPlan) {
+        self.contents.push('\n');
+        self.push_comment(&format!("{} -> {}", target.dataset, target.backup_dataset));
+        for action in &target.actions {
+            match action {
+                Action::CreateDatasetTree { backup_dataset } => {
+                    self.push_command(&["zfs", "create", "-p", backup_dataset]);
+                }
+                Action::SendFull {
+                    snapshot,
+                    backup_dataset,
+                } => {
+                    let mut sender = vec!["zfs", "send", "-p"];
+                    sender.extend(send_flags.iter().map(String::as_str));
+                    sender.push(snapshot);
+                    self.push_send(&sender, backup_dataset);
+                }
+                Action::SendIncremental {
+                    from,
+                    to,
+                    backup_dataset,
+                } => {
+                    let mut sender = vec!["zfs", "send"];
+                    sender.extend(send_flags.iter().map(String::as_str));
+                    sender.extend(["-i", from, to]);
+                    self.push_send(&sender, backup_dataset);
+                }
+                Action::Prune { snapshot } => {
+                    self.push_command(&["zfs", "destroy", snapshot]);
+                }
+                Action::Hold { .. } | Action::UpToDate | Action::Skip { .. } => {
+                    self.push_comment(&action.describe())
+                }
+                Action::Fail { reason, error } => {
+                    self.push_comment(&format!("{} {}", reason, error))
+                }
+            }
+        }
+    }
+
+    fn push_send(&mut self, sender: &[&str], backup_dataset: &str) {
+        self.contents.push_str(&format!(
+            "{{ {} || echo $? > \"$send_status\"; }} | {}\ncheck_send\n",
+            join(sender),
+            join(&["zfs", "recv", "-vF", backup_dataset])
+        ));
+    }
+
+    fn push_command(&mut self, command: &[&str]) {
+        self.contents.push_str(&join(command));
+        self.contents.push('\n');
+    }
+
+    fn push_comment(&mut self, comment: &str) {
+        for line in comment.lines() {
+            self.contents.push_str(&format!("# {}\n", line));
+        }
+    }
+}
+
+fn join(command: &[&str]) -> String {
+    command
+        .iter()
+        .map(|argument| quote(argument))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Quotes an argument for the shell. Arguments made only of characters that
+// the shell doesn't treat specially are left as they are.
+pub fn quote(argument: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c);
+    if !argument.is_empty() && argument.chars().all(is_safe) {
+        String::from(argument)
+    } else {
+        format!("'{}'", argument.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::DatasetPlan;
+
+    fn get_target(backup_dataset: &str, actions: Vec<Action>) -> TargetPlan {
+        TargetPlan {
+            dataset: String::from("tank/var log"),
+            backup_pool: String::from("backup"),
+            backup_dataset: String::from(backup_dataset),
+            source_snapshots: 2,
+            backup_snapshots: 1,
+            latest_snapshot: None,
+            common_snapshot: None,
+            replication_lag_seconds: None,
+            adopted: false,
+            actions,
+        }
+    }
+
+    #[test]
+    fn test_quote_should_only_quote_arguments_that_need_it() {
+        let arguments = [
+            "backup/tank/os@2022-09-01-0300-00-TEST",
+            "my data",
+            "it's",
+            "",
+        ];
+
+        let quoted: Vec<String> = arguments.iter().map(|argument| quote(argument)).collect();
+
+        assert_eq!(
+            quoted,
+            [
+                "backup/tank/os@2022-09-01-0300-00-TEST",
+                "'my data'",
+                r"'it'\''s'",
+                "''"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_add_plan_should_write_every_command_of_the_plan() {
+        let plan = Plan {
+            label: String::from("TEST"),
+            backup_pools: vec![String::from("backup"), String::from("usb")],
+            total_snapshots: 3,
+            tee: true,
+            datasets: vec![DatasetPlan {
+                dataset: String::from("tank/var log"),
+                targets: vec![
+                    get_target(
+                        "backup/tank/var log",
+                        vec![
+                            Action::SendIncremental {
+                                from: String::from("tank/var log@1-TEST"),
+                                to: String::from("tank/var log@2-TEST"),
+                                backup_dataset: String::from("backup/tank/var log"),
+                            },
+                            Action::Prune {
+                                snapshot: String::from("backup/tank/var log@0-TEST"),
+                            },
+                        ],
+                    ),
+                    get_target(
+                        "usb/tank/var log",
+                        vec![
+                            Action::CreateDatasetTree {
+                                backup_dataset: String::from("usb/tank/var log"),
+                            },
+                            Action::SendFull {
+                                snapshot: String::from("tank/var log@2-TEST"),
+                                backup_dataset: String::from("usb/tank/var log"),
+                            },
+                        ],
+                    ),
+                ],
+            }],
+        };
+        let mut script = Script::new("2022-09-03T03:00:00+00:00");
+
+        script.add_plan(
+            Some("nightly"),
+            &[String::from("-w")],
+            None,
+            &plan,
+            &BTreeMap::new(),
+        );
+
+        let contents = script.render();
+        assert!(contents.starts_with("#!/bin/sh\n"));
+        assert!(contents.contains("set -eu\n"));
+        assert!(contents.ends_with(
+            "# Job: nightly\n\
+             \n\
+             # tank/var log -> backup/tank/var log\n\
+             { zfs send -w -i 'tank/var log@1-TEST' 'tank/var log@2-TEST' || echo $? > \"$send_status\"; } | zfs recv -vF 'backup/tank/var log'\n\
+             check_send\n\
+             zfs destroy 'backup/tank/var log@0-TEST'\n\
+             \n\
+             # tank/var log -> usb/tank/var log\n\
+             zfs create -p 'usb/tank/var log'\n\
+             { zfs send -p -w 'tank/var log@2-TEST' || echo $? > \"$send_status\"; } | zfs recv -vF 'usb/tank/var log'\n\
+             check_send\n"
+        ));
+    }
+
+    #[test]
+    fn test_add_plan_should_comment_out_targets_without_commands() {
+        let plan = Plan {
+            label: String::from("TEST"),
+            backup_pools: vec![String::from("backup")],
+            total_snapshots: 0,
+            tee: false,
+            datasets: vec![DatasetPlan {
+                dataset: String::from("tank/var log"),
+                targets: vec![get_target(
+                    "backup/tank/var log",
+                    vec![Action::Skip {
+                        reason: String::from("No common snapshot found. Skipping."),
+                    }],
+                )],
+            }],
+        };
+        let mut script = Script::new("2022-09-03T03:00:00+00:00");
+
+        script.add_plan(None, &[], None, &plan, &BTreeMap::new());
+
+        assert!(script.render().ends_with(
+            "# Label: TEST\n\
+             \n\
+             # tank/var log -> backup/tank/var log\n\
+             # No common snapshot found. Skipping.\n"
+        ));
+    }
+
+    #[test]
+    fn test_add_plan_should_take_the_snapshots_before_sending() {
+        let plan = Plan {
+            label: String::from("TEST"),
+            backup_pools: vec![String::from("backup")],
+            total_snapshots: 0,
+            tee: false,
+            datasets: Vec::new(),
+        };
+        let options = SnapshotOptions {
+            recursive: true,
+            atomic: false,
+        };
+        let snapshots = [
+            String::from("tank/os@2-TEST"),
+            String::from("tank/var log@2-TEST"),
+        ];
+        let mut script = Script::new("2022-09-03T03:00:00+00:00");
+
+        script.add_plan(
+            None,
+            &[],
+            Some((&options, &snapshots)),
+            &plan,
+            &BTreeMap::new(),
+        );
+
+        assert!(script.render().ends_with(
+            "# Label: TEST\n\
+             zfs snapshot -r tank/os@2-TEST\n\
+             zfs snapshot -r 'tank/var log@2-TEST'\n"
+        ));
+    }
+
+    #[test]
+    fn test_render_should_check_the_guid_of_each_backup_pool_first() {
+        let plan = Plan {
+            label: String::from("TEST"),
+            backup_pools: vec![String::from("backup")],
+            total_snapshots: 0,
+            tee: false,
+            datasets: Vec::new(),
+        };
+        let pool_guids = BTreeMap::from([(String::from("backup"), String::from("42"))]);
+        let mut script = Script::new("2022-09-03T03:00:00+00:00");
+
+        script.add_plan(None, &[], None, &plan, &pool_guids);
+
+        let contents = script.render();
+        let check = "[ \"$(zpool get -H -o value guid backup)\" = 42 ] || { echo 'backup pool isn'\\''t the disk that the plan was made for.' >&2; exit 1; }\n";
+        assert!(contents.contains(check));
+        assert!(contents.find(check) < contents.find("# Label: TEST"));
+    }
+}
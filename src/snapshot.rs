@@ -25,6 +25,8 @@
 
 use std::fmt::{Display, Formatter, Result};
 
+use chrono::NaiveDateTime;
+
 #[derive(Eq, Ord, PartialOrd, Clone, Debug)]
 pub struct Snapshot {
     pub name: String,
@@ -37,10 +39,10 @@ impl Snapshot {
         }
     }
 
-    pub fn from_batch(unparsed_snapshots: &Vec<&str>) -> Vec<Snapshot> {
+    pub fn from_batch(unparsed_snapshots: &[&str]) -> Vec<Snapshot> {
         let mut snapshots = Vec::new();
         for snapshot in unparsed_snapshots {
-            if !Snapshot::validate_snapshot_format(&snapshot) {
+            if !Snapshot::validate_snapshot_format(snapshot) {
                 continue;
             }
 
@@ -49,23 +51,30 @@ impl Snapshot {
         snapshots
     }
 
+    // Gets the time encoded in the snapshot name (YYYY-mm-dd-HHMM-ss-LABEL).
+    pub fn get_time(&self) -> Option<NaiveDateTime> {
+        let (_, suffix) = self.name.split_once('@')?;
+        let time = suffix.get(..18)?;
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d-%H%M-%S").ok()
+    }
+
     fn validate_snapshot_format(name: &str) -> bool {
         let core_splinters: Vec<_> = name.split("@").collect();
         let splinters: Vec<_> = core_splinters[1].split("-").collect();
 
-        let year = splinters.get(0);
+        let year = splinters.first();
         let month = splinters.get(1);
         let day = splinters.get(2);
         let hour = splinters.get(3);
         let seconds = splinters.get(4);
         let label = splinters.get(5);
 
-        if year == None
-            || month == None
-            || day == None
-            || hour == None
-            || seconds == None
-            || label == None
+        if year.is_none()
+            || month.is_none()
+            || day.is_none()
+            || hour.is_none()
+            || seconds.is_none()
+            || label.is_none()
         {
             return false;
         }
@@ -122,4 +131,17 @@ mod tests {
 
         assert_eq!(snapshots.len(), 0);
     }
+
+    #[test]
+    fn test_get_time_should_parse_snapshot_name() {
+        let snapshot = Snapshot::new("tank/os@2022-09-27-1300-05-CHECKPOINT");
+
+        let time = snapshot.get_time();
+
+        assert_eq!(
+            time,
+            NaiveDateTime::parse_from_str("2022-09-27 13:00:05", "%Y-%m-%d %H:%M:%S").ok()
+        );
+        assert_eq!(Snapshot::new("tank@lol").get_time(), None);
+    }
 }
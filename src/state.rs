@@ -0,0 +1,298 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::report::{ActionKind, DatasetReport, Outcome, RunReport};
+
+// Where the results of previous runs are remembered.
+pub const DEFAULT_STATE_DIR: &str = "/var/lib/cantaloupe";
+
+// The result of the last run of a job. Each job has its own file so that
+// jobs running concurrently in daemon mode never write to the same file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JobState {
+    pub success: bool,
+    // RFC 3339 timestamp of when the run finished.
+    pub finished_at: String,
+    // The backup pools of the job, separated by commas.
+    pub backup_pool: String,
+    pub label: String,
+    pub bytes_sent: u64,
+    pub duration_ms: u128,
+    pub errors: Vec<String>,
+    // By backup dataset, since a source dataset can be replicated into
+    // several backup pools.
+    pub datasets: BTreeMap<String, DatasetState>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DatasetState {
+    pub dataset: String,
+    pub backup_dataset: String,
+    pub outcome: String,
+    // RFC 3339 timestamp of the last run that left the backup up to date.
+    // Carried over from previous runs when the dataset fails.
+    pub last_success: Option<String>,
+    // How far the backup is behind the source after the run, in seconds.
+    pub replication_lag_seconds: Option<i64>,
+    pub bytes_sent: u64,
+    pub duration_ms: u128,
+    pub source_snapshots: usize,
+    pub backup_snapshots: usize,
+}
+
+impl JobState {
+    // Builds the state of a job from the report of a run that finished at
+    // the given time and the state of the previous run (if any).
+    pub fn new(report: &RunReport, previous: Option<&JobState>, now: DateTime<Local>) -> Self {
+        let finished_at = now.to_rfc3339();
+        let datasets = report
+            .datasets
+            .iter()
+            .map(|dataset| {
+                // Older state files are keyed by source dataset, so match on
+                // the backup dataset that is stored in each entry instead.
+                let previous = previous.and_then(|state| {
+                    state
+                        .datasets
+                        .values()
+                        .find(|previous| previous.backup_dataset == dataset.backup_dataset)
+                });
+                (
+                    dataset.backup_dataset.clone(),
+                    DatasetState::new(dataset, previous, &finished_at),
+                )
+            })
+            .collect();
+
+        Self {
+            success: report.success,
+            finished_at,
+            backup_pool: report.config.backup_pools.join(","),
+            label: report.config.label.clone(),
+            bytes_sent: report.bytes_sent,
+            duration_ms: report.duration_ms,
+            errors: report.errors.clone(),
+            datasets,
+        }
+    }
+
+    // Loads the state of every job that ran before, by job name.
+    pub fn load_all(state_dir: &Path) -> BTreeMap<String, Self> {
+        let mut states = BTreeMap::new();
+        let entries = match fs::read_dir(state_dir.join("jobs")) {
+            Ok(entries) => entries,
+            Err(_) => return states,
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            if let Some(job) = path.file_stem().and_then(|stem| stem.to_str()) {
+                if let Some(state) = Self::load(state_dir, job) {
+                    states.insert(String::from(job), state);
+                }
+            }
+        }
+        states
+    }
+
+    // Returns None if the job never ran or its state can't be read.
+    pub fn load(state_dir: &Path, job: &str) -> Option<Self> {
+        let contents = fs::read_to_string(Self::get_path(state_dir, job)).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    pub fn save(&self, state_dir: &Path, job: &str) -> Result<(), String> {
+        let path = Self::get_path(state_dir, job);
+        let directory = path.parent().unwrap_or(state_dir);
+        fs::create_dir_all(directory)
+            .map_err(|e| format!("Failed to create {}: {}", directory.display(), e))?;
+
+        // Write to a temporary file first so that a crash never leaves a
+        // truncated state file behind.
+        let temporary = path.with_extension("json.tmp");
+        let contents = serde_json::to_string_pretty(self).unwrap();
+        fs::write(&temporary, contents)
+            .and_then(|_| fs::rename(&temporary, &path))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    fn get_path(state_dir: &Path, job: &str) -> PathBuf {
+        state_dir.join("jobs").join(format!("{}.json", job))
+    }
+}
+
+impl DatasetState {
+    fn new(dataset: &DatasetReport, previous: Option<&DatasetState>, finished_at: &str) -> Self {
+        let up_to_date = matches!(dataset.outcome, Outcome::Success | Outcome::UpToDate);
+
+        // Account for what this run changed on the backup dataset.
+        let mut backup_snapshots = dataset.backup_snapshots;
+        for action in dataset.actions.iter().filter(|a| a.executed && a.success) {
+            match action.kind {
+                ActionKind::SendFull { .. } | ActionKind::SendIncremental { .. } => {
+                    backup_snapshots += 1
+                }
+                ActionKind::Prune { .. } => backup_snapshots = backup_snapshots.saturating_sub(1),
+                ActionKind::CreateDatasetTree { .. } => {}
+            }
+        }
+
+        Self {
+            dataset: dataset.dataset.clone(),
+            backup_dataset: dataset.backup_dataset.clone(),
+            outcome: String::from(dataset.outcome.as_str()),
+            last_success: if up_to_date {
+                Some(String::from(finished_at))
+            } else {
+                previous.and_then(|previous| previous.last_success.clone())
+            },
+            replication_lag_seconds: if up_to_date {
+                Some(0)
+            } else {
+                dataset.replication_lag_seconds
+            },
+            bytes_sent: dataset.bytes_sent,
+            duration_ms: dataset.duration_ms,
+            source_snapshots: dataset.source_snapshots,
+            backup_snapshots,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::{ActionReport, RunConfig, SCHEMA_VERSION};
+
+    fn get_example_report(outcome: Outcome) -> RunReport {
+        let mut dataset = DatasetReport::new("tank/os", "backup/tank/os");
+        dataset.outcome = outcome;
+        dataset.backup_snapshots = 3;
+        dataset.replication_lag_seconds = Some(3600);
+        dataset.actions.push(ActionReport {
+            kind: ActionKind::SendIncremental {
+                from: String::from("tank/os@2022-09-01-0000-00-TEST"),
+                to: String::from("tank/os@2022-09-01-0100-00-TEST"),
+                backup_dataset: String::from("backup/tank/os"),
+            },
+            executed: true,
+            success: outcome == Outcome::Success,
+            bytes_sent: 0,
+            duration_ms: 0,
+            error: None,
+            attempts: Vec::new(),
+        });
+
+        RunReport {
+            schema_version: SCHEMA_VERSION,
+            version: String::from("test"),
+            config: RunConfig {
+                job: Some(String::from("nightly")),
+                backup_pool: String::from("backup"),
+                backup_pools: vec![String::from("backup")],
+                label: String::from("TEST"),
+                datasets: vec![String::from("tank/os")],
+                dry_run: false,
+            },
+            total_snapshots: 0,
+            created_snapshots: Vec::new(),
+            pools: Vec::new(),
+            datasets: vec![dataset],
+            scrubs: Vec::new(),
+            bytes_sent: 0,
+            duration_ms: 0,
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            success: outcome == Outcome::Success,
+        }
+    }
+
+    #[test]
+    fn test_new_should_record_successful_replication() {
+        let now = Local::now();
+
+        let state = JobState::new(&get_example_report(Outcome::Success), None, now);
+        let dataset = &state.datasets["backup/tank/os"];
+
+        assert_eq!(dataset.dataset, "tank/os");
+        assert_eq!(dataset.last_success, Some(now.to_rfc3339()));
+        assert_eq!(dataset.replication_lag_seconds, Some(0));
+        assert_eq!(dataset.backup_snapshots, 4);
+    }
+
+    #[test]
+    fn test_new_should_keep_last_success_when_failing() {
+        let earlier = Local::now() - chrono::Duration::days(1);
+        let previous = JobState::new(&get_example_report(Outcome::Success), None, earlier);
+
+        let state = JobState::new(
+            &get_example_report(Outcome::Failed),
+            Some(&previous),
+            Local::now(),
+        );
+        let dataset = &state.datasets["backup/tank/os"];
+
+        assert_eq!(dataset.last_success, Some(earlier.to_rfc3339()));
+        assert_eq!(dataset.replication_lag_seconds, Some(3600));
+        assert_eq!(dataset.backup_snapshots, 3);
+        assert_eq!(dataset.outcome, "failed");
+    }
+
+    #[test]
+    fn test_save_should_round_trip() {
+        let state_dir =
+            std::env::temp_dir().join(format!("cantaloupe-state-{}", std::process::id()));
+        let state = JobState {
+            success: false,
+            finished_at: String::from("2022-09-01T03:00:00+00:00"),
+            bytes_sent: 42,
+            errors: vec![String::from("backup pool is not imported")],
+            ..Default::default()
+        };
+
+        let saved = state.save(&state_dir, "nightly");
+        let loaded = JobState::load(&state_dir, "nightly");
+        let missing = JobState::load(&state_dir, "weekly");
+        let all = JobState::load_all(&state_dir);
+        let _ = fs::remove_dir_all(&state_dir);
+
+        assert!(saved.is_ok());
+        assert_eq!(loaded, Some(state));
+        assert_eq!(missing, None);
+        assert_eq!(all.len(), 1);
+    }
+}
@@ -0,0 +1,360 @@
+// Copyright © 2022 Jonathan Vasquez <jon@xyinn.org>
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+//
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS "AS IS" AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use crate::config::Job;
+use crate::helpers;
+use crate::pools::PoolHealth;
+use crate::report::SCHEMA_VERSION;
+use crate::snapshot::Snapshot;
+use crate::traits::SystemProvider;
+use crate::Cantaloupe;
+
+// The replication state of every dataset of the selected jobs, as reported
+// by the 'status' command.
+#[derive(Clone, Debug, Serialize)]
+pub struct StatusReport {
+    pub schema_version: u32,
+    pub version: String,
+    pub jobs: Vec<JobStatus>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct JobStatus {
+    pub job: String,
+    pub backup_pool: String,
+    pub label: String,
+    pub backup_pool_imported: bool,
+    // The health of the backup pool (when imported) and the source pools.
+    pub pools: Vec<PoolHealth>,
+    pub datasets: Vec<DatasetStatus>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DatasetStatus {
+    pub dataset: String,
+    pub backup_dataset: String,
+    pub latest_snapshot: Option<String>,
+    pub common_snapshot: Option<String>,
+    // The newest labeled snapshot on the backup dataset.
+    pub last_replicated_snapshot: Option<String>,
+    pub last_replicated_age_seconds: Option<i64>,
+    // How many labeled source snapshots haven't been replicated yet.
+    pub snapshots_behind: usize,
+    pub diverged: bool,
+    pub resume_token: Option<String>,
+    pub up_to_date: bool,
+}
+
+impl StatusReport {
+    pub fn new(jobs: Vec<JobStatus>) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            version: String::from(clap::crate_version!()),
+            jobs,
+        }
+    }
+}
+
+// Gets the replication state of every dataset of the job in each of its
+// backup pools without changing anything.
+pub fn get_job_statuses(
+    system: &impl SystemProvider,
+    name: &str,
+    job: &Job,
+    now: NaiveDateTime,
+) -> Vec<JobStatus> {
+    let snapshots = system.get_all_snapshots();
+    job.backup_pools
+        .iter()
+        .map(|backup_pool| get_job_status(system, &snapshots, name, job, backup_pool, now))
+        .collect()
+}
+
+pub fn get_job_status(
+    system: &impl SystemProvider,
+    snapshots: &[Snapshot],
+    name: &str,
+    job: &Job,
+    backup_pool: &str,
+    now: NaiveDateTime,
+) -> JobStatus {
+    let backup_pool_imported = system.is_pool_imported(backup_pool);
+
+    let mut pools = Vec::new();
+    if backup_pool_imported {
+        pools.push(backup_pool);
+    }
+    let mut source_pools: Vec<&str> = helpers::get_source_pool_names(&job.datasets)
+        .into_iter()
+        .collect();
+    source_pools.sort();
+    pools.extend(source_pools);
+    let pools = pools
+        .into_iter()
+        .filter_map(|pool| system.get_pool_health(pool).ok())
+        .collect();
+
+    let datasets = job
+        .datasets
+        .iter()
+        .map(|dataset| {
+            let backup_dataset = job.get_backup_dataset(backup_pool, dataset);
+            let program = Cantaloupe::new_with_backup_dataset(
+                snapshots,
+                backup_pool,
+                &backup_dataset,
+                dataset,
+                &job.label,
+            );
+            let resume_token = if backup_pool_imported {
+                system.get_resume_token(&backup_dataset)
+            } else {
+                None
+            };
+            get_dataset_status(&program, dataset, resume_token, now)
+        })
+        .collect();
+
+    JobStatus {
+        job: String::from(name),
+        backup_pool: String::from(backup_pool),
+        label: job.label.clone(),
+        backup_pool_imported,
+        pools,
+        datasets,
+    }
+}
+
+pub fn get_dataset_status(
+    program: &Cantaloupe,
+    dataset: &str,
+    resume_token: Option<String>,
+    now: NaiveDateTime,
+) -> DatasetStatus {
+    let latest_snapshot = program
+        .get_source_snapshots_labeled()
+        .last()
+        .map(|snapshot| snapshot.name.clone());
+    let common_snapshot = program.get_common_snapshot().map(String::from);
+    let last_replicated = program.get_latest_backup_snapshot();
+    let diverged = program.has_diverged();
+
+    DatasetStatus {
+        dataset: String::from(dataset),
+        backup_dataset: String::from(program.get_backup_dataset_name()),
+        up_to_date: latest_snapshot.is_some()
+            && latest_snapshot == common_snapshot
+            && !diverged
+            && resume_token.is_none(),
+        last_replicated_snapshot: last_replicated.map(|snapshot| snapshot.name.clone()),
+        last_replicated_age_seconds: last_replicated
+            .and_then(|snapshot| snapshot.get_time())
+            .map(|time| (now - time).num_seconds()),
+        snapshots_behind: program.get_snapshots_behind(),
+        latest_snapshot,
+        common_snapshot,
+        diverged,
+        resume_token,
+    }
+}
+
+// Prints the report in a human readable form.
+pub fn print_text(report: &StatusReport) {
+    for job in &report.jobs {
+        println!(
+            "Job: {} (Backup Pool: {}, Label: {})",
+            job.job, job.backup_pool, job.label
+        );
+        if !job.backup_pool_imported {
+            println!("{} pool is not imported.", job.backup_pool);
+        }
+        for pool in &job.pools {
+            println!("{} pool: {}", pool.pool, pool.describe());
+        }
+        println!();
+
+        for dataset in &job.datasets {
+            let none = || String::from("none");
+            println!("{} -> {}", dataset.dataset, dataset.backup_dataset);
+            println!("  Status: {}", get_summary(dataset));
+            println!(
+                "  Latest Snapshot: {}",
+                dataset.latest_snapshot.clone().unwrap_or_else(none)
+            );
+            println!(
+                "  Common Snapshot: {}",
+                dataset.common_snapshot.clone().unwrap_or_else(none)
+            );
+            match (
+                &dataset.last_replicated_snapshot,
+                dataset.last_replicated_age_seconds,
+            ) {
+                (Some(snapshot), Some(age)) => println!(
+                    "  Last Replicated: {} ({} ago)",
+                    snapshot,
+                    helpers::format_duration(age.max(0) as u64)
+                ),
+                (Some(snapshot), None) => println!("  Last Replicated: {}", snapshot),
+                (None, _) => println!("  Last Replicated: never"),
+            }
+            println!("  Snapshots Behind: {}", dataset.snapshots_behind);
+            println!(
+                "  Diverged: {}",
+                if dataset.diverged { "yes" } else { "no" }
+            );
+            println!(
+                "  Resume Token: {}",
+                if dataset.resume_token.is_some() {
+                    "present"
+                } else {
+                    "none"
+                }
+            );
+            println!();
+        }
+    }
+}
+
+fn get_summary(dataset: &DatasetStatus) -> &'static str {
+    if dataset.latest_snapshot.is_none() {
+        "no source snapshots"
+    } else if dataset.diverged {
+        "diverged"
+    } else if dataset.resume_token.is_some() {
+        "interrupted"
+    } else if dataset.up_to_date {
+        "up to date"
+    } else if dataset.last_replicated_snapshot.is_none() {
+        "never replicated"
+    } else {
+        "behind"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeSystem;
+
+    fn at(value: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn get_example_system() -> FakeSystem {
+        FakeSystem::new_with_snaps(vec![
+            Snapshot::new("tank/os@2022-09-01-0300-00-TEST"),
+            Snapshot::new("tank/os@2022-09-02-0300-00-TEST"),
+            Snapshot::new("tank/os@2022-09-03-0300-00-TEST"),
+            Snapshot::new("backup/tank/os@2022-09-01-0300-00-TEST"),
+            Snapshot::new("tank/var@2022-09-03-0300-00-TEST"),
+            Snapshot::new("backup/tank/var@2022-09-03-0300-00-TEST"),
+        ])
+    }
+
+    #[test]
+    fn test_get_job_status_should_report_each_dataset() {
+        let system = get_example_system();
+        let job = Job::new(
+            "backup",
+            "TEST",
+            &[String::from("tank/os"), String::from("tank/var")],
+        );
+
+        let statuses = get_job_statuses(&system, "nightly", &job, at("2022-09-03 04:00:00"));
+        let status = &statuses[0];
+        let os = &status.datasets[0];
+        let var = &status.datasets[1];
+
+        assert!(status.backup_pool_imported);
+        assert_eq!(os.snapshots_behind, 2);
+        assert_eq!(
+            os.common_snapshot.as_deref(),
+            Some("tank/os@2022-09-01-0300-00-TEST")
+        );
+        assert_eq!(os.last_replicated_age_seconds, Some(49 * 60 * 60));
+        assert!(!os.up_to_date);
+        assert!(!os.diverged);
+        assert_eq!(get_summary(os), "behind");
+        assert!(var.up_to_date);
+        assert_eq!(var.snapshots_behind, 0);
+        assert_eq!(get_summary(var), "up to date");
+    }
+
+    #[test]
+    fn test_get_job_status_should_report_pool_health() {
+        let mut system = get_example_system();
+        system.pool_health.insert(
+            String::from("backup"),
+            PoolHealth {
+                pool: String::from("backup"),
+                health: String::from("DEGRADED"),
+                capacity: 85,
+                fragmentation: Some(12),
+            },
+        );
+        let job = Job::new("backup", "TEST", &[String::from("tank/var")]);
+
+        let status = &get_job_statuses(&system, "nightly", &job, at("2022-09-03 04:00:00"))[0];
+
+        assert_eq!(status.pools.len(), 2);
+        assert_eq!(
+            status.pools[0].describe(),
+            "DEGRADED, 85% full, 12% fragmented"
+        );
+        assert_eq!(status.pools[1].pool, "tank");
+        assert_eq!(status.pools[1].describe(), "ONLINE, 0% full");
+    }
+
+    #[test]
+    fn test_get_job_status_should_report_resume_tokens() {
+        let mut system = get_example_system();
+        *system.resume_token.get_mut().unwrap() = Some(String::from("1-abcdef"));
+        let job = Job::new("backup", "TEST", &[String::from("tank/var")]);
+
+        let status = &get_job_statuses(&system, "nightly", &job, at("2022-09-03 04:00:00"))[0];
+
+        assert!(!status.datasets[0].up_to_date);
+        assert_eq!(get_summary(&status.datasets[0]), "interrupted");
+    }
+
+    #[test]
+    fn test_get_job_statuses_should_report_each_backup_pool() {
+        let system = get_example_system();
+        let mut job = Job::new("backup", "TEST", &[String::from("tank/var")]);
+        job.backup_pools.push(String::from("usb"));
+
+        let statuses = get_job_statuses(&system, "nightly", &job, at("2022-09-03 04:00:00"));
+
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(statuses[0].backup_pool, "backup");
+        assert!(statuses[0].datasets[0].up_to_date);
+        assert_eq!(statuses[1].backup_pool, "usb");
+        assert_eq!(statuses[1].datasets[0].backup_dataset, "usb/tank/var");
+        assert_eq!(get_summary(&statuses[1].datasets[0]), "never replicated");
+    }
+}
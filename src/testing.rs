@@ -24,14 +24,53 @@
 // SUCH DAMAGE.
 
 #![allow(unused_variables)]
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::pools::{PoolHealth, ScrubState, ScrubStatus};
 use crate::snapshot::Snapshot;
-use crate::traits::SystemProvider;
+use crate::traits::{SendOptions, SnapshotGuids, SystemProvider};
 
 pub struct FakeSystem {
     pub snapshots: Vec<Snapshot>,
     pub is_pool_imported: bool,
+    pub import_pool: bool,
+    pub export_pool: bool,
     pub send_full_backup: bool,
     pub send_incremental_backup: bool,
+    pub destroy_snapshot: bool,
+    pub create_snapshots: bool,
+    // The token of the partially received stream in every backup dataset.
+    pub resume_token: Mutex<Option<String>>,
+    // Left behind by the sends that fail.
+    pub failed_send_token: Option<String>,
+    // The filesystems and volumes without snapshots. The ones with snapshots
+    // are listed as well.
+    pub datasets: Vec<String>,
+    pub list_datasets: bool,
+    // Returned by the next sends, in order, before they go by the flags
+    // above.
+    pub send_errors: Mutex<Vec<String>>,
+    // The tokens that sends were resumed from.
+    pub resumed: Mutex<Vec<String>>,
+    // GUIDs by pool name. Pools without one fail to report their GUID.
+    pub pool_guids: BTreeMap<String, u64>,
+    // Health by pool name. Pools without one are ONLINE and empty.
+    pub pool_health: BTreeMap<String, PoolHealth>,
+    pub start_scrub: bool,
+    // What 'zpool status' reports about the last scrub of every pool.
+    pub scrub_status: ScrubStatus,
+    // Snapshot names and GUIDs by dataset, oldest first.
+    pub guids: BTreeMap<String, SnapshotGuids>,
+    // The snapshots with a user hold. Listing them fails when this is None.
+    pub held_snapshots: Option<Vec<String>>,
+}
+
+impl Default for FakeSystem {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FakeSystem {
@@ -39,8 +78,28 @@ impl FakeSystem {
         Self {
             snapshots: vec![],
             is_pool_imported: true,
+            import_pool: true,
+            export_pool: true,
             send_full_backup: true,
             send_incremental_backup: true,
+            destroy_snapshot: true,
+            create_snapshots: true,
+            resume_token: Mutex::new(None),
+            failed_send_token: None,
+            datasets: Vec::new(),
+            list_datasets: true,
+            send_errors: Mutex::new(Vec::new()),
+            resumed: Mutex::new(Vec::new()),
+            pool_guids: BTreeMap::new(),
+            pool_health: BTreeMap::new(),
+            start_scrub: true,
+            scrub_status: ScrubStatus {
+                state: ScrubState::Finished,
+                repaired_bytes: 0,
+                errors: 0,
+            },
+            guids: BTreeMap::new(),
+            held_snapshots: Some(Vec::new()),
         }
     }
 
@@ -49,6 +108,28 @@ impl FakeSystem {
         system.snapshots = snapshots;
         system
     }
+
+    fn send_result(&self, success: bool) -> Result<u64, String> {
+        let mut send_errors = self.send_errors.lock().unwrap();
+        let result = if send_errors.is_empty() {
+            FakeSystem::result(success)
+        } else {
+            Err(send_errors.remove(0))
+        };
+        *self.resume_token.lock().unwrap() = match result {
+            Ok(_) => None,
+            Err(_) => self.failed_send_token.clone(),
+        };
+        result
+    }
+
+    fn result(success: bool) -> Result<u64, String> {
+        if success {
+            Ok(0)
+        } else {
+            Err(String::from("fake failure"))
+        }
+    }
 }
 
 impl SystemProvider for FakeSystem {
@@ -56,12 +137,72 @@ impl SystemProvider for FakeSystem {
         self.snapshots.clone()
     }
 
+    fn get_all_datasets(&self) -> Result<Vec<String>, String> {
+        FakeSystem::result(self.list_datasets)?;
+        let mut datasets = self.datasets.clone();
+        for snapshot in &self.snapshots {
+            if let Some((dataset, _)) = snapshot.name.split_once('@') {
+                if !datasets.iter().any(|existing| existing == dataset) {
+                    datasets.push(String::from(dataset));
+                }
+            }
+        }
+        Ok(datasets)
+    }
+
     fn is_pool_imported(&self, pool_name: &str) -> bool {
         self.is_pool_imported
     }
 
-    fn send_full_backup(&self, latest_snapshot: &str, backup_dataset: &str) -> bool {
-        self.send_full_backup
+    fn import_pool(
+        &self,
+        pool_name: &str,
+        guid: Option<u64>,
+        altroot: Option<&Path>,
+        readonly: bool,
+    ) -> Result<(), String> {
+        FakeSystem::result(self.import_pool).map(|_| ())
+    }
+
+    fn export_pool(&self, pool_name: &str) -> Result<(), String> {
+        FakeSystem::result(self.export_pool).map(|_| ())
+    }
+
+    fn get_pool_guid(&self, pool_name: &str) -> Result<u64, String> {
+        self.pool_guids
+            .get(pool_name)
+            .copied()
+            .ok_or_else(|| String::from("fake failure"))
+    }
+
+    fn get_pool_health(&self, pool_name: &str) -> Result<PoolHealth, String> {
+        Ok(self
+            .pool_health
+            .get(pool_name)
+            .cloned()
+            .unwrap_or_else(|| PoolHealth {
+                pool: String::from(pool_name),
+                health: String::from("ONLINE"),
+                capacity: 0,
+                fragmentation: None,
+            }))
+    }
+
+    fn start_scrub(&self, pool_name: &str) -> Result<(), String> {
+        FakeSystem::result(self.start_scrub).map(|_| ())
+    }
+
+    fn get_scrub_status(&self, pool_name: &str) -> Result<ScrubStatus, String> {
+        Ok(self.scrub_status)
+    }
+
+    fn send_full_backup(
+        &self,
+        latest_snapshot: &str,
+        backup_dataset: &str,
+        options: &SendOptions,
+    ) -> Result<u64, String> {
+        self.send_result(self.send_full_backup)
     }
 
     fn send_incremental_backup(
@@ -69,11 +210,67 @@ impl SystemProvider for FakeSystem {
         ancestor_snapshot: &str,
         latest_snapshot: &str,
         backup_dataset: &str,
-    ) -> bool {
-        self.send_incremental_backup
+        options: &SendOptions,
+    ) -> Result<u64, String> {
+        self.send_result(self.send_incremental_backup)
+    }
+
+    fn send_backup_to_many(
+        &self,
+        ancestor_snapshot: Option<&str>,
+        latest_snapshot: &str,
+        backup_datasets: &[String],
+        options: &SendOptions,
+    ) -> Vec<Result<u64, String>> {
+        let success = match ancestor_snapshot {
+            Some(_) => self.send_incremental_backup,
+            None => self.send_full_backup,
+        };
+        backup_datasets
+            .iter()
+            .map(|_| self.send_result(success))
+            .collect()
+    }
+
+    fn resume_backup(
+        &self,
+        token: &str,
+        backup_dataset: &str,
+        options: &SendOptions,
+    ) -> Result<u64, String> {
+        self.resumed.lock().unwrap().push(String::from(token));
+        self.send_result(true)
     }
 
     fn create_dataset_tree_if_needed(&self, backup_dataset: &str) -> bool {
         true
     }
+
+    fn destroy_snapshot(&self, snapshot: &str) -> bool {
+        self.destroy_snapshot
+    }
+
+    fn create_snapshots(&self, snapshots: &[String], recursive: bool) -> Result<(), String> {
+        FakeSystem::result(self.create_snapshots).map(|_| ())
+    }
+
+    fn get_snapshot_guids(&self, dataset: &str) -> Result<SnapshotGuids, String> {
+        Ok(self.guids.get(dataset).cloned().unwrap_or_default())
+    }
+
+    fn get_resume_token(&self, dataset: &str) -> Option<String> {
+        self.resume_token.lock().unwrap().clone()
+    }
+
+    fn get_held_snapshots(&self, dataset: &str) -> Result<Vec<String>, String> {
+        let held = self
+            .held_snapshots
+            .as_ref()
+            .ok_or_else(|| String::from("fake failure"))?;
+        Ok(held
+            .iter()
+            .filter(|snapshot| snapshot.split_once('@').map(|(name, _)| name) == Some(dataset))
+            .cloned()
+            .collect())
+    }
 }
@@ -23,17 +23,97 @@
 // OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
 // SUCH DAMAGE.
 
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::bandwidth::Throttle;
+use crate::pools::{PoolHealth, ScrubStatus};
+use crate::retry::RetryPolicies;
 use crate::Snapshot;
 
-pub trait SystemProvider {
+// Extra settings that apply to a single send/receive pipeline.
+#[derive(Clone, Debug, Default)]
+pub struct SendOptions {
+    // Additional flags passed to 'zfs send' (e.g. "-w" for raw sends).
+    pub send_flags: Vec<String>,
+    // Every one of these slows the stream down to its rate.
+    pub throttles: Vec<Arc<Throttle>>,
+    // When the send has to be done by. Both sides are stopped after it.
+    pub deadline: Option<Instant>,
+    // How long the stream may go without moving any data before both sides
+    // are stopped.
+    pub stall_timeout: Option<Duration>,
+    // Set when the run is interrupted. Both sides are stopped once it is.
+    pub interrupted: Option<Arc<AtomicBool>>,
+    // How failed sends are tried again, by the class of their error. Applied
+    // by the executor, but receives are made resumable when there are any.
+    pub retry: RetryPolicies,
+}
+
+// Snapshot names with their GUIDs, oldest first.
+pub type SnapshotGuids = Vec<(String, u64)>;
+
+// Shared by the threads that replicate datasets in parallel.
+pub trait SystemProvider: Sync {
     fn get_all_snapshots(&self) -> Vec<Snapshot>;
+    // Gets the names of every filesystem and volume.
+    fn get_all_datasets(&self) -> Result<Vec<String>, String>;
     fn is_pool_imported(&self, pool_name: &str) -> bool;
+    // Imports the pool without mounting any of its datasets, by GUID (under
+    // the given name) when one is given.
+    fn import_pool(
+        &self,
+        pool_name: &str,
+        guid: Option<u64>,
+        altroot: Option<&Path>,
+        readonly: bool,
+    ) -> Result<(), String>;
+    fn export_pool(&self, pool_name: &str) -> Result<(), String>;
+    fn get_pool_guid(&self, pool_name: &str) -> Result<u64, String>;
+    fn get_pool_health(&self, pool_name: &str) -> Result<PoolHealth, String>;
+    fn start_scrub(&self, pool_name: &str) -> Result<(), String>;
+    fn get_scrub_status(&self, pool_name: &str) -> Result<ScrubStatus, String>;
     fn send_incremental_backup(
         &self,
         ancestor_snapshot: &str,
         latest_snapshot: &str,
         backup_dataset: &str,
-    ) -> bool;
-    fn send_full_backup(&self, latest_snapshot: &str, backup_dataset: &str) -> bool;
+        options: &SendOptions,
+    ) -> Result<u64, String>;
+    fn send_full_backup(
+        &self,
+        latest_snapshot: &str,
+        backup_dataset: &str,
+        options: &SendOptions,
+    ) -> Result<u64, String>;
+    // Sends the same stream (incremental from the ancestor snapshot when one
+    // is given, full otherwise) into several backup datasets at once. Returns
+    // one result per backup dataset, in the same order.
+    fn send_backup_to_many(
+        &self,
+        ancestor_snapshot: Option<&str>,
+        latest_snapshot: &str,
+        backup_datasets: &[String],
+        options: &SendOptions,
+    ) -> Vec<Result<u64, String>>;
+    // Resumes an interrupted receive into the backup dataset from its token.
+    fn resume_backup(
+        &self,
+        token: &str,
+        backup_dataset: &str,
+        options: &SendOptions,
+    ) -> Result<u64, String>;
     fn create_dataset_tree_if_needed(&self, backup_dataset: &str) -> bool;
+    fn destroy_snapshot(&self, snapshot: &str) -> bool;
+    fn create_snapshots(&self, snapshots: &[String], recursive: bool) -> Result<(), String>;
+    // Gets every snapshot of the dataset (regardless of its name) with its
+    // GUID.
+    fn get_snapshot_guids(&self, dataset: &str) -> Result<SnapshotGuids, String>;
+    // Gets the token of an interrupted receive that can be resumed, if any.
+    fn get_resume_token(&self, dataset: &str) -> Option<String>;
+    // Gets the snapshots of the dataset that have a user hold ('zfs hold'),
+    // which can't be destroyed until every hold is released.
+    fn get_held_snapshots(&self, dataset: &str) -> Result<Vec<String>, String>;
 }